@@ -5,33 +5,39 @@ use criterion::Criterion;
 use rand::distributions::{Alphanumeric, DistString};
 use rand::Rng;
 use std::iter::zip;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-use kvs::{KvStore, KvsEngine, Result, SledStore};
+use kvs::{
+    DBCommands, KVSClient, KvStore, KvsEngine, KvsServer, NaiveThreadPool, RayonThreadPool, Result,
+    ServerResponse, SharedQueueThreadPool, SledStore, ThreadPool,
+};
 use tempfile::TempDir;
 
-fn generate_strings(num: usize, min: usize, max: usize) -> Result<Vec<String>> {
+fn generate_strings(num: usize, min: usize, max: usize) -> Result<Vec<Vec<u8>>> {
     let mut strings = vec![];
     let mut rng = rand::thread_rng();
 
     for _ in 0..num {
         let len: usize = rng.gen_range(min..max);
         let string = Alphanumeric.sample_string(&mut rand::thread_rng(), len);
-        strings.push(string);
+        strings.push(string.into_bytes());
     }
     Ok(strings)
 }
 
-fn copy_strings(strings: &Vec<String>) -> Vec<String> {
-    strings.iter().map(|s| s.clone()).collect()
+fn copy_strings(strings: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    strings.to_vec()
 }
 
-fn set_values<S: KvsEngine>(storage: &mut S, keys: Vec<String>, values: Vec<String>) {
+fn set_values<S: KvsEngine>(storage: &S, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) {
     for (key, value) in zip(keys, values) {
         let _ = &storage.set(key, value);
     }
 }
 
-fn get_values<S: KvsEngine>(storage: &mut S, keys: Vec<String>, values: Vec<String>) {
+fn get_values<S: KvsEngine>(storage: &S, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) {
     for (key, value) in zip(keys, values) {
         let old_value = storage.get(key).expect("cant get");
         assert_eq!(Some(value), old_value);
@@ -49,12 +55,12 @@ fn set_benchmark(c: &mut Criterion) {
             &store_type,
             |b, &s| match *s {
                 "kvs" => {
-                    let mut store = KvStore::open(temp_dir.path()).expect("cant create store");
-                    b.iter(|| set_values(&mut store, copy_strings(&keys), copy_strings(&values)));
+                    let store = KvStore::open(temp_dir.path()).expect("cant create store");
+                    b.iter(|| set_values(&store, copy_strings(&keys), copy_strings(&values)));
                 }
                 "sled" => {
-                    let mut store = SledStore::open(temp_dir.path()).expect("cant create store");
-                    b.iter(|| set_values(&mut store, copy_strings(&keys), copy_strings(&values)))
+                    let store = SledStore::open(temp_dir.path()).expect("cant create store");
+                    b.iter(|| set_values(&store, copy_strings(&keys), copy_strings(&values)))
                 }
                 _ => (),
             },
@@ -74,14 +80,14 @@ fn get_benchmark(c: &mut Criterion) {
             &store_type,
             |b, &s| match *s {
                 "kvs" => {
-                    let mut store = KvStore::open(temp_dir.path()).expect("cant create store");
-                    set_values(&mut store, copy_strings(&keys), copy_strings(&values));
-                    b.iter(|| get_values(&mut store, copy_strings(&keys), copy_strings(&values)));
+                    let store = KvStore::open(temp_dir.path()).expect("cant create store");
+                    set_values(&store, copy_strings(&keys), copy_strings(&values));
+                    b.iter(|| get_values(&store, copy_strings(&keys), copy_strings(&values)));
                 }
                 "sled" => {
-                    let mut store = SledStore::open(temp_dir.path()).expect("cant create store");
-                    set_values(&mut store, copy_strings(&keys), copy_strings(&values));
-                    b.iter(|| get_values(&mut store, copy_strings(&keys), copy_strings(&values)))
+                    let store = SledStore::open(temp_dir.path()).expect("cant create store");
+                    set_values(&store, copy_strings(&keys), copy_strings(&values));
+                    b.iter(|| get_values(&store, copy_strings(&keys), copy_strings(&values)))
                 }
                 _ => (),
             },
@@ -90,5 +96,62 @@ fn get_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_benchmark, get_benchmark);
+/// Start server with given pool in background, it lives until bench exits
+fn start_server<P: ThreadPool + Send + 'static>(addr: &str, threads: u32, temp_dir: &TempDir) {
+    let store = KvStore::open(temp_dir.path()).expect("cant create store");
+    let pool = P::new(threads).expect("cant create pool");
+    let server = KvsServer::new(addr.to_owned(), store, pool).expect("cant create server");
+    thread::spawn(move || server.listen());
+    thread::sleep(Duration::from_millis(100));
+}
+
+/// Send every command from separate client of the client pool and wait all responses
+fn send_concurrently(client_pool: &SharedQueueThreadPool, addr: &str, keys: &[Vec<u8>]) {
+    let (sender, receiver) = mpsc::channel();
+    for key in keys {
+        let sender = sender.clone();
+        let addr = addr.to_owned();
+        let key = key.to_owned();
+        client_pool.spawn(move || {
+            let mut client = KVSClient::new(addr).expect("cant connect");
+            let cmd = DBCommands::Set {
+                key,
+                value: b"value".to_vec(),
+                ttl: None,
+            };
+            let resp = client.send_cmd(cmd).expect("cant send");
+            assert!(matches!(resp, ServerResponse::Success { .. }));
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in keys {
+        receiver.recv().unwrap();
+    }
+}
+
+fn pool_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool_benchmark");
+    group.sample_size(10);
+    let keys = generate_strings(100, 1, 100).expect("Cant create strings");
+    let client_pool = SharedQueueThreadPool::new(8).expect("cant create pool");
+    let mut port = 4100;
+    for pool_type in ["naive", "shared", "rayon"].iter() {
+        for threads in [1, 2, 4, 8].iter() {
+            let addr = format!("127.0.0.1:{}", port);
+            port += 1;
+            let temp_dir = TempDir::new().unwrap();
+            match *pool_type {
+                "naive" => start_server::<NaiveThreadPool>(&addr, *threads, &temp_dir),
+                "shared" => start_server::<SharedQueueThreadPool>(&addr, *threads, &temp_dir),
+                _ => start_server::<RayonThreadPool>(&addr, *threads, &temp_dir),
+            }
+            group.bench_with_input(BenchmarkId::new(*pool_type, threads), threads, |b, _| {
+                b.iter(|| send_concurrently(&client_pool, &addr, &keys))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_benchmark, get_benchmark, pool_benchmark);
 criterion_main!(benches);
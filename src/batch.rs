@@ -0,0 +1,77 @@
+// #![deny(missing_docs)]
+//! Group of writes applied atomically
+use serde::{Deserialize, Serialize};
+
+/// Single write of the batch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Set value by key
+    Put { key: Vec<u8>, value: Vec<u8> },
+    /// Remove key, missing key is not an error
+    Delete { key: Vec<u8> },
+}
+
+/// Puts and deletes which are applied all together or not at all,
+/// in the order they were added
+///
+/// ```rust
+/// use kvs::WriteBatch;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.put(b"key1".to_vec(), b"value1".to_vec());
+/// batch.delete(b"key2".to_vec());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Create empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Add set of the value by key
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Put { key, value });
+        self
+    }
+
+    /// Add removal of the key
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key });
+        self
+    }
+
+    /// Writes in order they were added
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Number of writes
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> Self {
+        WriteBatch { ops }
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
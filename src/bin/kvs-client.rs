@@ -1,33 +1,360 @@
-use clap::Parser;
-use kvs::{DBCommands, KVSClient, ServerResponse};
+use clap::{Parser, Subcommand};
+use kvs::{
+    parse_ttl, DBCommands, Encoding, ErrorCode, KVSClient, KVSError, ServerResponse, WriteBatch,
+};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[clap(
     author,
     version,
     about,
-    long_about = "Client for Key-Value storage, String:String"
+    long_about = "Client for Key-Value storage of arbitrary bytes"
 )]
 struct Cli {
     #[clap(short, long, default_value = "127.0.0.1:4000")]
     addr: String,
+    /// How keys and values are given: utf8, hex or base64
+    #[clap(long, global = true, default_value_t = Encoding::Utf8)]
+    input: Encoding,
+    /// How keys and values are printed: utf8, hex or base64
+    #[clap(long, global = true, default_value_t = Encoding::Utf8)]
+    output: Encoding,
     #[clap(subcommand)]
-    command: DBCommands,
+    command: Command,
 }
 
+/// Commands as typed on the command line, keys and values in `--input` encoding
+#[derive(Subcommand)]
+enum Command {
+    /// Set up value by key into KVS
+    Set {
+        key: String,
+        value: String,
+        /// Expire the key after given time, like 500ms, 30s, 5m or 1h
+        #[clap(long, value_parser = parse_ttl)]
+        ttl: Option<Duration>,
+    },
+    /// Get value by key
+    Get {
+        key: String,
+        /// Read from snapshot with given id
+        #[clap(long)]
+        snapshot: Option<u64>,
+    },
+    /// Removes value by key
+    Rm { key: String },
+    /// Remaining lifetime of the key
+    Ttl { key: String },
+    /// Get pairs with keys from start (inclusive) to end (exclusive)
+    Scan {
+        start: String,
+        end: Option<String>,
+        /// Maximum number of pairs
+        #[clap(short, long, default_value_t = 100)]
+        limit: u32,
+        /// Read from snapshot with given id
+        #[clap(long)]
+        snapshot: Option<u64>,
+    },
+    /// Get pairs with keys starting with prefix
+    ScanPrefix {
+        prefix: String,
+        /// Maximum number of pairs
+        #[clap(short, long, default_value_t = 100)]
+        limit: u32,
+        /// Read from snapshot with given id
+        #[clap(long)]
+        snapshot: Option<u64>,
+    },
+    /// Apply writes atomically, like: set KEY VALUE set KEY VALUE rm KEY
+    Batch {
+        #[clap(required = true)]
+        ops: Vec<String>,
+    },
+    /// Get value by key with its version
+    GetVersioned { key: String },
+    /// Write new value only when current one equals expected
+    Cas {
+        key: String,
+        /// Current value, missing means the key must not exist
+        #[clap(long)]
+        expected: Option<String>,
+        /// Value to write, missing means remove the key
+        #[clap(long)]
+        new: Option<String>,
+    },
+    /// Set value only when the key is missing, print its version
+    SetIfAbsent { key: String, value: String },
+    /// Set value only when current one has given version, print the new version
+    SetIfVersion {
+        key: String,
+        value: String,
+        version: u64,
+    },
+    /// Run operations in one transaction, like: get KEY set KEY VALUE rm KEY.
+    /// Values of gets are printed, commit fails if they were changed meanwhile
+    Txn {
+        #[clap(required = true)]
+        ops: Vec<String>,
+    },
+    /// Add delta to the value as integer, print the new value
+    Incr {
+        key: String,
+        #[clap(default_value_t = 1, allow_hyphen_values = true)]
+        delta: i64,
+    },
+    /// Subtract delta from the value as integer, print the new value
+    Decr {
+        key: String,
+        #[clap(default_value_t = 1, allow_hyphen_values = true)]
+        delta: i64,
+    },
+    /// Take snapshot of the server, print its id to read with `--snapshot`
+    Snapshot,
+    /// Drop snapshot by id
+    ReleaseSnapshot { id: u64 },
+}
+
+impl Command {
+    /// Decode keys and values into protocol commands sent over one connection
+    fn into_db_commands(self, input: Encoding) -> Result<Vec<DBCommands>, String> {
+        Ok(vec![match self {
+            Command::Set { key, value, ttl } => DBCommands::Set {
+                key: input.decode(&key)?,
+                value: input.decode(&value)?,
+                ttl,
+            },
+            Command::Get {
+                key,
+                snapshot: None,
+            } => DBCommands::Get {
+                key: input.decode(&key)?,
+            },
+            Command::Get {
+                key,
+                snapshot: Some(id),
+            } => DBCommands::SnapshotGet {
+                id,
+                key: input.decode(&key)?,
+            },
+            Command::Rm { key } => DBCommands::Rm {
+                key: input.decode(&key)?,
+            },
+            Command::Ttl { key } => DBCommands::Ttl {
+                key: input.decode(&key)?,
+            },
+            Command::Scan {
+                start,
+                end,
+                limit,
+                snapshot,
+            } => {
+                let start = input.decode(&start)?;
+                let end = end.map(|end| input.decode(&end)).transpose()?;
+                match snapshot {
+                    Some(id) => DBCommands::SnapshotScan {
+                        id,
+                        start,
+                        end,
+                        limit,
+                    },
+                    None => DBCommands::Scan { start, end, limit },
+                }
+            }
+            Command::ScanPrefix {
+                prefix,
+                limit,
+                snapshot,
+            } => {
+                let prefix = input.decode(&prefix)?;
+                match snapshot {
+                    Some(id) => DBCommands::SnapshotScanPrefix { id, prefix, limit },
+                    None => DBCommands::ScanPrefix { prefix, limit },
+                }
+            }
+            Command::Batch { ops } => DBCommands::Batch {
+                batch: parse_batch(&ops, input)?,
+            },
+            Command::GetVersioned { key } => DBCommands::GetVersioned {
+                key: input.decode(&key)?,
+            },
+            Command::Cas { key, expected, new } => DBCommands::Cas {
+                key: input.decode(&key)?,
+                expected: expected.map(|value| input.decode(&value)).transpose()?,
+                new: new.map(|value| input.decode(&value)).transpose()?,
+            },
+            Command::SetIfAbsent { key, value } => DBCommands::SetIfAbsent {
+                key: input.decode(&key)?,
+                value: input.decode(&value)?,
+            },
+            Command::SetIfVersion {
+                key,
+                value,
+                version,
+            } => DBCommands::SetIfVersion {
+                key: input.decode(&key)?,
+                value: input.decode(&value)?,
+                version,
+            },
+            Command::Txn { ops } => return parse_transaction(&ops, input),
+            Command::Incr { key, delta } => DBCommands::Incr {
+                key: input.decode(&key)?,
+                delta,
+            },
+            Command::Decr { key, delta } => DBCommands::Incr {
+                key: input.decode(&key)?,
+                delta: delta
+                    .checked_neg()
+                    .ok_or_else(|| format!("Cant decrement by {}", delta))?,
+            },
+            Command::Snapshot => DBCommands::Snapshot,
+            Command::ReleaseSnapshot { id } => DBCommands::ReleaseSnapshot { id },
+        }])
+    }
+}
+
+/// Parse writes like `set KEY VALUE` and `rm KEY` following each other
+fn parse_batch(ops: &[String], input: Encoding) -> Result<WriteBatch, String> {
+    let mut batch = WriteBatch::new();
+    let mut ops = ops.iter();
+    while let Some(op) = ops.next() {
+        match (op.as_str(), ops.next()) {
+            ("set", Some(key)) => match ops.next() {
+                Some(value) => batch.put(input.decode(key)?, input.decode(value)?),
+                None => return Err(format!("Missing value of {} in batch", key)),
+            },
+            ("rm", Some(key)) => batch.delete(input.decode(key)?),
+            _ => return Err(format!("Batch expects set KEY VALUE or rm KEY, got {}", op)),
+        };
+    }
+    Ok(batch)
+}
+
+/// Parse operations like `get KEY`, `set KEY VALUE` and `rm KEY`
+/// into commands between begin and commit of the transaction
+fn parse_transaction(ops: &[String], input: Encoding) -> Result<Vec<DBCommands>, String> {
+    let mut commands = vec![DBCommands::Begin];
+    let mut ops = ops.iter();
+    while let Some(op) = ops.next() {
+        let command = match (op.as_str(), ops.next()) {
+            ("get", Some(key)) => DBCommands::Get {
+                key: input.decode(key)?,
+            },
+            ("set", Some(key)) => match ops.next() {
+                Some(value) => DBCommands::Set {
+                    key: input.decode(key)?,
+                    value: input.decode(value)?,
+                    ttl: None,
+                },
+                None => return Err(format!("Missing value of {} in transaction", key)),
+            },
+            ("rm", Some(key)) => DBCommands::Rm {
+                key: input.decode(key)?,
+            },
+            _ => {
+                return Err(format!(
+                    "Transaction expects get KEY, set KEY VALUE or rm KEY, got {}",
+                    op
+                ))
+            }
+        };
+        commands.push(command);
+    }
+    commands.push(DBCommands::Commit);
+    Ok(commands)
+}
+
+/// Exit codes by class of the error
+const EXIT_USAGE: i32 = 2;
+const EXIT_KEY_NOT_FOUND: i32 = 3;
+const EXIT_NOT_APPLIED: i32 = 4;
+const EXIT_INVALID_REQUEST: i32 = 5;
+const EXIT_CONNECTION: i32 = 6;
+const EXIT_SERVER: i32 = 7;
+
 fn main() {
     let cli = Cli::parse();
 
-    let mut client = KVSClient::new(cli.addr).expect("cant create server");
-    let resp = client.send_cmd(cli.command).expect("IO error");
+    let commands = match cli.command.into_db_commands(cli.input) {
+        Ok(commands) => commands,
+        Err(message) => exit_with(&message, EXIT_USAGE),
+    };
+    let mut client = match KVSClient::new(cli.addr) {
+        Ok(client) => client,
+        Err(e) => exit_with(&e.to_string(), exit_code(&e)),
+    };
+    for command in commands {
+        match client.request(command) {
+            Ok(resp) => print_response(resp, cli.output),
+            Err(e) => exit_with(&e.to_string(), exit_code(&e)),
+        }
+    }
+}
+
+/// Exit code telling the class of the error
+fn exit_code(e: &KVSError) -> i32 {
+    if let KVSError::IOError { .. } = e {
+        // local IO error is a problem of the connection
+        return EXIT_CONNECTION;
+    }
+    match ErrorCode::from(e) {
+        ErrorCode::KeyNotFound => EXIT_KEY_NOT_FOUND,
+        ErrorCode::PreconditionFailed | ErrorCode::TransactionConflict => EXIT_NOT_APPLIED,
+        ErrorCode::NotAnInteger
+        | ErrorCode::InvalidCommand
+        | ErrorCode::SnapshotNotFound
+        | ErrorCode::TooLarge => EXIT_INVALID_REQUEST,
+        ErrorCode::Protocol | ErrorCode::UnsupportedVersion => EXIT_CONNECTION,
+        ErrorCode::Internal | ErrorCode::Io | ErrorCode::Corrupted | ErrorCode::Busy => EXIT_SERVER,
+    }
+}
+
+fn exit_with(message: &str, code: i32) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(code);
+}
+
+/// Print successful response in `output` encoding
+fn print_response(resp: ServerResponse, output: Encoding) {
     match resp {
         ServerResponse::Success { output } => {
             if !output.is_empty() {
                 println!("{}", output);
             }
         }
-        ServerResponse::Failure { message } => {
-            panic!("{}", message);
+        ServerResponse::Value { value } => {
+            println!("{}", output.encode(&value));
+        }
+        ServerResponse::NotFound => {
+            println!("Key not found");
+        }
+        ServerResponse::Entries { entries } => {
+            for (key, value) in entries {
+                println!("{}\t{}", output.encode(&key), output.encode(&value));
+            }
+        }
+        ServerResponse::Versioned { value, version } => {
+            println!("{}\t{}", output.encode(&value), version);
+        }
+        ServerResponse::Version { version } => {
+            println!("{}", version);
+        }
+        ServerResponse::Snapshot { id } => {
+            println!("{}", id);
+        }
+        ServerResponse::Integer { value } => {
+            println!("{}", value);
+        }
+        ServerResponse::Hello {
+            version,
+            capabilities,
+        } => {
+            println!("{}\t{}", version, capabilities.bits());
         }
+        // turned into errors by `KVSClient::request`
+        ServerResponse::Failure { .. } => unreachable!(),
+        // read by `KVSClient::get_stream`, which is not used here
+        ServerResponse::Stream { .. } | ServerResponse::Chunk { .. } => unreachable!(),
     }
 }
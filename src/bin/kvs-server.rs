@@ -1,10 +1,16 @@
 use clap::Parser;
 use env_logger::Env;
-use kvs::{KvStore, KvsServer, SledStore};
+use kvs::{
+    parse_ttl, Durability, FrameLimits, KvStore, KvStoreConfig, KvsEngine, KvsServer,
+    NaiveThreadPool, RayonThreadPool, ServerConfig, SharedQueueThreadPool, SledStore, ThreadPool,
+    DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_SNAPSHOTS,
+};
 use std::io::{Read, Write};
 use std::path::Path;
+use std::time::Duration;
 
-const LOCK_FILE: &str = "./.kvs.lock";
+/// Name of the file in the data directory with the engine of the data
+const LOCK_FILE: &str = ".kvs.lock";
 
 #[derive(Parser)]
 #[clap(
@@ -18,6 +24,51 @@ struct Cli {
     addr: String,
     #[clap(short, long)]
     engine: String,
+    /// Directory with the data of the engine, created when missing
+    #[clap(long, default_value_t = String::from("kvs-data"))]
+    data_dir: String,
+    /// Thread pool serving requests: naive, shared or rayon
+    #[clap(short, long, default_value_t = String::from("shared"))]
+    pool: String,
+    /// Number of pool workers, defaults to number of CPUs
+    #[clap(short, long)]
+    threads: Option<u32>,
+    /// When writes are synced to disk: none, every-write, interval:<ms> or group-commit
+    #[clap(short, long, default_value_t = Durability::EveryWrite)]
+    durability: Durability,
+    /// Close connections without requests for this long, like 60s or 500ms
+    #[clap(long, default_value = "60s", value_parser = parse_ttl)]
+    idle_timeout: Duration,
+    /// Most connections served at once, more get an error and are closed
+    #[clap(long, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+    /// Largest key accepted, in bytes
+    #[clap(long)]
+    max_key_size: Option<usize>,
+    /// Largest value accepted, in bytes, values larger than the frame size are streamed
+    #[clap(long)]
+    max_value_size: Option<usize>,
+    /// Largest packet accepted or sent, in bytes
+    #[clap(long)]
+    max_frame_size: Option<usize>,
+    /// Release snapshots not used for this long, like 5m or 30s
+    #[clap(long, default_value = "5m", value_parser = parse_ttl)]
+    snapshot_ttl: Duration,
+    /// Most snapshots kept at once
+    #[clap(long, default_value_t = DEFAULT_MAX_SNAPSHOTS)]
+    max_snapshots: usize,
+    /// Also serve Redis clients speaking RESP on this address
+    #[clap(long)]
+    resp_addr: Option<String>,
+    /// Also serve memcached clients speaking its text protocol on this address
+    #[clap(long)]
+    memcached_addr: Option<String>,
+    /// Let memcached clients run flush_all, which removes all keys of the engine
+    #[clap(long)]
+    memcached_flush: bool,
+    /// Also serve HTTP/JSON requests, like those of curl, on this address
+    #[clap(long)]
+    http_addr: Option<String>,
 }
 
 fn main() {
@@ -27,27 +78,84 @@ fn main() {
 
     let cli = Cli::parse();
 
+    let path = Path::new(&cli.data_dir).to_owned();
+    std::fs::create_dir_all(&path).expect("Cant create data directory");
     // Check engine in dir
-    check_engine(&cli.engine);
-    let path = Path::new(".");
+    check_engine(&path, &cli.engine);
+    log::info!("Engine -- {}", cli.engine);
+    log::info!("Data directory -- {:?}", path);
+    log::info!("Durability -- {}", cli.durability);
     match cli.engine.as_str() {
         "kvs" => {
-            let storage = KvStore::open(path).expect("Cant create kvs store");
-            let mut server = KvsServer::new(cli.addr, storage).expect("cant create server");
-            server.listen();
+            let config = KvStoreConfig {
+                durability: cli.durability,
+                ..KvStoreConfig::default()
+            };
+            let storage = KvStore::with_config(path, config).expect("Cant create kvs store");
+            run_with_pool(cli, storage);
         }
         "sled" => {
-            let storage = SledStore::open(path).expect("Cant create sled store");
-            let mut server = KvsServer::new(cli.addr, storage).expect("cant create server");
-            server.listen();
+            let storage = SledStore::open_with_durability(&path, cli.durability)
+                .expect("Cant create sled store");
+            run_with_pool(cli, storage);
         }
         _ => panic!("Only kvs engine is an option"),
     };
 }
 
-fn check_engine(engine: &str) {
-    if Path::new(LOCK_FILE).exists() {
-        let mut file = std::fs::File::open(LOCK_FILE).expect("Cant open");
+fn run_with_pool<S: KvsEngine>(cli: Cli, storage: S) {
+    let threads = cli.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(4)
+    });
+    log::info!("Pool -- {} with {} threads", cli.pool, threads);
+    let defaults = FrameLimits::default();
+    let config = ServerConfig {
+        idle_timeout: Some(cli.idle_timeout),
+        max_connections: cli.max_connections,
+        limits: FrameLimits {
+            max_key_size: cli.max_key_size.unwrap_or(defaults.max_key_size),
+            max_value_size: cli.max_value_size.unwrap_or(defaults.max_value_size),
+            max_frame_size: cli.max_frame_size.unwrap_or(defaults.max_frame_size),
+        },
+        snapshot_ttl: cli.snapshot_ttl,
+        max_snapshots: cli.max_snapshots,
+        resp_addr: cli.resp_addr.clone(),
+        memcached_addr: cli.memcached_addr.clone(),
+        memcached_flush: cli.memcached_flush,
+        http_addr: cli.http_addr.clone(),
+    };
+    log::info!("Idle timeout -- {:?}", cli.idle_timeout);
+    log::info!("Limits -- {:?}", config.limits);
+    match cli.pool.as_str() {
+        "naive" => run(cli.addr, storage, NaiveThreadPool::new(threads), config),
+        "shared" => run(
+            cli.addr,
+            storage,
+            SharedQueueThreadPool::new(threads),
+            config,
+        ),
+        "rayon" => run(cli.addr, storage, RayonThreadPool::new(threads), config),
+        _ => panic!("Pool must be one of: naive, shared, rayon"),
+    }
+}
+
+fn run<S: KvsEngine, P: ThreadPool + Send + 'static>(
+    addr: String,
+    storage: S,
+    pool: kvs::Result<P>,
+    config: ServerConfig,
+) {
+    let pool = pool.expect("cant create thread pool");
+    let server = KvsServer::with_config(addr, storage, pool, config).expect("cant create server");
+    server.listen().expect("cant listen");
+}
+
+fn check_engine(dir: &Path, engine: &str) {
+    let lock_path = dir.join(LOCK_FILE);
+    if lock_path.exists() {
+        let mut file = std::fs::File::open(&lock_path).expect("Cant open");
         let mut contents = String::new();
         file.read_to_string(&mut contents)
             .expect("Cant read from file");
@@ -55,7 +163,7 @@ fn check_engine(engine: &str) {
             panic!("Use previous engine: --engine={}", contents);
         }
     } else {
-        let mut file = std::fs::File::create(LOCK_FILE).expect("cant create file");
+        let mut file = std::fs::File::create(&lock_path).expect("cant create file");
         file.write_all(engine.as_bytes())
             .expect("Cant write to file");
         log::info!("Created lock file with engine -- {}", engine);
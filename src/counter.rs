@@ -0,0 +1,22 @@
+// #![deny(missing_docs)]
+//! Counters shared by storage engines
+//!
+//! Counter is stored as decimal i64 text like `-12`,
+//! so it is read and set as a usual value.
+use crate::error::{KVSError, Result};
+
+/// Add `delta` to the counter stored in `value`, missing value counts as 0.
+/// Return the new counter with its stored form
+pub(crate) fn add(value: Option<&[u8]>, delta: i64) -> Result<(i64, Vec<u8>)> {
+    let current = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KVSError::NotAnIntegerError)?,
+        None => 0,
+    };
+    let counter = current
+        .checked_add(delta)
+        .ok_or(KVSError::NotAnIntegerError)?;
+    Ok((counter, counter.to_string().into_bytes()))
+}
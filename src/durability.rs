@@ -0,0 +1,101 @@
+// #![deny(missing_docs)]
+//! Durability policy shared by storage engines
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+
+use crate::error::Result;
+
+/// When written data is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never sync explicitly, leave it to OS or engine
+    #[default]
+    None,
+    /// Sync data before every write returns
+    EveryWrite,
+    /// Sync data in background every given number of milliseconds
+    Interval(u64),
+    /// Writers wait for sync, concurrent writers share one sync
+    GroupCommit,
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Durability::None => write!(f, "none"),
+            Durability::EveryWrite => write!(f, "every-write"),
+            Durability::Interval(ms) => write!(f, "interval:{}", ms),
+            Durability::GroupCommit => write!(f, "group-commit"),
+        }
+    }
+}
+
+/// Parse `none`, `every-write`, `interval:<ms>` or `group-commit`
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "every-write" => Ok(Durability::EveryWrite),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ => match s.strip_prefix("interval:").map(str::parse::<u64>) {
+                Some(Ok(ms)) if ms > 0 => Ok(Durability::Interval(ms)),
+                _ => Err(format!(
+                    "Durability must be one of: none, every-write, interval:<ms>, group-commit. Got {}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Batches waiting writers into one sync.
+///
+/// Every write gets increasing sequence number, writer waits until
+/// its number is synced. First waiter becomes leader and syncs
+/// everything written so far, the others wait for it.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct GroupCommitState {
+    synced_seq: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Wait until write `seq` is synced.
+    /// `sync` syncs data and returns sequence number of the last write it covers
+    pub(crate) fn commit<F>(&self, seq: u64, sync: F) -> Result<()>
+    where
+        F: Fn() -> Result<u64>,
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced_seq >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // become leader, sync all writes done so far
+            state.syncing = true;
+            drop(state);
+            let result = sync();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if let Ok(covered_seq) = result {
+                state.synced_seq = state.synced_seq.max(covered_seq);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// #![deny(missing_docs)]
+//! Text representation of raw keys and values
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// How bytes are written as text on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Text as is, not UTF-8 bytes are shown with replacement character
+    #[default]
+    Utf8,
+    /// Two hex digits per byte
+    Hex,
+    /// Standard base64 with padding
+    Base64,
+}
+
+impl Encoding {
+    /// Bytes from their text representation
+    pub fn decode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Hex => hex::decode(text).map_err(|e| format!("Invalid hex {}: {}", text, e)),
+            Encoding::Base64 => BASE64
+                .decode(text)
+                .map_err(|e| format!("Invalid base64 {}: {}", text, e)),
+        }
+    }
+
+    /// Text representation of bytes
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Hex => hex::encode(bytes),
+            Encoding::Base64 => BASE64.encode(bytes),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "utf8"),
+            Encoding::Hex => write!(f, "hex"),
+            Encoding::Base64 => write!(f, "base64"),
+        }
+    }
+}
+
+/// Parse `utf8`, `hex` or `base64`
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(Encoding::Utf8),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            _ => Err(format!(
+                "Encoding must be one of: utf8, hex, base64. Got {}",
+                s
+            )),
+        }
+    }
+}
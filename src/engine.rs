@@ -1,8 +1,150 @@
-use crate::error::Result;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use crate::batch::WriteBatch;
+use crate::error::{KVSError, Result};
+use crate::snapshot::KvsSnapshot;
+use crate::transaction::{Transaction, DEFAULT_TRANSACTION_ATTEMPTS};
+
+/// Bytes of a value read in pieces, see `KvsEngine::get_reader`
+pub type ValueReader = Box<dyn Read + Send>;
 
 /// General interface for Server to use
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+///
+/// Keys and values are arbitrary bytes.
+/// Engines are cheap to clone handles over shared state,
+/// so every worker thread can own its own copy.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view of the engine
+    type Snapshot: KvsSnapshot;
+    /// Set value without expiration, removing TTL of the previous value
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Set value which expires after `ttl`
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+    /// Apply all puts and deletes of the batch atomically,
+    /// after a crash either all of them are visible or none
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Remaining lifetime of the key, `None` when key never expires.
+    /// Missing or expired key is `KeyNotFoundError`
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+    /// Get at most `limit` pairs with keys in range [start, end) ordered by key,
+    /// `None` end means no upper bound
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Get at most `limit` pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Take point-in-time view, later writes are not visible through it
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Get value with its version, every set of any key gets a higher version
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+    /// Write `new` only when current value equals `expected`,
+    /// `None` expected means missing key and `None` new removes the key.
+    /// Otherwise `PreconditionFailedError`
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+    /// Set value only when the key is missing, return version of the value.
+    /// Otherwise `PreconditionFailedError`
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64>;
+    /// Set value only when current value has `version`, return the new version.
+    /// Otherwise `PreconditionFailedError`
+    fn set_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<u64>;
+    /// Like `set_if_absent` for value which expires after `ttl`
+    fn set_if_absent_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<u64>;
+    /// Like `set_if_version` for value which expires after `ttl`
+    fn set_if_version_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+        ttl: Duration,
+    ) -> Result<u64>;
+    /// Atomically add `delta` to the value as decimal i64, missing key counts as 0.
+    /// Return the new value, TTL of the key is kept.
+    /// Value which is not an i64 or overflow is `NotAnIntegerError`
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+    /// Apply writes atomically only when every key of `reads` still has the version
+    /// seen, `None` meaning missing key. Otherwise `TransactionConflictError`.
+    /// Used by `Transaction::commit`
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: WriteBatch,
+    ) -> Result<()>;
+
+    /// Set value of `len` bytes read from `value` in pieces, which expires
+    /// after `ttl` when given. Value shorter than `len` sets nothing.
+    /// Engines which can write the value as it is read never hold it whole,
+    /// the default reads it into memory and sets it
+    fn set_from_reader(
+        &self,
+        key: Vec<u8>,
+        value: &mut dyn Read,
+        len: u64,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        value.take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            let e = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "value is shorter than its length",
+            );
+            return Err(e.into());
+        }
+        match ttl {
+            Some(ttl) => self.set_with_ttl(key, buf, ttl),
+            None => self.set(key, buf),
+        }
+    }
+    /// Length of the value by key and reader of its bytes, `None` for missing key.
+    /// Engines which can read the value as the reader is read never hold
+    /// it whole, the default gets it into memory
+    fn get_reader(&self, key: Vec<u8>) -> Result<Option<(u64, ValueReader)>> {
+        Ok(self.get(key)?.map(|value| {
+            let len = value.len() as u64;
+            (len, Box::new(io::Cursor::new(value)) as ValueReader)
+        }))
+    }
+    /// Atomically subtract `delta` from the value, see `incr`
+    fn decr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KVSError::NotAnIntegerError)?;
+        self.incr(key, delta)
+    }
+    /// Start optimistic transaction
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::begin(self.clone())
+    }
+    /// Run `f` in a transaction and commit it, running again on conflicts
+    /// up to `DEFAULT_TRANSACTION_ATTEMPTS` times. Error of `f` is returned as is
+    fn transact<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<Self>) -> Result<T>,
+    {
+        self.transact_with_attempts(DEFAULT_TRANSACTION_ATTEMPTS, f)
+    }
+    /// Like `transact` with given number of attempts
+    fn transact_with_attempts<T, F>(&self, attempts: usize, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<Self>) -> Result<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            let mut transaction = self.begin();
+            let res = f(&mut transaction).and_then(|out| transaction.commit().map(|()| out));
+            match res {
+                Err(KVSError::TransactionConflictError) if attempt < attempts => attempt += 1,
+                res => return res,
+            }
+        }
+    }
 }
@@ -2,63 +2,275 @@
 //! Module with key-value storage
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
 
 #[derive(Debug)]
 pub enum KVSError {
-    GeneralKVSError,
+    /// Key is missing or expired
     KeyNotFoundError,
-    IOError,
-    SerdeJsonError,
-    FromUtf8Error,
-    SledError,
+    /// Input/output failure, with the file accessed when known
+    IOError {
+        source: io::Error,
+        path: Option<PathBuf>,
+    },
+    /// JSON of legacy log records cant be (de)serialized
+    SerdeJsonError { source: serde_json::Error },
+    /// Bytes expected to be a string are not UTF-8
+    FromUtf8Error { source: FromUtf8Error },
+    /// Failure of the sled engine
+    SledError { source: sled::Error },
+    /// Rayon pool cant be built
+    ThreadPoolError { source: rayon::ThreadPoolBuildError },
+    /// Condition of the conditional write does not hold
+    PreconditionFailedError,
+    /// Key read by the transaction was changed before its commit
+    TransactionConflictError,
+    /// Counter value is not an i64 or the result overflows
+    NotAnIntegerError,
+    /// Damaged record in the log generation file at offset
+    CorruptedLogError {
+        path: PathBuf,
+        gen: u64,
+        offset: u64,
+    },
+    /// Stored bytes cant be decoded, like damaged record or file of unknown format
+    CorruptedDataError { reason: String },
+    /// Peer sent packet which does not follow the protocol
+    ProtocolError { reason: String },
+    /// Key, value or packet is larger than the limit
+    TooLargeError { reason: String },
+    /// Failure reported by the server which has no own variant
+    ServerError { code: ErrorCode, message: String },
+}
+
+/// Class of the error sent in failure response, so clients can tell
+/// a missing key from a disk failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Failure without more specific class
+    Internal,
+    KeyNotFound,
+    /// Disk or other IO failure of the server
+    Io,
+    /// Stored data is damaged
+    Corrupted,
+    /// Request does not follow the protocol
+    Protocol,
+    PreconditionFailed,
+    TransactionConflict,
+    NotAnInteger,
+    /// Command is not valid in the state of the session, like commit without transaction
+    InvalidCommand,
+    SnapshotNotFound,
+    /// Client and server have no protocol version in common
+    UnsupportedVersion,
+    /// Key, value or packet is larger than the limit of the server
+    TooLarge,
+    /// Server serves as many connections as it allows, try again later
+    Busy,
+}
+
+impl ErrorCode {
+    /// Number sent over the protocol
+    pub fn as_u16(self) -> u16 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::KeyNotFound => 2,
+            ErrorCode::Io => 3,
+            ErrorCode::Corrupted => 4,
+            ErrorCode::Protocol => 5,
+            ErrorCode::PreconditionFailed => 6,
+            ErrorCode::TransactionConflict => 7,
+            ErrorCode::NotAnInteger => 8,
+            ErrorCode::InvalidCommand => 9,
+            ErrorCode::SnapshotNotFound => 10,
+            ErrorCode::UnsupportedVersion => 11,
+            ErrorCode::TooLarge => 12,
+            ErrorCode::Busy => 13,
+        }
+    }
+
+    /// Code by its number, unknown numbers of newer servers are `Internal`
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            2 => ErrorCode::KeyNotFound,
+            3 => ErrorCode::Io,
+            4 => ErrorCode::Corrupted,
+            5 => ErrorCode::Protocol,
+            6 => ErrorCode::PreconditionFailed,
+            7 => ErrorCode::TransactionConflict,
+            8 => ErrorCode::NotAnInteger,
+            9 => ErrorCode::InvalidCommand,
+            10 => ErrorCode::SnapshotNotFound,
+            11 => ErrorCode::UnsupportedVersion,
+            12 => ErrorCode::TooLarge,
+            13 => ErrorCode::Busy,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Error is a failure of the server rather than of the request
+    pub fn is_server_fault(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Internal | ErrorCode::Io | ErrorCode::Corrupted
+        )
+    }
+}
+
+impl From<&KVSError> for ErrorCode {
+    fn from(e: &KVSError) -> Self {
+        match e {
+            KVSError::KeyNotFoundError => ErrorCode::KeyNotFound,
+            KVSError::IOError { .. } => ErrorCode::Io,
+            KVSError::SledError { .. }
+            | KVSError::SerdeJsonError { .. }
+            | KVSError::ThreadPoolError { .. } => ErrorCode::Internal,
+            KVSError::CorruptedLogError { .. } | KVSError::CorruptedDataError { .. } => {
+                ErrorCode::Corrupted
+            }
+            KVSError::FromUtf8Error { .. } | KVSError::ProtocolError { .. } => ErrorCode::Protocol,
+            KVSError::PreconditionFailedError => ErrorCode::PreconditionFailed,
+            KVSError::TransactionConflictError => ErrorCode::TransactionConflict,
+            KVSError::NotAnIntegerError => ErrorCode::NotAnInteger,
+            KVSError::TooLargeError { .. } => ErrorCode::TooLarge,
+            KVSError::ServerError { code, .. } => *code,
+        }
+    }
+}
+
+impl KVSError {
+    /// Typed error of the failure response, errors without own variant
+    /// become `ServerError`
+    pub fn from_failure(code: ErrorCode, message: String) -> KVSError {
+        match code {
+            ErrorCode::KeyNotFound => KVSError::KeyNotFoundError,
+            ErrorCode::PreconditionFailed => KVSError::PreconditionFailedError,
+            ErrorCode::TransactionConflict => KVSError::TransactionConflictError,
+            ErrorCode::NotAnInteger => KVSError::NotAnIntegerError,
+            ErrorCode::Corrupted => KVSError::CorruptedDataError { reason: message },
+            ErrorCode::Protocol => KVSError::ProtocolError { reason: message },
+            ErrorCode::TooLarge => KVSError::TooLargeError { reason: message },
+            code => KVSError::ServerError { code, message },
+        }
+    }
+
+    /// Wrap IO error of accessing the file at `path`, for `map_err`
+    pub(crate) fn io_at(path: &Path) -> impl FnOnce(io::Error) -> KVSError + '_ {
+        move |source| KVSError::IOError {
+            source,
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    /// Add the file to IO error which has none, for `map_err` of operations on it
+    pub(crate) fn in_file(path: &Path) -> impl FnOnce(KVSError) -> KVSError + '_ {
+        move |e| match e {
+            KVSError::IOError { source, path: None } => KVSError::IOError {
+                source,
+                path: Some(path.to_path_buf()),
+            },
+            e => e,
+        }
+    }
+
+    /// Stored bytes cant be decoded for the reason
+    pub(crate) fn corrupted(reason: impl Into<String>) -> KVSError {
+        KVSError::CorruptedDataError {
+            reason: reason.into(),
+        }
+    }
+
+    /// Size of `what` is over the limit
+    pub(crate) fn too_large(what: &str, size: u64, limit: u64) -> KVSError {
+        KVSError::TooLargeError {
+            reason: format!("{} of {} bytes, limit is {}", what, size, limit),
+        }
+    }
+
+    /// Received packet breaks the protocol for the reason
+    pub(crate) fn protocol(reason: impl Into<String>) -> KVSError {
+        KVSError::ProtocolError {
+            reason: reason.into(),
+        }
+    }
 }
 
 impl Display for KVSError {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
             KVSError::KeyNotFoundError => write!(f, "Key not found"),
-            KVSError::IOError => write!(f, "Imput output error"),
-            KVSError::SerdeJsonError => write!(f, "Json serialization error"),
-            KVSError::GeneralKVSError => write!(f, "Unknown error"),
-            KVSError::FromUtf8Error => write!(f, "Cant converct to string"),
-            KVSError::SledError => write!(f, "Sled engine error"),
+            KVSError::IOError {
+                source,
+                path: Some(path),
+            } => write!(f, "IO error on {:?}: {}", path, source),
+            KVSError::IOError { source, path: None } => write!(f, "IO error: {}", source),
+            KVSError::SerdeJsonError { source } => {
+                write!(f, "Json serialization error: {}", source)
+            }
+            KVSError::FromUtf8Error { source } => write!(f, "Cant convert to string: {}", source),
+            KVSError::SledError { source } => write!(f, "Sled engine error: {}", source),
+            KVSError::ThreadPoolError { source } => write!(f, "Cant build thread pool: {}", source),
+            KVSError::PreconditionFailedError => write!(f, "Precondition failed"),
+            KVSError::TransactionConflictError => write!(f, "Transaction conflict"),
+            KVSError::NotAnIntegerError => write!(f, "Value is not an integer or out of range"),
+            KVSError::CorruptedLogError { path, gen, offset } => {
+                write!(
+                    f,
+                    "Log generation {} is corrupted at offset {} of {:?}",
+                    gen, offset, path
+                )
+            }
+            KVSError::CorruptedDataError { reason } => write!(f, "Corrupted data: {}", reason),
+            KVSError::ProtocolError { reason } => write!(f, "Protocol violation: {}", reason),
+            KVSError::TooLargeError { reason } => write!(f, "Too large: {}", reason),
+            KVSError::ServerError { message, .. } => write!(f, "{}", message),
         }
     }
 }
 
 impl Error for KVSError {
-    fn description(&self) -> &str {
-        "KVS error .. please get some help"
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KVSError::IOError { source, .. } => Some(source),
+            KVSError::SerdeJsonError { source } => Some(source),
+            KVSError::FromUtf8Error { source } => Some(source),
+            KVSError::SledError { source } => Some(source),
+            KVSError::ThreadPoolError { source } => Some(source),
+            _ => None,
+        }
     }
 }
 
-impl From<std::io::Error> for KVSError {
-    fn from(_err: std::io::Error) -> KVSError {
-        KVSError::IOError
+impl From<io::Error> for KVSError {
+    fn from(source: io::Error) -> KVSError {
+        KVSError::IOError { source, path: None }
     }
 }
 
 impl From<serde_json::Error> for KVSError {
-    fn from(_err: serde_json::Error) -> KVSError {
-        KVSError::SerdeJsonError
+    fn from(source: serde_json::Error) -> KVSError {
+        KVSError::SerdeJsonError { source }
     }
 }
 
-impl From<std::string::FromUtf8Error> for KVSError {
-    fn from(_err: std::string::FromUtf8Error) -> KVSError {
-        KVSError::FromUtf8Error
+impl From<FromUtf8Error> for KVSError {
+    fn from(source: FromUtf8Error) -> KVSError {
+        KVSError::FromUtf8Error { source }
     }
 }
 
 impl From<sled::Error> for KVSError {
-    fn from(_err: sled::Error) -> KVSError {
-        KVSError::SledError
+    fn from(source: sled::Error) -> KVSError {
+        KVSError::SledError { source }
     }
 }
 
-impl From<String> for KVSError {
-    fn from(_err: String) -> KVSError {
-        KVSError::GeneralKVSError
+impl From<rayon::ThreadPoolBuildError> for KVSError {
+    fn from(source: rayon::ThreadPoolBuildError) -> KVSError {
+        KVSError::ThreadPoolError { source }
     }
 }
 
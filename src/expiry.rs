@@ -0,0 +1,104 @@
+// #![deny(missing_docs)]
+//! Expiration of keys shared by storage engines
+//!
+//! Expiration time is stored as milliseconds since UNIX epoch,
+//! so it survives restarts of the store.
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+
+/// How often expired keys are removed in background by default
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Current time in milliseconds since UNIX epoch
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Expiration time of the key set now with given TTL
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_ms().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Key with this expiration time is already gone
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Remaining lifetime of the key, `None` for keys without expiration
+pub(crate) fn remaining(expires_at: Option<u64>, now: u64) -> Option<Duration> {
+    expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now)))
+}
+
+/// Parse TTL like `30s`, `500ms`, `5m`, `1h`, plain number means seconds
+pub fn parse_ttl(s: &str) -> std::result::Result<Duration, String> {
+    let (number, unit_ms) = if let Some(number) = s.strip_suffix("ms") {
+        (number, 1)
+    } else if let Some(number) = s.strip_suffix('s') {
+        (number, 1000)
+    } else if let Some(number) = s.strip_suffix('m') {
+        (number, 60 * 1000)
+    } else if let Some(number) = s.strip_suffix('h') {
+        (number, 60 * 60 * 1000)
+    } else {
+        (s, 1000)
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number
+            .checked_mul(unit_ms)
+            .map(Duration::from_millis)
+            .ok_or_else(|| "ttl too large".to_owned()),
+        _ => Err(format!(
+            "TTL must be positive number with unit ms, s, m or h. Got {}",
+            s
+        )),
+    }
+}
+
+/// Background thread removing expired keys, or other expired entries,
+/// every interval, stopped and joined when dropped
+#[derive(Debug)]
+pub(crate) struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Run `sweep` every `interval` until the sweeper is dropped
+    pub(crate) fn spawn<F>(interval: Duration, sweep: F) -> Result<Self>
+    where
+        F: Fn() -> Result<usize> + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new().spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match sweep() {
+                    Ok(0) => {}
+                    Ok(removed) => log::debug!("Removed {} expired entries", removed),
+                    Err(e) => log::error!("Sweeping expired entries failed: {}", e),
+                }
+            }
+        })?;
+        Ok(Sweeper {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // disconnected channel stops the thread
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("Sweeper thread panicked");
+            }
+        }
+    }
+}
@@ -1,19 +1,52 @@
-pub use engine::KvsEngine;
-pub use error::{KVSError, Result};
-pub use storages::kv_store::KvStore;
-pub use storages::sled_store::SledStore;
-pub use tcp::client::KVSClient;
-pub use tcp::protocol::{DBCommands, ServerResponse};
-pub use tcp::server::KvsServer;
+pub use batch::{BatchOp, WriteBatch};
+pub use durability::Durability;
+pub use encoding::Encoding;
+pub use engine::{KvsEngine, ValueReader};
+pub use error::{ErrorCode, KVSError, Result};
+pub use expiry::{parse_ttl, DEFAULT_SWEEP_INTERVAL};
+pub use snapshot::KvsSnapshot;
+pub use storages::kv_log::RecoveryPolicy;
+pub use storages::kv_store::{KvStore, KvStoreConfig, KvStoreSnapshot};
+pub use storages::sled_store::{SledSnapshot, SledStore};
+pub use tcp::client::{KVSClient, Pipeline};
+pub use tcp::protocol::{
+    Capabilities, DBCommands, FrameLimits, RequestId, ServerResponse, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, STREAM_CHUNK_SIZE,
+};
+pub use tcp::server::{KvsServer, ServerConfig, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS};
+pub use tcp::session::{DEFAULT_MAX_SNAPSHOTS, DEFAULT_SNAPSHOT_TTL};
+pub use thread_pool::ThreadPool;
+pub use thread_pools::naive::NaiveThreadPool;
+pub use thread_pools::rayon_pool::RayonThreadPool;
+pub use thread_pools::shared_queue::SharedQueueThreadPool;
+pub use transaction::{Transaction, DEFAULT_TRANSACTION_ATTEMPTS};
 
+mod batch;
+mod counter;
+mod durability;
+mod encoding;
 mod engine;
 mod error;
+mod expiry;
+mod snapshot;
 mod storages {
+    pub mod kv_log;
     pub mod kv_store;
     pub mod sled_store;
 }
 mod tcp {
     pub mod client;
+    pub mod http;
+    pub mod memcached;
     pub mod protocol;
+    pub mod resp;
     pub mod server;
+    pub mod session;
 }
+mod thread_pool;
+mod thread_pools {
+    pub mod naive;
+    pub mod rayon_pool;
+    pub mod shared_queue;
+}
+mod transaction;
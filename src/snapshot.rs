@@ -0,0 +1,21 @@
+// #![deny(missing_docs)]
+//! Read-only point-in-time views of engines
+use crate::error::Result;
+
+/// Read-only view of the engine at the time `KvsEngine::snapshot` was called,
+/// later writes are not visible through it.
+/// Snapshots are cheap to clone handles, the view lives until the last one is dropped
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Get value by key as it was at the time of the snapshot
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Get at most `limit` pairs with keys in range [start, end) ordered by key,
+    /// `None` end means no upper bound
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Get at most `limit` pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
@@ -0,0 +1,799 @@
+// #![deny(missing_docs)]
+//! On-disk format of the KvStore log
+//!
+//! Every generation file starts with a header (magic + format version)
+//! followed by framed records: payload length, CRC-32 of the payload,
+//! CRC-32 of both of them and the payload itself. Length has its own
+//! checksum, so damaged length is never taken for a record cut off by
+//! interrupted write. Payload is binary: tag of the record,
+//! key length, value length, raw key and value bytes. Set also has
+//! expiration time (ms since UNIX epoch, 0 for none) and version of the value
+//! before the key. Sets of previous versions have no version, or only
+//! expiration time under their own tag.
+//! Batch is a single record: its tag, number of writes and payloads of
+//! the writes one after another, so CRC covers the whole batch.
+//! Version mark is its tag and the highest version given so far.
+//!
+//! Older logs are still readable: version 2 has binary payloads in frames
+//! without checksum of the length, version 1 has JSON payloads in such frames,
+//! files without header are plain stream of JSON records. Compaction
+//! rewrites live records of such files in the current format.
+//!
+//! Generations written by compaction get a hint file next to them:
+//! key, offset, length, expiration time and version of every record, so the
+//! index can be loaded without reading the values. It ends with CRC-32 of the
+//! whole content. Version 1 hints have neither expiration time nor version,
+//! version 2 hints have no version.
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::path::Path;
+
+use crate::engine::ValueReader;
+use crate::error::{KVSError, Result};
+
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+const FRAMED_JSON_VERSION: u8 = 1;
+const BINARY_VERSION: u8 = 2;
+const CHECKED_LENGTH_VERSION: u8 = 3;
+/// Size of magic and version at the start of the file
+pub const LOG_HEADER_SIZE: u64 = 5;
+/// Size of payload length and checksum before every payload
+const FRAME_HEADER_SIZE: usize = 8;
+/// Size of payload length, its checksum and checksum of both
+const CHECKED_FRAME_HEADER_SIZE: usize = 12;
+/// Bytes read at once while looking for the next valid frame
+const SCAN_WINDOW_SIZE: u64 = 64 * 1024;
+/// Size of tag, key length and value length of binary payload
+const PAYLOAD_HEADER_SIZE: usize = 9;
+
+const HINT_MAGIC: &[u8; 4] = b"KVSH";
+const HINT_VERSION_NO_EXPIRY: u8 = 1;
+const HINT_VERSION_NO_VERSION: u8 = 2;
+const HINT_VERSION: u8 = 3;
+/// Size of key length, offset and record length of the hint entry
+const HINT_ENTRY_HEADER_SIZE: usize = 16;
+/// Size of expiration time in set and hint entry, 0 means no expiration
+const EXPIRES_SIZE: usize = 8;
+/// Size of value version in set and hint entry
+const VERSION_SIZE: usize = 8;
+
+const SET_TAG: u8 = 1;
+const RM_TAG: u8 = 2;
+const SET_TTL_TAG: u8 = 3;
+const BATCH_TAG: u8 = 4;
+const SET_VERSIONED_TAG: u8 = 5;
+const VERSION_MARK_TAG: u8 = 6;
+/// Size of tag and number of writes of batch payload
+const BATCH_HEADER_SIZE: usize = 5;
+
+/// Record of the log
+pub enum DBInsertion {
+    /// Set up value by key into KVS, optionally expiring at given ms since UNIX epoch.
+    /// Records of previous versions have version 0
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        version: u64,
+    },
+    /// Removes value by key
+    Rm { key: Vec<u8> },
+    /// Sets and removes applied atomically
+    Batch { insertions: Vec<DBInsertion> },
+    /// Highest version given so far, so versions of records dropped
+    /// by compaction are not given again
+    VersionMark { version: u64 },
+}
+
+impl DBInsertion {
+    /// Value set by key with its expiration time, last one for batch
+    pub fn value_of(self, key: &[u8]) -> Option<(Vec<u8>, Option<u64>)> {
+        match self {
+            DBInsertion::Set {
+                key: set_key,
+                value,
+                expires_at,
+                ..
+            } if set_key == key => Some((value, expires_at)),
+            DBInsertion::Batch { insertions } => insertions
+                .into_iter()
+                .rev()
+                .find_map(|insertion| insertion.value_of(key)),
+            _ => None,
+        }
+    }
+
+    /// Highest version of values set by the insertion, 0 for removal
+    pub fn version(&self) -> u64 {
+        match self {
+            DBInsertion::Set { version, .. } | DBInsertion::VersionMark { version } => *version,
+            DBInsertion::Rm { .. } => 0,
+            DBInsertion::Batch { insertions } => insertions
+                .iter()
+                .map(DBInsertion::version)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Record of JSON logs, which could hold only strings
+#[derive(Deserialize)]
+enum JsonInsertion {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+impl From<JsonInsertion> for DBInsertion {
+    fn from(insertion: JsonInsertion) -> Self {
+        match insertion {
+            JsonInsertion::Set { key, value } => DBInsertion::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+                version: 0,
+            },
+            JsonInsertion::Rm { key } => DBInsertion::Rm {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+/// Layout of records in the generation file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Headerless stream of JSON records without checksums
+    LegacyJson,
+    /// JSON payloads in frames with length and CRC
+    FramedJson,
+    /// Binary payloads in frames with length and CRC
+    Binary,
+    /// Binary payloads in frames with length, CRC and CRC of the frame header
+    BinaryCheckedLength,
+}
+
+impl LogFormat {
+    /// Size of the frame before its payload
+    fn frame_header_size(self) -> usize {
+        match self {
+            LogFormat::LegacyJson => 0,
+            LogFormat::FramedJson | LogFormat::Binary => FRAME_HEADER_SIZE,
+            LogFormat::BinaryCheckedLength => CHECKED_FRAME_HEADER_SIZE,
+        }
+    }
+
+    /// Whether length of the frame is covered by checksum
+    fn checks_length(self) -> bool {
+        self == LogFormat::BinaryCheckedLength
+    }
+}
+
+/// What to do with damaged records found on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryPolicy {
+    /// Any damaged record fails the open
+    Strict,
+    /// Incomplete record at the end of the newest generation (interrupted write)
+    /// is cut off, damaged record anywhere else fails the open.
+    /// Record is taken for torn only when no valid record follows it
+    #[default]
+    TruncateTornTail,
+    /// Cut off torn tail and skip damaged records, or damaged end of
+    /// older generations
+    SkipCorrupted,
+}
+
+/// Write header of the new generation file
+pub fn write_header(file: &mut File) -> Result<()> {
+    file.write_all(LOG_MAGIC)?;
+    file.write_all(&[CHECKED_LENGTH_VERSION])?;
+    Ok(())
+}
+
+/// Detect format of the generation file by its header
+pub fn read_format(file: &mut File) -> Result<LogFormat> {
+    let file_len = file.metadata()?.len();
+    let mut header = Vec::with_capacity(LOG_HEADER_SIZE as usize);
+    file.seek(SeekFrom::Start(0))?;
+    file.take(LOG_HEADER_SIZE).read_to_end(&mut header)?;
+
+    if !LOG_MAGIC.starts_with(&header[..header.len().min(LOG_MAGIC.len())]) {
+        return Ok(LogFormat::LegacyJson);
+    }
+    if file_len < LOG_HEADER_SIZE {
+        // header write was interrupted, no records in the file
+        return Ok(LogFormat::BinaryCheckedLength);
+    }
+    match header[LOG_MAGIC.len()] {
+        FRAMED_JSON_VERSION => Ok(LogFormat::FramedJson),
+        BINARY_VERSION => Ok(LogFormat::Binary),
+        CHECKED_LENGTH_VERSION => Ok(LogFormat::BinaryCheckedLength),
+        version => {
+            log::error!("Unknown log format version {}", version);
+            Err(KVSError::corrupted(format!(
+                "unknown log format version {}",
+                version
+            )))
+        }
+    }
+}
+
+/// Pack insertion into frame of the current format
+pub fn encode(insertion: &DBInsertion) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    encode_payload(insertion, &mut payload)?;
+    let header = frame_header(payload.len() as u32, crc32fast::hash(&payload));
+    Ok([header, payload].concat())
+}
+
+/// Pack frame of set up to its value, for value of `value_len` bytes written
+/// right after it in pieces. `value_checksum` is CRC-32 state of the value
+pub fn encode_set_head(
+    key: &[u8],
+    value_len: u64,
+    expires_at: Option<u64>,
+    version: u64,
+    value_checksum: &crc32fast::Hasher,
+) -> Result<Vec<u8>> {
+    // length of the payload is 32 bit
+    let too_large = || KVSError::too_large("record of value", value_len, u32::MAX as u64);
+    let mut head = vec![SET_VERSIONED_TAG];
+    head.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    head.extend_from_slice(
+        &u32::try_from(value_len)
+            .map_err(|_| too_large())?
+            .to_be_bytes(),
+    );
+    head.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+    head.extend_from_slice(&version.to_be_bytes());
+    head.extend_from_slice(key);
+    let payload_len = u32::try_from(head.len() as u64 + value_len).map_err(|_| too_large())?;
+
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&head);
+    checksum.combine(value_checksum);
+    let header = frame_header(payload_len, checksum.finalize());
+    Ok([header, head].concat())
+}
+
+/// Frame header of the current format: payload length, its CRC
+/// and CRC of both
+fn frame_header(len: u32, checksum: u32) -> Vec<u8> {
+    let mut header = [len.to_be_bytes(), checksum.to_be_bytes()].concat();
+    let header_checksum = crc32fast::hash(&header);
+    header.extend_from_slice(&header_checksum.to_be_bytes());
+    header
+}
+
+/// Reader of the value of set record with frame of `len` bytes at `pos`,
+/// which checks CRC of the record when the value is read to the end.
+/// Return length of the value and the reader, `None` for records whose
+/// value is not stored whole after the key: batches and JSON records
+pub fn value_reader(
+    mut file: File,
+    format: LogFormat,
+    pos: u64,
+    len: usize,
+) -> Result<Option<(u64, ValueReader)>> {
+    if !matches!(format, LogFormat::Binary | LogFormat::BinaryCheckedLength) {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(pos))?;
+    let mut file = BufReader::new(file);
+    let mut header = vec![0u8; format.frame_header_size()];
+    file.read_exact(&mut header)?;
+    if format.checks_length() && !is_checked_header_valid(&header) {
+        return Err(KVSError::corrupted("checksum of frame header not matched"));
+    }
+    let payload_len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let expected = u32::from_be_bytes(header[4..8].try_into().unwrap());
+
+    let mut head = vec![0u8; PAYLOAD_HEADER_SIZE];
+    file.read_exact(&mut head)?;
+    let head_size = match head[0] {
+        SET_TAG => PAYLOAD_HEADER_SIZE,
+        SET_TTL_TAG => PAYLOAD_HEADER_SIZE + EXPIRES_SIZE,
+        SET_VERSIONED_TAG => PAYLOAD_HEADER_SIZE + EXPIRES_SIZE + VERSION_SIZE,
+        _ => return Ok(None),
+    };
+    let key_len = u32::from_be_bytes(head[1..5].try_into().unwrap()) as usize;
+    let value_len = u32::from_be_bytes(head[5..9].try_into().unwrap()) as usize;
+    if header.len() + payload_len != len || head_size + key_len + value_len != payload_len {
+        return Err(KVSError::corrupted("length of record not matched"));
+    }
+    head.resize(head_size + key_len, 0);
+    file.read_exact(&mut head[PAYLOAD_HEADER_SIZE..])?;
+
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&head);
+    if value_len == 0 && checksum.clone().finalize() != expected {
+        return Err(KVSError::corrupted("checksum of record not matched"));
+    }
+    let value = RecordValue {
+        file: file.take(value_len as u64),
+        checksum,
+        expected,
+    };
+    Ok(Some((value_len as u64, Box::new(value))))
+}
+
+/// Value bytes of the record, see `value_reader`
+struct RecordValue {
+    file: Take<BufReader<File>>,
+    /// CRC-32 state of the payload read so far
+    checksum: crc32fast::Hasher,
+    expected: u32,
+}
+
+impl Read for RecordValue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.file.limit();
+        if left == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let read = self.file.read(buf)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record is cut off in its value",
+            ));
+        }
+        self.checksum.update(&buf[..read]);
+        // last bytes are not given out when the record is damaged
+        if read as u64 == left && self.checksum.clone().finalize() != self.expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum of record not matched",
+            ));
+        }
+        Ok(read)
+    }
+}
+
+/// Whether checksum at the end of the frame header matches length and CRC before it
+fn is_checked_header_valid(header: &[u8]) -> bool {
+    let (fields, checksum) = header.split_at(FRAME_HEADER_SIZE);
+    crc32fast::hash(fields).to_be_bytes() == checksum
+}
+
+/// Append binary payload of insertion
+fn encode_payload(insertion: &DBInsertion, payload: &mut Vec<u8>) -> Result<()> {
+    let (tag, key, value, extra) = match insertion {
+        DBInsertion::Set {
+            key,
+            value,
+            expires_at,
+            version,
+        } => (
+            SET_VERSIONED_TAG,
+            key,
+            value.as_slice(),
+            [expires_at.unwrap_or(0).to_be_bytes(), version.to_be_bytes()].concat(),
+        ),
+        DBInsertion::Rm { key } => (RM_TAG, key, &[][..], vec![]),
+        DBInsertion::VersionMark { version } => {
+            payload.push(VERSION_MARK_TAG);
+            payload.extend_from_slice(&version.to_be_bytes());
+            return Ok(());
+        }
+        DBInsertion::Batch { insertions } => {
+            payload.push(BATCH_TAG);
+            payload.extend_from_slice(&(insertions.len() as u32).to_be_bytes());
+            for insertion in insertions {
+                if let DBInsertion::Batch { .. } = insertion {
+                    return Err(KVSError::corrupted("nested batch"));
+                }
+                encode_payload(insertion, payload)?;
+            }
+            return Ok(());
+        }
+    };
+    payload.push(tag);
+    payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&extra);
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(value);
+    Ok(())
+}
+
+/// Unpack insertion from bytes of one record, checking its CRC
+pub fn decode(format: LogFormat, record: &[u8]) -> Result<DBInsertion> {
+    if format == LogFormat::LegacyJson {
+        return Ok(serde_json::from_slice::<JsonInsertion>(record)?.into());
+    }
+    if record.len() < format.frame_header_size() {
+        return Err(KVSError::corrupted("record is shorter than frame header"));
+    }
+    let (header, payload) = record.split_at(format.frame_header_size());
+    if format.checks_length() && !is_checked_header_valid(header) {
+        return Err(KVSError::corrupted("checksum of frame header not matched"));
+    }
+    let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if crc32fast::hash(payload) != checksum {
+        return Err(KVSError::corrupted("checksum of record not matched"));
+    }
+    match format {
+        LogFormat::Binary | LogFormat::BinaryCheckedLength => match decode_binary(payload)? {
+            (insertion, len) if len == payload.len() => Ok(insertion),
+            _ => Err(KVSError::corrupted("length of record not matched")),
+        },
+        _ => Ok(serde_json::from_slice::<JsonInsertion>(payload)?.into()),
+    }
+}
+
+/// Unpack binary payload: tag, key length, value length,
+/// expiration time and version for set, key, value.
+/// Batch payload is its tag, number of writes and their payloads,
+/// version mark is its tag and the version.
+/// Return insertion and number of bytes it takes
+fn decode_binary(payload: &[u8]) -> Result<(DBInsertion, usize)> {
+    match payload.first() {
+        Some(&BATCH_TAG) => return decode_batch(payload),
+        Some(&VERSION_MARK_TAG) if payload.len() > VERSION_SIZE => {
+            let version = read_u64(payload, 1);
+            return Ok((DBInsertion::VersionMark { version }, 1 + VERSION_SIZE));
+        }
+        _ => {}
+    }
+    if payload.len() < PAYLOAD_HEADER_SIZE {
+        return Err(KVSError::corrupted("payload is shorter than its header"));
+    }
+    let tag = payload[0];
+    let key_len = u32::from_be_bytes(payload[1..5].try_into().unwrap()) as usize;
+    let value_len = u32::from_be_bytes(payload[5..9].try_into().unwrap()) as usize;
+    let header_size = match tag {
+        SET_TTL_TAG => PAYLOAD_HEADER_SIZE + EXPIRES_SIZE,
+        SET_VERSIONED_TAG => PAYLOAD_HEADER_SIZE + EXPIRES_SIZE + VERSION_SIZE,
+        _ => PAYLOAD_HEADER_SIZE,
+    };
+    let len = header_size + key_len + value_len;
+    if payload.len() < len {
+        return Err(KVSError::corrupted("payload is shorter than key and value"));
+    }
+    let expires_at = match tag {
+        SET_TTL_TAG | SET_VERSIONED_TAG => Some(read_u64(payload, PAYLOAD_HEADER_SIZE)),
+        _ => None,
+    }
+    .filter(|&expires_at| expires_at != 0);
+    let version = match tag {
+        SET_VERSIONED_TAG => read_u64(payload, PAYLOAD_HEADER_SIZE + EXPIRES_SIZE),
+        _ => 0,
+    };
+    let (key, value) = payload[header_size..len].split_at(key_len);
+    let key = key.to_vec();
+
+    let insertion = match tag {
+        SET_TAG | SET_TTL_TAG | SET_VERSIONED_TAG => DBInsertion::Set {
+            key,
+            value: value.to_vec(),
+            expires_at,
+            version,
+        },
+        RM_TAG => DBInsertion::Rm { key },
+        tag => return Err(KVSError::corrupted(format!("unknown record tag {}", tag))),
+    };
+    Ok((insertion, len))
+}
+
+/// Big endian u64 at offset, caller checks the length
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn decode_batch(payload: &[u8]) -> Result<(DBInsertion, usize)> {
+    if payload.len() < BATCH_HEADER_SIZE {
+        return Err(KVSError::corrupted("batch is shorter than its header"));
+    }
+    let count = u32::from_be_bytes(payload[1..5].try_into().unwrap()) as usize;
+    let mut insertions = Vec::with_capacity(count.min(payload.len()));
+    let mut len = BATCH_HEADER_SIZE;
+    for _ in 0..count {
+        // nested batches are never written
+        if payload.get(len) == Some(&BATCH_TAG) {
+            return Err(KVSError::corrupted("nested batch"));
+        }
+        let (insertion, insertion_len) = decode_binary(&payload[len..])?;
+        insertions.push(insertion);
+        len += insertion_len;
+    }
+    Ok((DBInsertion::Batch { insertions }, len))
+}
+
+/// Read every record of the generation file in order, calling `f`
+/// with the record, its offset and length.
+/// Damaged records are handled according to the policy, incomplete record
+/// is cut off only at the end of the `newest` generation
+pub fn replay<F>(path: &Path, gen: u64, newest: bool, policy: RecoveryPolicy, f: F) -> Result<()>
+where
+    F: FnMut(DBInsertion, u64, usize),
+{
+    replay_file(path, gen, newest, policy, f).map_err(KVSError::in_file(path))
+}
+
+fn replay_file<F>(
+    path: &Path,
+    gen: u64,
+    newest: bool,
+    policy: RecoveryPolicy,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(DBInsertion, u64, usize),
+{
+    let mut file = File::open(path).map_err(KVSError::io_at(path))?;
+    match read_format(&mut file)? {
+        LogFormat::LegacyJson => replay_legacy(file, f),
+        format => {
+            let file_len = file.metadata()?.len();
+            let mut reader = BufReader::new(file);
+            let mut offset = LOG_HEADER_SIZE.min(file_len);
+            reader.seek(SeekFrom::Start(offset))?;
+
+            while offset < file_len {
+                // offset of the next valid record after the damaged one
+                let resume = match read_frame(&mut reader, format, offset, file_len)? {
+                    Frame::Record(insertion, len) => {
+                        f(insertion, offset, len);
+                        offset += len as u64;
+                        continue;
+                    }
+                    Frame::Incomplete => None,
+                    Frame::Damaged(Some(len)) => {
+                        Some(offset + len as u64).filter(|&next| next < file_len)
+                    }
+                    Frame::Damaged(None) => {
+                        find_frame(reader.get_mut(), format, offset + 1, file_len)?
+                    }
+                };
+                match resume {
+                    None if newest && policy != RecoveryPolicy::Strict => {
+                        log::warn!("Cutting torn tail of generation {} at {}", gen, offset);
+                        drop(reader);
+                        OpenOptions::new()
+                            .write(true)
+                            .open(path)
+                            .and_then(|file| file.set_len(offset))
+                            .map_err(KVSError::io_at(path))?;
+                        break;
+                    }
+                    Some(next) if policy == RecoveryPolicy::SkipCorrupted => {
+                        log::warn!(
+                            "Skipping damaged record of generation {} at {}",
+                            gen,
+                            offset
+                        );
+                        offset = next;
+                        reader.seek(SeekFrom::Start(offset))?;
+                    }
+                    None if policy == RecoveryPolicy::SkipCorrupted => {
+                        log::warn!("Skipping damaged end of generation {} at {}", gen, offset);
+                        break;
+                    }
+                    _ => {
+                        log::error!("Generation {} is damaged at offset {}", gen, offset);
+                        return Err(KVSError::CorruptedLogError {
+                            path: path.to_path_buf(),
+                            gen,
+                            offset,
+                        });
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Result of reading one frame while replaying
+enum Frame {
+    /// Valid record and length of its frame
+    Record(DBInsertion, usize),
+    /// Frame with reliable length goes past the end of file
+    Incomplete,
+    /// Frame has wrong checksum or content, with length of the frame
+    /// when its header is valid, otherwise valid records may follow anywhere
+    Damaged(Option<usize>),
+}
+
+fn read_frame<R: Read>(
+    reader: &mut R,
+    format: LogFormat,
+    offset: u64,
+    file_len: u64,
+) -> Result<Frame> {
+    let header_size = format.frame_header_size();
+    let left = file_len - offset;
+    if left < header_size as u64 {
+        return Ok(Frame::Incomplete);
+    }
+    let mut record = vec![0u8; header_size];
+    reader.read_exact(&mut record)?;
+    if format.checks_length() && !is_checked_header_valid(&record) {
+        return Ok(Frame::Damaged(None));
+    }
+    let payload_len = u32::from_be_bytes(record[0..4].try_into().unwrap()) as usize;
+    let frame_len = header_size + payload_len;
+    if left < frame_len as u64 {
+        // unchecked length may be damaged rather than cut off
+        if format.checks_length() {
+            return Ok(Frame::Incomplete);
+        }
+        return Ok(Frame::Damaged(None));
+    }
+
+    record.resize(frame_len, 0);
+    reader.read_exact(&mut record[header_size..])?;
+    match decode(format, &record) {
+        Ok(insertion) => Ok(Frame::Record(insertion, frame_len)),
+        Err(_) if format.checks_length() => Ok(Frame::Damaged(Some(frame_len))),
+        Err(_) => Ok(Frame::Damaged(None)),
+    }
+}
+
+/// Offset of the first frame at or after `from` which looks valid.
+/// Checked header is enough, frame with unchecked length has to decode
+fn find_frame(file: &mut File, format: LogFormat, from: u64, file_len: u64) -> Result<Option<u64>> {
+    let header_size = format.frame_header_size();
+    let mut start = from;
+    let mut window = Vec::new();
+    while start + header_size as u64 <= file_len {
+        // windows overlap, so every header is whole in one of them
+        window.clear();
+        file.seek(SeekFrom::Start(start))?;
+        file.take(SCAN_WINDOW_SIZE + header_size as u64 - 1)
+            .read_to_end(&mut window)?;
+        for (i, header) in window.windows(header_size).enumerate() {
+            let offset = start + i as u64;
+            if is_frame_at(file, format, header, offset, file_len)? {
+                return Ok(Some(offset));
+            }
+        }
+        start += SCAN_WINDOW_SIZE;
+    }
+    Ok(None)
+}
+
+/// Whether valid frame with given header starts at the offset
+fn is_frame_at(
+    file: &mut File,
+    format: LogFormat,
+    header: &[u8],
+    offset: u64,
+    file_len: u64,
+) -> Result<bool> {
+    if format.checks_length() && !is_checked_header_valid(header) {
+        return Ok(false);
+    }
+    let payload_len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+    let frame_len = header.len() as u64 + payload_len;
+    if offset + frame_len > file_len {
+        return Ok(false);
+    }
+    if format.checks_length() {
+        return Ok(true);
+    }
+    let mut record = vec![0u8; frame_len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut record)?;
+    Ok(decode(format, &record).is_ok())
+}
+
+/// Logs of previous versions: stop at the first record which cant be parsed
+fn replay_legacy<F>(mut file: File, mut f: F) -> Result<()>
+where
+    F: FnMut(DBInsertion, u64, usize),
+{
+    file.seek(SeekFrom::Start(0))?;
+    let buf_reader = BufReader::new(file);
+    let mut stream = Deserializer::from_reader(buf_reader).into_iter::<JsonInsertion>();
+    let mut start = 0;
+    // loop over all commands deserialized in file
+    while let Some(Ok(insertion)) = stream.next() {
+        let end = stream.byte_offset();
+        f(insertion.into(), start as u64, end - start);
+        start = end;
+    }
+    Ok(())
+}
+
+/// Position of the live record in compacted generation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: usize,
+    pub expires_at: Option<u64>,
+    pub version: u64,
+}
+
+/// Write hint file into `tmp_path`, sync it and move to `path`
+pub fn write_hint(tmp_path: &Path, path: &Path, entries: &[HintEntry]) -> Result<()> {
+    let mut content = [HINT_MAGIC.to_vec(), vec![HINT_VERSION]].concat();
+    for entry in entries {
+        content.extend_from_slice(&(entry.key.len() as u32).to_be_bytes());
+        content.extend_from_slice(&entry.pos.to_be_bytes());
+        content.extend_from_slice(&(entry.len as u32).to_be_bytes());
+        content.extend_from_slice(&entry.expires_at.unwrap_or(0).to_be_bytes());
+        content.extend_from_slice(&entry.version.to_be_bytes());
+        content.extend_from_slice(&entry.key);
+    }
+    let checksum = crc32fast::hash(&content);
+    content.extend_from_slice(&checksum.to_be_bytes());
+
+    File::create(tmp_path)
+        .and_then(|mut file| {
+            file.write_all(&content)?;
+            file.sync_all()
+        })
+        .map_err(KVSError::io_at(tmp_path))?;
+    fs::rename(tmp_path, path).map_err(KVSError::io_at(tmp_path))?;
+    Ok(())
+}
+
+/// Read entries of the hint file.
+/// Returns `None` when there is no hint or it is damaged, so log has to be scanned
+pub fn read_hint(path: &Path) -> Result<Option<Vec<HintEntry>>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let content = fs::read(path).map_err(KVSError::io_at(path))?;
+    let entries = parse_hint(&content);
+    if entries.is_none() {
+        log::warn!("Hint file {:?} is damaged, scanning log instead", path);
+    }
+    Ok(entries)
+}
+
+fn parse_hint(content: &[u8]) -> Option<Vec<HintEntry>> {
+    let header_size = HINT_MAGIC.len() + 1;
+    if content.len() < header_size + 4 {
+        return None;
+    }
+    let (content, checksum) = content.split_at(content.len() - 4);
+    if crc32fast::hash(content).to_be_bytes() != checksum
+        || &content[..HINT_MAGIC.len()] != HINT_MAGIC
+    {
+        return None;
+    }
+    let entry_header_size = match content[HINT_MAGIC.len()] {
+        HINT_VERSION_NO_EXPIRY => HINT_ENTRY_HEADER_SIZE,
+        HINT_VERSION_NO_VERSION => HINT_ENTRY_HEADER_SIZE + EXPIRES_SIZE,
+        HINT_VERSION => HINT_ENTRY_HEADER_SIZE + EXPIRES_SIZE + VERSION_SIZE,
+        _ => return None,
+    };
+
+    let mut entries = Vec::new();
+    let mut rest = &content[header_size..];
+    while !rest.is_empty() {
+        if rest.len() < entry_header_size {
+            return None;
+        }
+        let key_len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let pos = u64::from_be_bytes(rest[4..12].try_into().unwrap());
+        let len = u32::from_be_bytes(rest[12..16].try_into().unwrap()) as usize;
+        let mut extra = rest[HINT_ENTRY_HEADER_SIZE..entry_header_size]
+            .chunks(8)
+            .map(|field| u64::from_be_bytes(field.try_into().unwrap()));
+        let expires_at = extra.next().filter(|&expires_at| expires_at != 0);
+        let version = extra.next().unwrap_or(0);
+        rest = &rest[entry_header_size..];
+        if rest.len() < key_len {
+            return None;
+        }
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+        entries.push(HintEntry {
+            key,
+            pos,
+            len,
+            expires_at,
+            version,
+        });
+    }
+    Some(entries)
+}
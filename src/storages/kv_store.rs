@@ -1,41 +1,82 @@
 // #![deny(missing_docs)]
 //! Module with key-value storage
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-
-use crate::engine::KvsEngine;
+use std::cell::RefCell;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Weak;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use im::OrdMap;
+
+use crate::batch::{BatchOp, WriteBatch};
+use crate::counter;
+use crate::durability::{Durability, GroupCommit};
+use crate::engine::{KvsEngine, ValueReader};
 use crate::error::{KVSError, Result};
+use crate::expiry::{self, Sweeper, DEFAULT_SWEEP_INTERVAL};
+use crate::snapshot::KvsSnapshot;
+use crate::storages::kv_log::{
+    self, DBInsertion, HintEntry, LogFormat, RecoveryPolicy, LOG_HEADER_SIZE,
+};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
-const DATABASE_FILENAME: &str = "kvs.db";
+/// Single log file of the previous versions, upgraded to the first generation
+const LEGACY_FILENAME: &str = "kvs.db";
+const LOG_EXTENSION: &str = "log";
+const HINT_EXTENSION: &str = "hint";
+const COMPACTION_EXTENSION: &str = "compact";
+/// Value of streamed set waiting for its record to be appended
+const SPOOL_EXTENSION: &str = "spool";
+/// Piece of streamed value copied at once
+const SPOOL_BUFFER_SIZE: usize = 64 * 1024;
 
-// TODO: its duplicated in kvs.rs for cli usage
-#[derive(Serialize, Deserialize)]
-enum DBInsertion {
-    /// Set up value by key into KVS
-    Set { key: String, value: String },
-    /// Removes value by key
-    Rm { key: String },
+/// Options of the KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// How damaged log records are handled on open
+    pub recovery: RecoveryPolicy,
+    /// When appended records are synced to disk
+    pub durability: Durability,
+    /// How often tombstones are written for expired keys
+    pub sweep_interval: Duration,
 }
 
-/// Database insertion log
-impl DBInsertion {
-    pub fn get_key(&self) -> &String {
-        match self {
-            DBInsertion::Rm { key } => key,
-            DBInsertion::Set { key, .. } => key,
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            recovery: RecoveryPolicy::default(),
+            durability: Durability::default(),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Positions of values by key. Clones share nodes, which are copied on write
+/// only while shared, so taking a snapshot of the index is O(1)
+type Index = OrdMap<Vec<u8>, ItemPosition>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct ItemPosition {
+    gen: u64,
     pos: u64,
     len: usize,
+    /// Expiration time of the value, kept here to check it without reading the log
+    expires_at: Option<u64>,
+    /// Version of the value, kept here for conditional writes
+    version: u64,
+}
+
+impl ItemPosition {
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires_at, now)
+    }
 }
 
 /// Usage
@@ -50,173 +91,1306 @@ struct ItemPosition {
 ///
 /// let mut path = Path::new(db_path);
 ///
-/// let mut store = KvStore::open(path).unwrap();
-/// store.set("key1".to_owned(), "value1".to_owned());
-/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-/// store.remove("key1".to_owned());
+/// let store = KvStore::open(path).unwrap();
+/// store.set(b"key1".to_vec(), b"value1".to_vec());
+/// assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+/// store.remove(b"key1".to_vec());
 /// #
 /// #     Ok(())
 /// # }
 /// ```
-
-/// In memory key value storage String:String
 ///
-#[derive(Debug)]
+/// Log-structured key value storage of arbitrary bytes
+///
+/// Log is split into numbered generation files `<gen>.log`, new records
+/// always go to the newest one. Every record is framed with its length
+/// and checksum, see `RecoveryPolicy` for handling of damaged records.
+/// When enough stale data is collected,
+/// live records are copied into a fresh generation on a background thread
+/// and older generations are deleted after the switch. Compacted generation
+/// gets `<gen>.hint` file with positions of its records, which is loaded
+/// on open instead of scanning the generation.
+/// `Durability` of the config decides when appended records are synced.
+///
+/// Index is ordered by key, so range and prefix scans walk it directly.
+///
+/// Batch is written as a single record, so it is replayed completely or
+/// not at all. Compaction copies live values of batches as separate records.
+///
+/// Values set with TTL are hidden once expired, and a background sweeper
+/// appends tombstones for them, so compaction can drop them.
+///
+/// Every set value gets the next version of the store, stored in its record.
+/// Conditional writes and transaction commits check the current value
+/// under the writer lock. Compaction marks the highest version given so far
+/// in the generation the writer moves to, so versions of records it drops
+/// are not given again after reopen.
+///
+/// Snapshot takes a clone of the index sharing its nodes, so it keeps
+/// positions of values overwritten later, and writes copy only the nodes
+/// on the path to the written key while a snapshot shares them. Compaction keeps stale generations on disk while any snapshot
+/// is alive, they are deleted when the last snapshot is dropped.
+///
+/// Streamed set is written into a spool file of the store as it is read,
+/// then its record is copied from there, so the writer waits only for
+/// the copy. Streamed get reads the value from its record as it is sent,
+/// keeping generations on disk like a snapshot until the reader is dropped.
+///
+/// Cloning gives a new handle to the same storage: the index and the
+/// writer are shared behind locks, while every clone reads the log
+/// through its own file handles.
+#[derive(Debug, Clone)]
 pub struct KvStore {
-    storage: HashMap<String, ItemPosition>,
-    possible_compaction: u64,
-    file: File,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    compactor: Arc<Compactor>,
+    snapshots: Arc<Mutex<LiveSnapshots>>,
+    /// Number of the next spool file of streamed set
+    spools: Arc<AtomicU64>,
+    /// Held only to stop the sweeper with the last handle
+    _sweeper: Arc<Sweeper>,
 }
 
-impl KvsEngine for KvStore {
-    /// Set up value by key into KVS
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        if self.possible_compaction > COMPACTION_THRESHOLD {
-            let _ = self.compaction();
+/// Read-only point-in-time view of the KvStore, see `KvsEngine::snapshot`
+#[derive(Debug, Clone)]
+pub struct KvStoreSnapshot {
+    index: Index,
+    reader: KvStoreReader,
+    /// Expiration is checked against the time of the snapshot
+    taken_at: u64,
+    _guard: Arc<SnapshotGuard>,
+}
+
+/// Number of live snapshots and generations waiting for them to be deleted
+#[derive(Debug, Default)]
+struct LiveSnapshots {
+    count: usize,
+    stale_gens: BTreeSet<u64>,
+}
+
+/// Registers snapshot as live until dropped, the last one deletes stale generations
+#[derive(Debug)]
+struct SnapshotGuard {
+    path: Arc<PathBuf>,
+    snapshots: Arc<Mutex<LiveSnapshots>>,
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.count -= 1;
+        if snapshots.count == 0 {
+            for stale_gen in std::mem::take(&mut snapshots.stale_gens) {
+                remove_gen(&self.path, stale_gen);
+            }
         }
+    }
+}
 
-        let insertion = DBInsertion::Set {
-            key: key.clone(),
-            value,
+/// Per-handle readers of generation files, opened lazily on first `get`
+#[derive(Debug)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    /// Generations below this one are already deleted by compaction
+    safe_gen: Arc<AtomicU64>,
+    files: RefCell<BTreeMap<u64, (File, LogFormat)>>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_gen: Arc::clone(&self.safe_gen),
+            files: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Read value of the key stored at position, which could be inside batch
+    fn read_value(&self, key: &[u8], position: &ItemPosition) -> Result<Vec<u8>> {
+        match self.read(position)?.value_of(key) {
+            Some((value, _)) => Ok(value),
+            // index points to the record which does not have the key
+            None => Err(KVSError::CorruptedLogError {
+                path: log_path(&self.path, position.gen),
+                gen: position.gen,
+                offset: position.pos,
+            }),
+        }
+    }
+
+    /// Read insertion stored at position
+    fn read(&self, position: &ItemPosition) -> Result<DBInsertion> {
+        let mut files = self.files.borrow_mut();
+        // close handles of deleted generations
+        let safe_gen = self.safe_gen.load(Ordering::SeqCst);
+        *files = files.split_off(&safe_gen);
+
+        let path = log_path(&self.path, position.gen);
+        let (file, format) = match files.entry(position.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut file = File::open(&path).map_err(KVSError::io_at(&path))?;
+                let format = kv_log::read_format(&mut file).map_err(KVSError::in_file(&path))?;
+                entry.insert((file, format))
+            }
+        };
+
+        let mut record = vec![0u8; position.len];
+        file.seek(SeekFrom::Start(position.pos))
+            .and_then(|_| file.read_exact(&mut record))
+            .map_err(KVSError::io_at(&path))?;
+        kv_log::decode(*format, &record).map_err(|e| {
+            log::error!("Cant read record of generation {}: {}", position.gen, e);
+            KVSError::CorruptedLogError {
+                path,
+                gen: position.gen,
+                offset: position.pos,
+            }
+        })
+    }
+}
+
+/// Single writer of the log, shared between all handles
+#[derive(Debug)]
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    gen: u64,
+    /// Created by the first append, so opening without writes leaves no empty generation
+    file: Option<File>,
+    /// Bytes of stale records which compaction can reclaim
+    possible_compaction: u64,
+    compacting: bool,
+    durability: Durability,
+    /// Sequence number of the last appended record
+    written_seq: u64,
+    /// Version of the last set value
+    version: u64,
+}
+
+impl KvStoreWriter {
+    /// Append insertion to the end of the current generation and return its position
+    fn append(&mut self, insertion: &DBInsertion) -> Result<ItemPosition> {
+        let (expires_at, version) = match insertion {
+            DBInsertion::Set {
+                expires_at,
+                version,
+                ..
+            } => (*expires_at, *version),
+            DBInsertion::Rm { .. }
+            | DBInsertion::Batch { .. }
+            | DBInsertion::VersionMark { .. } => (None, 0),
+        };
+        let record = kv_log::encode(insertion)?;
+        self.append_record(&record, &mut io::empty(), 0, expires_at, version)
+    }
+
+    /// Append record made of `head` and `tail_len` bytes of `tail`,
+    /// return its position. Record cut off by a failure is removed
+    fn append_record(
+        &mut self,
+        head: &[u8],
+        tail: &mut dyn Read,
+        tail_len: u64,
+        expires_at: Option<u64>,
+        version: u64,
+    ) -> Result<ItemPosition> {
+        let len = head.len() + tail_len as usize;
+        let durability = self.durability;
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(new_log_file(&self.path, self.gen)?),
         };
-        let insertion_str = serde_json::to_string(&insertion)?;
-        let len = insertion_str.len();
         // move to end of the file and then write
-        let pos = self.file.seek(SeekFrom::End(0))?;
+        let pos = file.seek(SeekFrom::End(0))?;
 
-        let position = ItemPosition { pos, len };
+        let written = file
+            .write_all(head)
+            .and_then(|()| io::copy(&mut tail.take(tail_len), file))
+            .and_then(|copied| {
+                if copied < tail_len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                file.flush()
+            });
+        if let Err(e) = written {
+            // later records must not follow a damaged one
+            let _ = file.set_len(pos);
+            return Err(e.into());
+        }
+        if durability == Durability::EveryWrite {
+            file.sync_data()?;
+        }
+        self.written_seq += 1;
+        Ok(ItemPosition {
+            gen: self.gen,
+            pos,
+            len,
+            expires_at,
+            version,
+        })
+    }
+
+    /// Next version for the value to set
+    fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
 
-        self.file.write_all(insertion_str.as_bytes())?;
-        self.file.flush()?;
-        if let Some(_old_position) = self.storage.insert(key, position) {
-            self.possible_compaction += len as u64;
+    /// Sync appended records of the current generation, if any
+    fn sync(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()?;
         }
         Ok(())
     }
-    /// Get value by key
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        // println!("{:?}", self.storage);
-        let record_option = self.storage.get(key.as_str());
-        match record_option {
-            Some(record) => {
-                let mut buf_reader = BufReader::new(&self.file);
-                buf_reader.seek(SeekFrom::Start(record.pos))?;
-                let handle = buf_reader.take(record.len as u64);
-
-                let insertion: DBInsertion = serde_json::from_reader(handle)?;
-                match insertion {
-                    DBInsertion::Set { value, .. } => Ok(Some(value)),
-                    _ => Err(KVSError::GeneralKVSError),
-                }
+
+    /// Continue writing into new generation file
+    fn switch_to(&mut self, gen: u64) -> Result<()> {
+        // writers waiting for sync may have records only in the old file
+        if self.durability != Durability::None {
+            self.sync()?;
+        }
+        self.file = Some(new_log_file(&self.path, gen)?);
+        self.gen = gen;
+        Ok(())
+    }
+}
+
+/// Keeps the background compaction thread, joined when the last handle is dropped
+#[derive(Debug, Default)]
+struct Compactor {
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            if handle.join().is_err() {
+                log::error!("Compaction thread panicked");
             }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+    /// Set up value by key into KVS
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_set(key, value, None)
+    }
+    /// Set up value by key into KVS, expiring after ttl
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write_set(key, value, Some(expiry::expires_at(ttl)))
+    }
+    /// Get value by key
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        // keep index locked while reading, so compaction cant delete the record
+        let index = self.index.read().unwrap();
+        match index.get(key.as_slice()) {
+            Some(position) if position.is_expired(expiry::now_ms()) => Ok(None),
+            Some(position) => Ok(Some(self.reader.read_value(&key, position)?)),
             None => Ok(None),
         }
     }
+    /// Get pairs with keys in range [start, end) ordered by key
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let index = self.index.read().unwrap();
+        let now = expiry::now_ms();
+        scan_index(&self.reader, &index, start, end, limit, now)
+    }
+    /// Get pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let index = self.index.read().unwrap();
+        let now = expiry::now_ms();
+        scan_index_prefix(&self.reader, &index, prefix, limit, now)
+    }
+    /// Share the index, generations it points to are kept until the snapshot is dropped
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        // register before cloning, so compaction switching the index meanwhile keeps the files
+        let index = self.index.read().unwrap();
+        let guard = self.keep_generations();
+        Ok(KvStoreSnapshot {
+            index: index.clone(),
+            reader: KvStoreReader {
+                path: Arc::clone(&self.reader.path),
+                // generations of the snapshot are never deleted under it
+                safe_gen: Arc::new(AtomicU64::new(0)),
+                files: RefCell::new(BTreeMap::new()),
+            },
+            taken_at: expiry::now_ms(),
+            _guard: Arc::new(guard),
+        })
+    }
+    /// Apply puts and deletes as one batch record
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        self.append_batch(&mut writer, batch)?;
+        self.finish_write(writer)
+    }
+    /// Validate versions of read keys against the index and apply
+    /// writes as one batch record, both under the writer lock
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: WriteBatch,
+    ) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        {
+            let index = self.index.read().unwrap();
+            let now = expiry::now_ms();
+            let changed = reads.iter().any(|(key, version)| {
+                let current = index
+                    .get(key.as_slice())
+                    .filter(|position| !position.is_expired(now));
+                current.map(|position| position.version) != *version
+            });
+            if changed {
+                return Err(KVSError::TransactionConflictError);
+            }
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        self.append_batch(&mut writer, writes)?;
+        self.finish_write(writer)
+    }
+    /// Remaining lifetime of the key
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = expiry::now_ms();
+        match self.index.read().unwrap().get(key.as_slice()) {
+            Some(position) if !position.is_expired(now) => {
+                Ok(expiry::remaining(position.expires_at, now))
+            }
+            _ => Err(KVSError::KeyNotFoundError),
+        }
+    }
     /// Removes value by key
-    fn remove(&mut self, key: String) -> Result<()> {
-        let insertion = DBInsertion::Rm { key: key.clone() };
-
-        if let Some(old_insertion_pos) = self.storage.remove(key.as_str()) {
-            // move to end of the file and then write
-            let insertion_str = serde_json::to_string(&insertion)?;
-            self.file.seek(SeekFrom::End(0))?;
-            self.file.write_all(insertion_str.as_bytes())?;
-            self.file.flush()?;
-            self.possible_compaction += old_insertion_pos.len as u64;
-            Ok(())
-        } else {
-            Err(KVSError::GeneralKVSError)
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match self.index.read().unwrap().get(key.as_slice()) {
+            Some(position) if !position.is_expired(expiry::now_ms()) => {}
+            _ => return Err(KVSError::KeyNotFoundError),
         }
+        self.append_rm(&mut writer, key)?;
+        self.finish_write(writer)
+    }
+    /// Get value by key with its version
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let index = self.index.read().unwrap();
+        match index.get(key.as_slice()) {
+            Some(position) if position.is_expired(expiry::now_ms()) => Ok(None),
+            Some(position) => Ok(Some((
+                self.reader.read_value(&key, position)?,
+                position.version,
+            ))),
+            None => Ok(None),
+        }
+    }
+    /// Replace value when current one equals expected
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.write_if(key.clone(), new, None, |position| {
+            let current = match position {
+                Some(position) => Some(self.reader.read_value(&key, position)?),
+                None => None,
+            };
+            Ok(current == expected)
+        })?;
+        Ok(())
+    }
+    /// Set value when the key is missing
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        self.write_if(key, Some(value), None, |position| Ok(position.is_none()))
+    }
+    /// Set value when current one has given version
+    fn set_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<u64> {
+        self.write_if(key, Some(value), None, |position| {
+            Ok(position.is_some_and(|position| position.version == version))
+        })
+    }
+    /// Set expiring value when the key is missing
+    fn set_if_absent_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<u64> {
+        let expires_at = Some(expiry::expires_at(ttl));
+        self.write_if(key, Some(value), expires_at, |position| {
+            Ok(position.is_none())
+        })
+    }
+    /// Set expiring value when current one has given version
+    fn set_if_version_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let expires_at = Some(expiry::expires_at(ttl));
+        self.write_if(key, Some(value), expires_at, |position| {
+            Ok(position.is_some_and(|position| position.version == version))
+        })
+    }
+    /// Add delta to the counter under the writer lock
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut writer = self.writer.lock().unwrap();
+        let (counter, value, expires_at) = {
+            // writer lock keeps the current value until the write
+            let index = self.index.read().unwrap();
+            let now = expiry::now_ms();
+            match index
+                .get(key.as_slice())
+                .filter(|position| !position.is_expired(now))
+            {
+                Some(position) => {
+                    let current = self.reader.read_value(&key, position)?;
+                    let (counter, value) = counter::add(Some(&current), delta)?;
+                    (counter, value, position.expires_at)
+                }
+                None => {
+                    let (counter, value) = counter::add(None, delta)?;
+                    (counter, value, None)
+                }
+            }
+        };
+        self.append_set(&mut writer, key, value, expires_at)?;
+        self.finish_write(writer)?;
+        Ok(counter)
+    }
+    /// Write the value into a spool file as it is read,
+    /// then append its record copying the value from there
+    fn set_from_reader(
+        &self,
+        key: Vec<u8>,
+        value: &mut dyn Read,
+        len: u64,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let number = self.spools.fetch_add(1, Ordering::SeqCst);
+        let mut spool = Spool::create(&self.reader.path, number)?;
+        let checksum = spool.fill(value, len)?;
+
+        let expires_at = ttl.map(expiry::expires_at);
+        let mut writer = self.writer.lock().unwrap();
+        let version = writer.next_version();
+        let head = kv_log::encode_set_head(&key, len, expires_at, version, &checksum)?;
+        let position = writer.append_record(&head, &mut spool.file, len, expires_at, version)?;
+        self.point_to(&mut writer, key, position);
+        self.finish_write(writer)
+    }
+    /// Read the value from its record as the reader is read,
+    /// values of batches and of older formats are read whole
+    fn get_reader(&self, key: Vec<u8>) -> Result<Option<(u64, ValueReader)>> {
+        // registered before unlocking the index, so compaction keeps the generation
+        let (position, guard) = {
+            let index = self.index.read().unwrap();
+            match index.get(key.as_slice()) {
+                Some(position) if !position.is_expired(expiry::now_ms()) => {
+                    (position.clone(), self.keep_generations())
+                }
+                _ => return Ok(None),
+            }
+        };
+        let path = log_path(&self.reader.path, position.gen);
+        let mut file = File::open(&path).map_err(KVSError::io_at(&path))?;
+        let format = kv_log::read_format(&mut file).map_err(KVSError::in_file(&path))?;
+        let found = kv_log::value_reader(file, format, position.pos, position.len)
+            .map_err(KVSError::in_file(&path));
+        let (len, value) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                let value = self.reader.read_value(&key, &position)?;
+                return Ok(Some((value.len() as u64, Box::new(io::Cursor::new(value)))));
+            }
+            Err(e @ KVSError::IOError { .. }) => return Err(e),
+            Err(e) => {
+                log::error!("Cant read record of generation {}: {}", position.gen, e);
+                return Err(KVSError::CorruptedLogError {
+                    path,
+                    gen: position.gen,
+                    offset: position.pos,
+                });
+            }
+        };
+        let value = LogValue {
+            value,
+            _guard: guard,
+        };
+        Ok(Some((len, Box::new(value))))
     }
 }
 
-impl KvStore {
-    /// Create new instance
-    pub fn new(path: PathBuf) -> Result<Self> {
+/// Value read from the log, keeps generations on disk until dropped
+struct LogValue {
+    value: ValueReader,
+    _guard: SnapshotGuard,
+}
+
+impl Read for LogValue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.value.read(buf)
+    }
+}
+
+/// File of the store holding value of streamed set until its record
+/// is appended, deleted when dropped
+struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    fn create(dir: &Path, number: u64) -> Result<Spool> {
+        let path = dir.join(format!("{}.{}", number, SPOOL_EXTENSION));
         let file = OpenOptions::new()
             .create(true)
-            .write(true)
+            .truncate(true)
             .read(true)
-            .append(true)
-            .open(path)
-            .unwrap();
-        let possible_compaction = 0;
-        let storage = HashMap::new();
-
-        let mut obj = Self {
-            storage,
+            .write(true)
+            .open(&path)
+            .map_err(KVSError::io_at(&path))?;
+        Ok(Spool { path, file })
+    }
+
+    /// Copy `len` bytes of `value` into the file and rewind it,
+    /// return CRC-32 state of the bytes
+    fn fill(&mut self, value: &mut dyn Read, len: u64) -> Result<crc32fast::Hasher> {
+        let mut checksum = crc32fast::Hasher::new();
+        let mut out = BufWriter::new(&mut self.file);
+        let mut buf = vec![0u8; SPOOL_BUFFER_SIZE.min(len as usize)];
+        let mut left = len;
+        while left > 0 {
+            let size = left.min(buf.len() as u64) as usize;
+            let read = value.read(&mut buf[..size])?;
+            if read == 0 {
+                let e = io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "value is shorter than its length",
+                );
+                return Err(e.into());
+            }
+            checksum.update(&buf[..read]);
+            out.write_all(&buf[..read])
+                .map_err(KVSError::io_at(&self.path))?;
+            left -= read as u64;
+        }
+        out.flush().map_err(KVSError::io_at(&self.path))?;
+        drop(out);
+        self.file
+            .seek(SeekFrom::Start(0))
+            .map_err(KVSError::io_at(&self.path))?;
+        Ok(checksum)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::error!("Cant remove spool {:?}: {}", self.path, e);
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    /// Get value by key from the index of the snapshot
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key.as_slice()) {
+            Some(position) if position.is_expired(self.taken_at) => Ok(None),
+            Some(position) => Ok(Some(self.reader.read_value(&key, position)?)),
+            None => Ok(None),
+        }
+    }
+    /// Get pairs with keys in range [start, end) ordered by key
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        scan_index(&self.reader, &self.index, start, end, limit, self.taken_at)
+    }
+    /// Get pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        scan_index_prefix(&self.reader, &self.index, prefix, limit, self.taken_at)
+    }
+}
+
+impl KvStore {
+    /// Create new instance in the directory
+    pub fn new(path: PathBuf) -> Result<Self> {
+        KvStore::with_config(path, KvStoreConfig::default())
+    }
+
+    /// Create new instance in the directory with given options
+    pub fn with_config(path: PathBuf, config: KvStoreConfig) -> Result<Self> {
+        fs::create_dir_all(&path).map_err(KVSError::io_at(&path))?;
+        upgrade_legacy_log(&path)?;
+        remove_unfinished_writes(&path)?;
+        remove_orphan_hints(&path)?;
+
+        let path = Arc::new(path);
+        let gens = sorted_gens(&path)?;
+
+        let mut index = Index::new();
+        let mut possible_compaction = 0;
+        let mut version = 0;
+        for &gen in &gens {
+            let newest = Some(&gen) == gens.last();
+            possible_compaction += load_gen(
+                &path,
+                gen,
+                newest,
+                config.recovery,
+                &mut index,
+                &mut version,
+            )?;
+        }
+        // every start writes into a fresh generation, created with the first record
+        let gen = gens.last().unwrap_or(&0) + 1;
+        let writer = KvStoreWriter {
+            path: Arc::clone(&path),
+            gen,
+            file: None,
             possible_compaction,
-            file,
+            compacting: false,
+            durability: config.durability,
+            written_seq: 0,
+            version,
+        };
+        let writer = Arc::new(Mutex::new(writer));
+        if let Durability::Interval(ms) = config.durability {
+            spawn_interval_sync(Arc::downgrade(&writer), ms)?;
+        }
+        let index = Arc::new(RwLock::new(index));
+        let sweeper = {
+            let index = Arc::clone(&index);
+            let writer = Arc::clone(&writer);
+            Sweeper::spawn(config.sweep_interval, move || {
+                remove_expired(&index, &writer)
+            })?
         };
-        obj.create_index()?;
-        Ok(obj)
+
+        Ok(Self {
+            index,
+            reader: KvStoreReader {
+                path: Arc::clone(&path),
+                safe_gen: Arc::new(AtomicU64::new(0)),
+                files: RefCell::new(BTreeMap::new()),
+            },
+            writer,
+            durability: config.durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            compactor: Arc::new(Compactor::default()),
+            snapshots: Arc::new(Mutex::new(LiveSnapshots::default())),
+            spools: Arc::new(AtomicU64::new(0)),
+            _sweeper: Arc::new(sweeper),
+        })
     }
 
-    fn create_index(&mut self) -> Result<()> {
-        let buf_reader = BufReader::new(&self.file);
+    /// Open the KvStore at a given path. Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::new(path.into())
+    }
 
-        let mut stream = Deserializer::from_reader(buf_reader).into_iter::<DBInsertion>();
-        let mut start = 0;
-        // loop over all commands deserialized in file
-        while let Some(Ok(insertion)) = stream.next() {
-            let end = stream.byte_offset();
-            let len = end - start;
+    /// Append set record, replacing value and expiration of the key
+    fn write_set(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.append_set(&mut writer, key, value, expires_at)?;
+        self.finish_write(writer)
+    }
 
-            let position = ItemPosition {
-                pos: start as u64,
+    /// Set the value expiring at `expires_at`, or remove the key for `None`,
+    /// when `check` accepts position of the current value
+    /// (`None` for missing or expired key).
+    /// Return version of the new value, 0 for removal
+    fn write_if<F>(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+        check: F,
+    ) -> Result<u64>
+    where
+        F: FnOnce(Option<&ItemPosition>) -> Result<bool>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let exists = {
+            // writer lock keeps the current value until the write
+            let index = self.index.read().unwrap();
+            let now = expiry::now_ms();
+            let current = index
+                .get(key.as_slice())
+                .filter(|position| !position.is_expired(now));
+            if !check(current)? {
+                return Err(KVSError::PreconditionFailedError);
+            }
+            current.is_some()
+        };
+        let version = match value {
+            Some(value) => self.append_set(&mut writer, key, value, expires_at)?,
+            None if exists => {
+                self.append_rm(&mut writer, key)?;
+                0
+            }
+            None => return Ok(0),
+        };
+        self.finish_write(writer)?;
+        Ok(version)
+    }
+
+    /// Append set record with the next version and point the key to it.
+    /// Return the version
+    fn append_set(
+        &self,
+        writer: &mut KvStoreWriter,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let insertion = DBInsertion::Set {
+            key: key.clone(),
+            value,
+            expires_at,
+            version: writer.next_version(),
+        };
+        let position = writer.append(&insertion)?;
+        Ok(self.point_to(writer, key, position))
+    }
+
+    /// Point the key to the set record at position, return version of the value
+    fn point_to(&self, writer: &mut KvStoreWriter, key: Vec<u8>, position: ItemPosition) -> u64 {
+        let version = position.version;
+        if let Some(old_position) = self.index.write().unwrap().insert(key, position) {
+            writer.possible_compaction += old_position.len as u64;
+        }
+        version
+    }
+
+    /// Register reader of the log as a snapshot, so compaction keeps
+    /// the generations until it is dropped. Caller holds the index lock
+    fn keep_generations(&self) -> SnapshotGuard {
+        self.snapshots.lock().unwrap().count += 1;
+        SnapshotGuard {
+            path: Arc::clone(&self.reader.path),
+            snapshots: Arc::clone(&self.snapshots),
+        }
+    }
+
+    /// Append batch record with the next versions for its puts and apply it to the index
+    fn append_batch(&self, writer: &mut KvStoreWriter, batch: WriteBatch) -> Result<()> {
+        let insertions = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value } => DBInsertion::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                    version: writer.next_version(),
+                },
+                BatchOp::Delete { key } => DBInsertion::Rm { key },
+            })
+            .collect();
+        let insertion = DBInsertion::Batch { insertions };
+
+        let position = writer.append(&insertion)?;
+        let stale = apply_to_index(&mut self.index.write().unwrap(), insertion, &position);
+        writer.possible_compaction += stale;
+        Ok(())
+    }
+
+    /// Append tombstone and remove the key from the index
+    fn append_rm(&self, writer: &mut KvStoreWriter, key: Vec<u8>) -> Result<()> {
+        let insertion = DBInsertion::Rm { key: key.clone() };
+        let position = writer.append(&insertion)?;
+        if let Some(old_position) = self.index.write().unwrap().remove(key.as_slice()) {
+            writer.possible_compaction += (old_position.len + position.len) as u64;
+        }
+        Ok(())
+    }
+
+    /// Start compaction if enough stale data, release the writer
+    /// and wait until the write is synced
+    fn finish_write(&self, mut writer: MutexGuard<KvStoreWriter>) -> Result<()> {
+        if writer.possible_compaction > COMPACTION_THRESHOLD && !writer.compacting {
+            self.start_compaction(&mut writer)?;
+        }
+        let seq = writer.written_seq;
+        drop(writer);
+        self.wait_synced(seq)
+    }
+
+    /// With group commit wait until record `seq` is synced,
+    /// one of the waiting writers syncs for everybody
+    fn wait_synced(&self, seq: u64) -> Result<()> {
+        if self.durability != Durability::GroupCommit {
+            return Ok(());
+        }
+        self.group_commit.commit(seq, || {
+            let writer = self.writer.lock().unwrap();
+            let file = match &writer.file {
+                Some(file) => file.try_clone()?,
+                None => return Ok(writer.written_seq),
+            };
+            let covered_seq = writer.written_seq;
+            // sync without writer lock, so others can append meanwhile
+            drop(writer);
+            file.sync_data()?;
+            Ok(covered_seq)
+        })
+    }
+
+    /// Reserve generation for compaction, move writer past it
+    /// and copy live records there on the background thread.
+    /// Caller must hold the writer lock
+    fn start_compaction(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        let compaction_gen = writer.gen + 1;
+        writer.switch_to(compaction_gen + 1)?;
+        // records of removed keys may have the highest versions,
+        // the mark keeps them from being given again once they are dropped
+        let mark = DBInsertion::VersionMark {
+            version: writer.version,
+        };
+        writer.append(&mark)?;
+        writer.sync()?;
+        writer.possible_compaction = 0;
+        writer.compacting = true;
+        log::info!("Compaction triggered into generation {}", compaction_gen);
+
+        let compaction = Compaction {
+            gen: compaction_gen,
+            index: Arc::clone(&self.index),
+            reader: self.reader.clone(),
+            writer: Arc::clone(&self.writer),
+            snapshots: Arc::clone(&self.snapshots),
+        };
+        let mut handle = self.compactor.handle.lock().unwrap();
+        if let Some(previous) = handle.take() {
+            let _ = previous.join();
+        }
+        *handle = Some(thread::spawn(move || compaction.run()));
+        Ok(())
+    }
+}
+
+/// State moved to the background thread for one compaction run
+struct Compaction {
+    gen: u64,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    snapshots: Arc<Mutex<LiveSnapshots>>,
+}
+
+impl Compaction {
+    fn run(self) {
+        if let Err(e) = self.compact() {
+            log::error!("Compaction into generation {} failed: {}", self.gen, e);
+        }
+        self.writer.lock().unwrap().compacting = false;
+    }
+
+    /// Copy live records of older generations into `gen`,
+    /// switch the index to them and delete stale generations
+    fn compact(&self) -> Result<()> {
+        let path = Arc::clone(&self.reader.path);
+        let now = expiry::now_ms();
+        let live: Vec<(Vec<u8>, ItemPosition)> = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, position)| position.gen < self.gen && !position.is_expired(now))
+            .map(|(key, position)| (key.to_owned(), position.clone()))
+            .collect();
+
+        // older generations are not touched by writer, so no locks needed while copying
+        let tmp_path = path.join(format!("{}.{}", self.gen, COMPACTION_EXTENSION));
+        let mut tmp_file = File::create(&tmp_path).map_err(KVSError::io_at(&tmp_path))?;
+        kv_log::write_header(&mut tmp_file)?;
+        let mut tmp_file = BufWriter::new(tmp_file);
+        let mut moved = Vec::with_capacity(live.len());
+        let mut pos = LOG_HEADER_SIZE;
+        for (key, old_position) in live {
+            // records of batches are copied as separate sets
+            let insertion = DBInsertion::Set {
+                value: self.reader.read_value(&key, &old_position)?,
+                key: key.clone(),
+                expires_at: old_position.expires_at,
+                version: old_position.version,
+            };
+            let record = kv_log::encode(&insertion)?;
+            tmp_file.write_all(&record)?;
+
+            let len = record.len();
+            let new_position = ItemPosition {
+                gen: self.gen,
+                pos,
                 len,
+                expires_at: old_position.expires_at,
+                version: old_position.version,
             };
-            start = end;
-            // insert or remove keys from memory
-            // sum up repeated keys for compaction acountability
-            match insertion {
-                DBInsertion::Set { key, .. } => {
-                    if let Some(old_insertion_pos) = self.storage.insert(key.to_owned(), position) {
-                        self.possible_compaction += old_insertion_pos.len as u64;
-                    }
-                }
-                DBInsertion::Rm { key } => {
-                    if let Some(old_insertion_pos) = self.storage.remove(key.as_str()) {
-                        self.possible_compaction += old_insertion_pos.len as u64;
+            pos += len as u64;
+            moved.push((key, old_position, new_position));
+        }
+        let tmp_file = tmp_file.into_inner().map_err(|e| e.into_error())?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, log_path(&path, self.gen)).map_err(KVSError::io_at(&tmp_path))?;
+
+        // without hint the generation is just scanned on open
+        let hints: Vec<HintEntry> = moved
+            .iter()
+            .map(|(key, _, position)| HintEntry {
+                key: key.to_owned(),
+                pos: position.pos,
+                len: position.len,
+                expires_at: position.expires_at,
+                version: position.version,
+            })
+            .collect();
+        let tmp_hint_path = path.join(format!(
+            "{}.{}.{}",
+            self.gen, HINT_EXTENSION, COMPACTION_EXTENSION
+        ));
+        if let Err(e) = kv_log::write_hint(&tmp_hint_path, &hint_path(&path, self.gen), &hints) {
+            log::error!("Cant write hint of generation {}: {}", self.gen, e);
+        }
+
+        // switch only records which were not overwritten meanwhile,
+        // expired records left behind are dropped with their generations
+        {
+            let mut index = self.index.write().unwrap();
+            for (key, old_position, new_position) in moved {
+                if let Some(position) = index.get_mut(&key) {
+                    if *position == old_position {
+                        *position = new_position;
                     }
                 }
             }
+            let left: Vec<Vec<u8>> = index
+                .iter()
+                .filter(|(_, position)| position.gen < self.gen)
+                .map(|(key, _)| key.to_owned())
+                .collect();
+            for key in left {
+                index.remove(&key);
+            }
+            self.reader.safe_gen.store(self.gen, Ordering::SeqCst);
         }
+
+        let stale_gens = sorted_gens(&path)?.into_iter().filter(|&g| g < self.gen);
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if snapshots.count > 0 {
+            log::info!("Stale generations are kept for live snapshots");
+            snapshots.stale_gens.extend(stale_gens);
+        } else {
+            for stale_gen in stale_gens {
+                remove_gen(&path, stale_gen);
+            }
+        }
+        drop(snapshots);
+        log::info!("Compaction into generation {} finished", self.gen);
         Ok(())
     }
+}
 
-    fn compaction(&mut self) -> Result<()> {
-        log::info!("Compaction triggered");
-        self.file.seek(SeekFrom::Start(0))?;
-        let buf_reader = BufReader::new(&self.file);
+/// Sync current generation every `ms` until the store is dropped
+fn spawn_interval_sync(writer: Weak<Mutex<KvStoreWriter>>, ms: u64) -> Result<()> {
+    thread::Builder::new().spawn(move || loop {
+        thread::sleep(Duration::from_millis(ms));
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        let writer = writer.lock().unwrap();
+        if let Err(e) = writer.sync() {
+            log::error!("Interval sync of generation {} failed: {}", writer.gen, e);
+        }
+    })?;
+    Ok(())
+}
 
-        let mut stream = Deserializer::from_reader(buf_reader).into_iter::<DBInsertion>();
+/// Append tombstones for expired keys and remove them from the index.
+/// Return number of removed keys
+fn remove_expired(index: &RwLock<Index>, writer: &Mutex<KvStoreWriter>) -> Result<usize> {
+    let now = expiry::now_ms();
+    let expired: Vec<(Vec<u8>, ItemPosition)> = index
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, position)| position.is_expired(now))
+        .map(|(key, position)| (key.to_owned(), position.clone()))
+        .collect();
 
-        let mut index: HashMap<String, String> = HashMap::new();
-        while let Some(Ok(insertion)) = stream.next() {
-            self.storage.remove(insertion.get_key());
-            match insertion {
-                DBInsertion::Set { key, value } => {
-                    index.insert(key.to_owned(), value.to_owned());
-                }
-                DBInsertion::Rm { key } => {
-                    index.remove(key.as_str());
-                }
-            }
+    let mut removed = 0;
+    for (key, expired_position) in expired {
+        let mut writer = writer.lock().unwrap();
+        // key could be set again meanwhile
+        if index.read().unwrap().get(&key) != Some(&expired_position) {
+            continue;
         }
-        // flush file
-        self.file.set_len(0)?;
-        self.possible_compaction = 0;
-        for (key, value) in index {
-            self.set(key, value)?
+        let position = writer.append(&DBInsertion::Rm { key: key.clone() })?;
+        index.write().unwrap().remove(&key);
+        writer.possible_compaction += (expired_position.len + position.len) as u64;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Delete log and hint of the generation, errors are only logged
+fn remove_gen(dir: &Path, gen: u64) {
+    if let Err(e) = fs::remove_file(log_path(dir, gen)) {
+        log::error!("Cant remove stale generation {}: {}", gen, e);
+    }
+    let stale_hint_path = hint_path(dir, gen);
+    if stale_hint_path.is_file() {
+        if let Err(e) = fs::remove_file(stale_hint_path) {
+            log::error!("Cant remove hint of generation {}: {}", gen, e);
         }
-        Ok(())
     }
+}
 
-    /// Open the KvStore at a given path. Return the KvStore.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path_buf = path.into();
-        path_buf.push(DATABASE_FILENAME);
-        KvStore::new(path_buf)
+/// Read first `limit` pairs with keys in range [start, end) not expired at `now`,
+/// caller keeps index locked
+fn scan_index(
+    reader: &KvStoreReader,
+    index: &Index,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    limit: usize,
+    now: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if end.as_ref().is_some_and(|end| *end <= start) {
+        return Ok(Vec::new());
+    }
+    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+    read_entries(
+        reader,
+        index.range((Bound::Included(start), end)),
+        limit,
+        now,
+    )
+}
+
+/// Read first `limit` pairs with keys starting with prefix not expired at `now`,
+/// caller keeps index locked
+fn scan_index_prefix(
+    reader: &KvStoreReader,
+    index: &Index,
+    prefix: Vec<u8>,
+    limit: usize,
+    now: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let range = index
+        .range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(prefix.as_slice()));
+    read_entries(reader, range, limit, now)
+}
+
+/// Read values of first `limit` positions not expired at `now`
+fn read_entries<'a, I>(
+    reader: &KvStoreReader,
+    positions: I,
+    limit: usize,
+    now: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    I: Iterator<Item = (&'a Vec<u8>, &'a ItemPosition)>,
+{
+    let mut entries = Vec::new();
+    let live = positions.filter(|(_, position)| !position.is_expired(now));
+    for (key, position) in live.take(limit) {
+        entries.push((key.to_owned(), reader.read_value(key, position)?));
+    }
+    Ok(entries)
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, LOG_EXTENSION))
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, HINT_EXTENSION))
+}
+
+fn new_log_file(dir: &Path, gen: u64) -> Result<File> {
+    let path = log_path(dir, gen);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&path)
+        .map_err(KVSError::io_at(&path))?;
+    if file.metadata().map_err(KVSError::io_at(&path))?.len() == 0 {
+        kv_log::write_header(&mut file).map_err(KVSError::in_file(&path))?;
+    }
+    Ok(file)
+}
+
+/// Generation numbers of all log files in the directory, ascending
+fn sorted_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(dir)
+        .map_err(KVSError::io_at(dir))?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.is_file())
+        .filter_map(|path| gen_of(&path, LOG_EXTENSION))
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Generation of the file named `<gen>.<suffix>`, `None` for files the store does not own
+fn gen_of(path: &Path, suffix: &str) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let gen = name.strip_suffix(suffix)?.strip_suffix('.')?;
+    if gen.is_empty() || !gen.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    gen.parse().ok()
+}
+
+/// Single file log of previous versions becomes the first generation
+fn upgrade_legacy_log(dir: &Path) -> Result<()> {
+    let legacy_path = dir.join(LEGACY_FILENAME);
+    if legacy_path.is_file() && sorted_gens(dir)?.is_empty() {
+        log::info!("Upgrading {:?} to generation 1", legacy_path);
+        fs::rename(&legacy_path, log_path(dir, 1)).map_err(KVSError::io_at(&legacy_path))?;
+    }
+    Ok(())
+}
+
+/// Compaction which was interrupted before the switch has nothing valuable,
+/// neither has value of streamed set not appended yet.
+/// Only `<gen>.compact`, `<gen>.hint.compact` and `<n>.spool` files of the store are removed
+fn remove_unfinished_writes(dir: &Path) -> Result<()> {
+    let hint_suffix = format!("{}.{}", HINT_EXTENSION, COMPACTION_EXTENSION);
+    for entry in fs::read_dir(dir).map_err(KVSError::io_at(dir))? {
+        let path = entry.map_err(KVSError::io_at(dir))?.path();
+        let owned = gen_of(&path, COMPACTION_EXTENSION).is_some()
+            || gen_of(&path, &hint_suffix).is_some()
+            || gen_of(&path, SPOOL_EXTENSION).is_some();
+        if path.is_file() && owned {
+            log::info!("Removing unfinished write {:?}", path);
+            fs::remove_file(&path).map_err(KVSError::io_at(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Hint of generation which log is gone must not describe new log with the same number
+fn remove_orphan_hints(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(KVSError::io_at(dir))? {
+        let path = entry.map_err(KVSError::io_at(dir))?.path();
+        let gen = match gen_of(&path, HINT_EXTENSION) {
+            Some(gen) if path.is_file() => gen,
+            _ => continue,
+        };
+        if !log_path(dir, gen).is_file() {
+            log::info!("Removing orphan hint {:?}", path);
+            fs::remove_file(&path).map_err(KVSError::io_at(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Load generation into the index from its hint file,
+/// or replay the whole generation when there is no hint, torn tail is cut off
+/// only in the `newest` one.
+/// Raise `version` to the highest one seen. Return number of stale bytes
+fn load_gen(
+    dir: &Path,
+    gen: u64,
+    newest: bool,
+    recovery: RecoveryPolicy,
+    index: &mut Index,
+    version: &mut u64,
+) -> Result<u64> {
+    let mut possible_compaction = 0;
+    if let Some(hints) = kv_log::read_hint(&hint_path(dir, gen))? {
+        for HintEntry {
+            key,
+            pos,
+            len,
+            expires_at,
+            version: entry_version,
+        } in hints
+        {
+            *version = (*version).max(entry_version);
+            let position = ItemPosition {
+                gen,
+                pos,
+                len,
+                expires_at,
+                version: entry_version,
+            };
+            if let Some(old_position) = index.insert(key, position) {
+                possible_compaction += old_position.len as u64;
+            }
+        }
+        return Ok(possible_compaction);
+    }
+
+    let path = log_path(dir, gen);
+    kv_log::replay(&path, gen, newest, recovery, |insertion, pos, len| {
+        *version = (*version).max(insertion.version());
+        let position = ItemPosition {
+            gen,
+            pos,
+            len,
+            expires_at: None,
+            version: 0,
+        };
+        possible_compaction += apply_to_index(index, insertion, &position);
+    })?;
+    Ok(possible_compaction)
+}
+
+/// Insert or remove keys of the record stored at position.
+/// Return number of bytes which became stale, to sum up for compaction
+fn apply_to_index(index: &mut Index, insertion: DBInsertion, position: &ItemPosition) -> u64 {
+    match insertion {
+        DBInsertion::Set {
+            key,
+            expires_at,
+            version,
+            ..
+        } => {
+            // expired value stays in the index to hide older ones until swept
+            let position = ItemPosition {
+                expires_at,
+                version,
+                ..position.clone()
+            };
+            index.insert(key, position).map_or(0, |old| old.len as u64)
+        }
+        DBInsertion::Rm { key } => {
+            let stale = index.remove(key.as_slice()).map_or(0, |old| old.len as u64);
+            stale + position.len as u64
+        }
+        // only raises the version, which the caller does
+        DBInsertion::VersionMark { .. } => position.len as u64,
+        // every put of the batch points to the whole batch record
+        DBInsertion::Batch { insertions } => insertions
+            .into_iter()
+            .map(|insertion| match insertion {
+                DBInsertion::Rm { key } => {
+                    index.remove(key.as_slice()).map_or(0, |old| old.len as u64)
+                }
+                insertion => apply_to_index(index, insertion, position),
+            })
+            .sum(),
     }
 }
@@ -1,12 +1,33 @@
 // #![deny(missing_docs)]
 //! Sled engine implementation
-use sled::Db;
+use sled::transaction::{self, TransactionError};
+use sled::{Db, IVec};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
+use crate::batch::{BatchOp, WriteBatch};
+use crate::counter;
+use crate::durability::{Durability, GroupCommit};
 use crate::engine::KvsEngine;
 use crate::error::{KVSError, Result};
+use crate::expiry::{self, Sweeper, DEFAULT_SWEEP_INTERVAL};
+use crate::snapshot::KvsSnapshot;
 
 const DATABASE_FILENAME: &str = "sled.db";
+/// Flush period of sled itself, when no explicit durability is asked
+const DEFAULT_FLUSH_MS: u64 = 500;
+/// First byte of stored value with expiration time header of previous versions,
+/// never starts valid UTF-8 so string values of older versions are told apart
+const VALUE_MARK: u8 = 0xff;
+/// Size of mark and expiration time before the value, 0 means no expiration
+const VALUE_HEADER_SIZE: usize = 9;
+/// First byte of stored value with expiration time and version header
+const VERSIONED_VALUE_MARK: u8 = 0xfe;
+/// Size of mark, expiration time and version before the value
+const VERSIONED_VALUE_HEADER_SIZE: usize = 17;
 
 /// Usage
 /// ```rust
@@ -19,53 +40,295 @@ const DATABASE_FILENAME: &str = "sled.db";
 ///
 /// let mut path = Path::new("/tmp/sled");
 ///
-/// let mut store = SledStore::open(path).unwrap();
-/// store.set("key1".to_owned(), "value1".to_owned());
-/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-/// store.remove("key1".to_owned());
+/// let store = SledStore::open(path).unwrap();
+/// store.set(b"key1".to_vec(), b"value1".to_vec());
+/// assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+/// store.remove(b"key1".to_vec());
 /// #
 /// #     Ok(())
 /// # }
 /// ```
-
-/// In memory key value storage String:String
 ///
-#[derive(Debug)]
+/// Key value storage of arbitrary bytes on top of sled
+///
+/// `sled::Db` is already a thread-safe handle, so cloning is cheap
+///
+/// Expiration time and version are stored in a header before the value.
+/// Expired values are hidden on read and removed by background sweeper.
+/// Versions come from `Db::generate_id`, conditional writes are
+/// retried `compare_and_swap` of the stored bytes and transactions
+/// are validated and applied inside `sled` transaction.
+///
+/// `sled` iterators are not point-in-time, so snapshot only takes a sequence
+/// number while writes wait at the snapshot gate. Writes after it keep the
+/// value each written key had for the newest live snapshot, first write of
+/// the key only, and snapshot reads the tree preferring kept values. Kept
+/// values stay in memory until the last snapshot seeing them is dropped,
+/// servers cap the number of live snapshots with `ServerConfig::max_snapshots`.
+///
+/// `sled` keeps a value in one piece, so streamed set and get use the
+/// buffered defaults of `KvsEngine` and hold the whole value.
+#[derive(Debug, Clone)]
 pub struct SledStore {
     tree: Db,
+    durability: Durability,
+    /// Sequence number of the last write, for group commit
+    written_seq: Arc<AtomicU64>,
+    group_commit: Arc<GroupCommit>,
+    /// Writes hold it shared, snapshot holds it exclusively while taking
+    /// its sequence number
+    snapshot_gate: Arc<RwLock<()>>,
+    history: Arc<Mutex<SnapshotHistory>>,
+    /// Held only to stop the sweeper with the last handle
+    _sweeper: Arc<Sweeper>,
+}
+
+/// Read-only point-in-time view of the SledStore, see `KvsEngine::snapshot`
+#[derive(Debug, Clone)]
+pub struct SledSnapshot {
+    tree: Db,
+    /// Values expired at this time are not in the snapshot
+    taken_at: u64,
+    guard: Arc<SnapshotGuard>,
+}
+
+/// Values of keys written while snapshots live, as the snapshots see them
+#[derive(Debug, Default)]
+struct SnapshotHistory {
+    next_seq: u64,
+    /// Kept values by sequence number of the live snapshot which was the newest
+    /// when the key was written, `None` for key missing then
+    kept: BTreeMap<u64, BTreeMap<Vec<u8>, Option<IVec>>>,
+}
+
+/// Registers snapshot as live until dropped
+#[derive(Debug)]
+struct SnapshotGuard {
+    seq: u64,
+    history: Arc<Mutex<SnapshotHistory>>,
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.history.lock().unwrap().release(self.seq);
+    }
 }
 
 impl KvsEngine for SledStore {
+    type Snapshot = SledSnapshot;
     /// Set up value by key into Sled
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.tree.insert(key, value.as_bytes())?;
-        self.tree.flush()?;
-        Ok(())
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let gate = self.write_gate();
+        self.keep([key.as_slice()])?;
+        let version = self.tree.generate_id()?;
+        self.tree.insert(key, encode_value(&value, None, version))?;
+        drop(gate);
+        self.written()
+    }
+    /// Set up value by key into Sled, expiring after ttl
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        let gate = self.write_gate();
+        self.keep([key.as_slice()])?;
+        let version = self.tree.generate_id()?;
+        self.tree
+            .insert(key, encode_value(&value, Some(expires_at), version))?;
+        drop(gate);
+        self.written()
     }
     /// Get value by key
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let val_ivec = self.tree.get(&key)?;
-
-        match val_ivec {
-            Some(ivec) => {
-                let value = String::from_utf8(ivec.to_vec())?;
-                Ok(Some(value))
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+    /// Apply puts and deletes as one `sled::Batch`
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let gate = self.write_gate();
+        self.keep(batch.ops().iter().map(op_key))?;
+        let mut sled_batch = sled::Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Put { key, value } => {
+                    let version = self.tree.generate_id()?;
+                    sled_batch.insert(key, encode_value(&value, None, version))
+                }
+                BatchOp::Delete { key } => sled_batch.remove(key),
             }
-            None => Ok(None),
+        }
+        self.tree.apply_batch(sled_batch)?;
+        drop(gate);
+        self.written()
+    }
+    /// Remaining lifetime of the key
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = expiry::now_ms();
+        match self
+            .tree
+            .get(&key)?
+            .map(|ivec| decode_value(&ivec).expires_at)
+        {
+            Some(expires_at) if !expiry::is_expired(expires_at, now) => {
+                Ok(expiry::remaining(expires_at, now))
+            }
+            _ => Err(KVSError::KeyNotFoundError),
+        }
+    }
+    /// Get pairs with keys in range [start, end) ordered by key
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match end {
+            Some(end) if end <= start => Ok(Vec::new()),
+            Some(end) => collect_entries(self.tree.range(start..end), limit),
+            None => collect_entries(self.tree.range(start..), limit),
         }
     }
+    /// Get pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        collect_entries(self.tree.scan_prefix(prefix), limit)
+    }
+    /// Take sequence number of the snapshot while writes wait,
+    /// later writes keep the values it sees
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let gate = self.snapshot_gate.write().unwrap();
+        let taken_at = expiry::now_ms();
+        let seq = self.history.lock().unwrap().take();
+        drop(gate);
+        Ok(SledSnapshot {
+            tree: self.tree.clone(),
+            taken_at,
+            guard: Arc::new(SnapshotGuard {
+                seq,
+                history: Arc::clone(&self.history),
+            }),
+        })
+    }
     /// Removes value by key
-    fn remove(&mut self, key: String) -> Result<()> {
-        if let Ok(old_value_option) = self.tree.remove(&key) {
-            match old_value_option {
-                Some(_v) => {
-                    self.tree.flush()?;
-                    Ok(())
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let gate = self.write_gate();
+        self.keep([key.as_slice()])?;
+        let removed = self.tree.remove(&key);
+        drop(gate);
+        let now = expiry::now_ms();
+        match removed?.map(|ivec| decode_value(&ivec).expires_at) {
+            Some(expires_at) if !expiry::is_expired(expires_at, now) => self.written(),
+            _ => Err(KVSError::KeyNotFoundError),
+        }
+    }
+    /// Validate versions of read keys and apply writes in one `sled` transaction
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: WriteBatch,
+    ) -> Result<()> {
+        let gate = self.write_gate();
+        self.keep(writes.ops().iter().map(op_key))?;
+        let res = self.tree.transaction(|tree| {
+            let now = expiry::now_ms();
+            for (key, version) in &reads {
+                let current = tree.get(key)?;
+                let current_version = current
+                    .as_deref()
+                    .map(decode_value)
+                    .filter(|stored| !stored.is_expired(now))
+                    .map(|stored| stored.version);
+                if current_version != *version {
+                    return transaction::abort(KVSError::TransactionConflictError);
+                }
+            }
+            for op in writes.ops() {
+                match op {
+                    BatchOp::Put { key, value } => {
+                        let version = tree.generate_id()?;
+                        tree.insert(key.as_slice(), encode_value(value, None, version))?;
+                    }
+                    BatchOp::Delete { key } => {
+                        tree.remove(key.as_slice())?;
+                    }
                 }
-                None => Err(KVSError::GeneralKVSError),
             }
-        } else {
-            Err(KVSError::GeneralKVSError)
+            Ok(())
+        });
+        drop(gate);
+        match res {
+            Ok(()) => self.written(),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+    /// Get value by key with its version
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let val_ivec = self.tree.get(&key)?;
+
+        match val_ivec.as_deref().map(decode_value) {
+            Some(stored) if stored.is_expired(expiry::now_ms()) => Ok(None),
+            Some(stored) => Ok(Some((stored.value.to_vec(), stored.version))),
+            None => Ok(None),
+        }
+    }
+    /// Replace value when current one equals expected
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.write_if(&key, new, None, |current| {
+            current.map(|stored| stored.value) == expected.as_deref()
+        })?;
+        Ok(())
+    }
+    /// Set value when the key is missing
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        self.write_if(&key, Some(value), None, |current| current.is_none())
+    }
+    /// Set value when current one has given version
+    fn set_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<u64> {
+        self.write_if(&key, Some(value), None, |current| {
+            current.is_some_and(|stored| stored.version == version)
+        })
+    }
+    /// Set expiring value when the key is missing
+    fn set_if_absent_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<u64> {
+        let expires_at = Some(expiry::expires_at(ttl));
+        self.write_if(&key, Some(value), expires_at, |current| current.is_none())
+    }
+    /// Set expiring value when current one has given version
+    fn set_if_version_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let expires_at = Some(expiry::expires_at(ttl));
+        self.write_if(&key, Some(value), expires_at, |current| {
+            current.is_some_and(|stored| stored.version == version)
+        })
+    }
+    /// Add delta to the counter, retrying when other writer changed the key
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let now = expiry::now_ms();
+        let gate = self.write_gate();
+        self.keep([key.as_slice()])?;
+        loop {
+            let current = self.tree.get(&key)?;
+            let stored = current.as_deref().map(decode_value);
+            let live = stored.filter(|stored| !stored.is_expired(now));
+            let (counter, value) = counter::add(live.as_ref().map(|stored| stored.value), delta)?;
+            let expires_at = live.and_then(|stored| stored.expires_at);
+            let new = encode_value(&value, expires_at, self.tree.generate_id()?);
+            if self
+                .tree
+                .compare_and_swap(&key, current, Some(new))?
+                .is_ok()
+            {
+                drop(gate);
+                self.written()?;
+                return Ok(counter);
+            }
         }
     }
 }
@@ -73,15 +336,364 @@ impl KvsEngine for SledStore {
 impl SledStore {
     /// Create new instance of Sled
     pub fn new(path: PathBuf) -> Result<Self> {
-        let tree = sled::open(path)?;
-        let obj = Self { tree };
-        Ok(obj)
+        SledStore::with_durability(path, Durability::default())
+    }
+
+    /// Create new instance of Sled syncing writes by durability policy
+    pub fn with_durability(path: PathBuf, durability: Durability) -> Result<Self> {
+        let flush_every_ms = match durability {
+            Durability::Interval(ms) => ms,
+            _ => DEFAULT_FLUSH_MS,
+        };
+        let tree = sled::Config::new()
+            .path(path)
+            .flush_every_ms(Some(flush_every_ms))
+            .open()?;
+        let snapshot_gate = Arc::new(RwLock::new(()));
+        let history = Arc::new(Mutex::new(SnapshotHistory::default()));
+        let sweeper = {
+            let tree = tree.clone();
+            let snapshot_gate = Arc::clone(&snapshot_gate);
+            let history = Arc::clone(&history);
+            Sweeper::spawn(DEFAULT_SWEEP_INTERVAL, move || {
+                let _gate = snapshot_gate.read().unwrap();
+                remove_expired(&tree, &history)
+            })?
+        };
+        Ok(Self {
+            tree,
+            durability,
+            written_seq: Arc::new(AtomicU64::new(0)),
+            group_commit: Arc::new(GroupCommit::default()),
+            snapshot_gate,
+            history,
+            _sweeper: Arc::new(sweeper),
+        })
     }
 
     /// Open the Sled at a given path. Return the Sled tree.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledStore> {
+        SledStore::open_with_durability(path, Durability::default())
+    }
+
+    /// Open the Sled at a given path with durability policy
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<SledStore> {
         let mut path_buf = path.into();
         path_buf.push(DATABASE_FILENAME);
-        SledStore::new(path_buf)
+        SledStore::with_durability(path_buf, durability)
+    }
+
+    /// Set the value expiring at `expires_at`, or remove the key for `None`,
+    /// when `check` accepts the current value (`None` for missing or expired key).
+    /// Return version of the new value, 0 for removal
+    fn write_if<F>(
+        &self,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+        check: F,
+    ) -> Result<u64>
+    where
+        F: Fn(Option<&Stored>) -> bool,
+    {
+        let now = expiry::now_ms();
+        let gate = self.write_gate();
+        self.keep([key])?;
+        loop {
+            let current = self.tree.get(key)?;
+            let stored = current.as_deref().map(decode_value);
+            let live = stored.as_ref().filter(|stored| !stored.is_expired(now));
+            if !check(live) {
+                return Err(KVSError::PreconditionFailedError);
+            }
+            let (new, version) = match &value {
+                Some(value) => {
+                    let version = self.tree.generate_id()?;
+                    (Some(encode_value(value, expires_at, version)), version)
+                }
+                None if live.is_none() => return Ok(0),
+                None => (None, 0),
+            };
+            // other writer changed the key since the read, check again
+            if self.tree.compare_and_swap(key, current, new)?.is_ok() {
+                drop(gate);
+                self.written()?;
+                return Ok(version);
+            }
+        }
+    }
+
+    /// Shared hold of the snapshot gate for the time of the write
+    fn write_gate(&self) -> RwLockReadGuard<'_, ()> {
+        self.snapshot_gate.read().unwrap()
+    }
+
+    /// Keep values of the keys for live snapshots before writing them,
+    /// called with the snapshot gate held
+    fn keep<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        let mut history = self.history.lock().unwrap();
+        keys.into_iter()
+            .try_for_each(|key| history.keep(&self.tree, key))
+    }
+
+    /// Sync write according to durability policy
+    fn written(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => {
+                self.tree.flush()?;
+            }
+            Durability::GroupCommit => {
+                let seq = self.written_seq.fetch_add(1, Ordering::SeqCst) + 1;
+                self.group_commit.commit(seq, || {
+                    let covered_seq = self.written_seq.load(Ordering::SeqCst);
+                    self.tree.flush()?;
+                    Ok(covered_seq)
+                })?;
+            }
+            Durability::None | Durability::Interval(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    /// Get value by key as the snapshot sees it
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let current = self.tree.get(&key)?;
+        Ok(self
+            .value(&key, current)
+            .as_deref()
+            .map(decode_value)
+            .filter(|stored| !stored.is_expired(self.taken_at))
+            .map(|stored| stored.value.to_vec()))
+    }
+    /// Get pairs with keys in range [start, end) ordered by key
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let iter = match &end {
+            Some(end) if *end <= start => return Ok(Vec::new()),
+            Some(end) => self.tree.range(start.as_slice()..end.as_slice()),
+            None => self.tree.range(start.as_slice()..),
+        };
+        let in_range = |key: &[u8]| end.as_ref().is_none_or(|end| key < end.as_slice());
+        self.live_entries(iter, &start, in_range, limit)
+    }
+    /// Get pairs with keys starting with prefix ordered by key
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let iter = self.tree.scan_prefix(&prefix);
+        self.live_entries(iter, &prefix, |key| key.starts_with(&prefix), limit)
+    }
+}
+
+impl SledSnapshot {
+    /// Value of the key as the snapshot sees it: kept value when the key
+    /// was written since, otherwise the current one. Current value is read
+    /// before the kept one, as writes keep the value before changing it
+    fn value(&self, key: &[u8], current: Option<IVec>) -> Option<IVec> {
+        let history = self.guard.history.lock().unwrap();
+        history.value_at(self.guard.seq, key).unwrap_or(current)
+    }
+
+    /// First `limit` pairs not expired when the snapshot was taken: pairs of
+    /// the tree iterator as the snapshot sees them and kept pairs of keys
+    /// removed from the tree since, keys from `start` while `in_range` holds
+    fn live_entries<I, F>(
+        &self,
+        iter: I,
+        start: &[u8],
+        in_range: F,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        I: Iterator<Item = sled::Result<(IVec, IVec)>>,
+        F: Fn(&[u8]) -> bool,
+    {
+        let is_live = |value: &IVec| !decode_value(value).is_expired(self.taken_at);
+        let mut entries = BTreeMap::new();
+        for item in iter {
+            if entries.len() >= limit {
+                break;
+            }
+            let (key, current) = item?;
+            if let Some(value) = self.value(&key, Some(current)).filter(is_live) {
+                entries.insert(key.to_vec(), value);
+            }
+        }
+        // kept keys past the last pair fall out of the limit, when iteration stopped at it
+        let kept = self
+            .guard
+            .history
+            .lock()
+            .unwrap()
+            .values_from(self.guard.seq, start);
+        for (key, value) in kept.into_iter().take_while(|(key, _)| in_range(key)) {
+            if let Some(value) = value.filter(is_live) {
+                entries.entry(key).or_insert(value);
+            }
+        }
+        Ok(entries
+            .into_iter()
+            .take(limit)
+            .map(|(key, value)| (key, decode_value(&value).value.to_vec()))
+            .collect())
+    }
+}
+
+impl SnapshotHistory {
+    /// Register new live snapshot, return its sequence number
+    fn take(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.kept.insert(seq, BTreeMap::new());
+        seq
+    }
+
+    /// Keep the value of the key for the newest live snapshot,
+    /// unless the key was written since it and is kept already
+    fn keep(&mut self, tree: &Db, key: &[u8]) -> Result<()> {
+        if let Some((_, kept)) = self.kept.iter_mut().next_back() {
+            if !kept.contains_key(key) {
+                kept.insert(key.to_vec(), tree.get(key)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Value kept for the snapshot, the first one kept since it was taken.
+    /// `None` when the key was not written since
+    fn value_at(&self, seq: u64, key: &[u8]) -> Option<Option<IVec>> {
+        self.kept
+            .range(seq..)
+            .find_map(|(_, kept)| kept.get(key))
+            .cloned()
+    }
+
+    /// Values kept for the snapshot with keys from `start`
+    fn values_from(&self, seq: u64, start: &[u8]) -> BTreeMap<Vec<u8>, Option<IVec>> {
+        let mut values = BTreeMap::new();
+        for (_, kept) in self.kept.range(seq..) {
+            for (key, value) in kept.range(start.to_vec()..) {
+                values.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        values
+    }
+
+    /// Forget dropped snapshot. Its values are passed to the previous live
+    /// snapshot for keys not written since that one, as it sees them too
+    fn release(&mut self, seq: u64) {
+        let kept = self.kept.remove(&seq).unwrap_or_default();
+        if let Some((_, previous)) = self.kept.range_mut(..seq).next_back() {
+            for (key, value) in kept {
+                previous.entry(key).or_insert(value);
+            }
+        }
+    }
+}
+
+/// Key written by the operation
+fn op_key(op: &BatchOp) -> &[u8] {
+    match op {
+        BatchOp::Put { key, .. } | BatchOp::Delete { key } => key,
+    }
+}
+
+/// Collect first `limit` not expired pairs of sled iterator
+fn collect_entries<I>(iter: I, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    I: Iterator<Item = sled::Result<(IVec, IVec)>>,
+{
+    let now = expiry::now_ms();
+    let mut entries = Vec::new();
+    for item in iter {
+        if entries.len() >= limit {
+            break;
+        }
+        let (key, value) = item?;
+        let stored = decode_value(&value);
+        if stored.is_expired(now) {
+            continue;
+        }
+        entries.push((key.to_vec(), stored.value.to_vec()));
+    }
+    Ok(entries)
+}
+
+/// Value with its header as stored in the tree
+struct Stored<'a> {
+    expires_at: Option<u64>,
+    /// Values of previous versions have version 0
+    version: u64,
+    value: &'a [u8],
+}
+
+impl Stored<'_> {
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires_at, now)
+    }
+}
+
+/// Put header with expiration time and version before the value
+fn encode_value(value: &[u8], expires_at: Option<u64>, version: u64) -> Vec<u8> {
+    [
+        vec![VERSIONED_VALUE_MARK],
+        expires_at.unwrap_or(0).to_be_bytes().to_vec(),
+        version.to_be_bytes().to_vec(),
+        value.to_vec(),
+    ]
+    .concat()
+}
+
+/// Split stored bytes into header and value,
+/// values of previous versions have shorter header or no header at all
+fn decode_value(stored: &[u8]) -> Stored<'_> {
+    let header_size = match stored.first() {
+        Some(&VERSIONED_VALUE_MARK) if stored.len() >= VERSIONED_VALUE_HEADER_SIZE => {
+            VERSIONED_VALUE_HEADER_SIZE
+        }
+        Some(&VALUE_MARK) if stored.len() >= VALUE_HEADER_SIZE => VALUE_HEADER_SIZE,
+        _ => {
+            return Stored {
+                expires_at: None,
+                version: 0,
+                value: stored,
+            }
+        }
+    };
+    let read_u64 =
+        |offset: usize| u64::from_be_bytes(stored[offset..offset + 8].try_into().unwrap());
+    let version = match header_size {
+        VERSIONED_VALUE_HEADER_SIZE => read_u64(VALUE_HEADER_SIZE),
+        _ => 0,
+    };
+    Stored {
+        expires_at: Some(read_u64(1)).filter(|&expires_at| expires_at != 0),
+        version,
+        value: &stored[header_size..],
+    }
+}
+
+/// Remove expired values which were not overwritten meanwhile,
+/// keeping them for live snapshots first. Return number of removed keys
+fn remove_expired(tree: &Db, history: &Mutex<SnapshotHistory>) -> Result<usize> {
+    let now = expiry::now_ms();
+    let mut removed = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        if !decode_value(&value).is_expired(now) {
+            continue;
+        }
+        history.lock().unwrap().keep(tree, &key)?;
+        if let Ok(Ok(())) = tree.compare_and_swap(&key, Some(&value), None as Option<&[u8]>) {
+            removed += 1;
+        }
     }
+    Ok(removed)
 }
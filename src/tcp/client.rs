@@ -1,26 +1,475 @@
-use crate::error::Result;
-use crate::tcp::protocol::{DBCommands, ServerResponse};
-use std::io::Write;
-use std::net::TcpStream;
+use crate::error::{KVSError, Result};
+use crate::tcp::protocol::{
+    Capabilities, DBCommands, RequestId, ServerResponse, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    STREAM_CHUNK_SIZE,
+};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
 
-/// KVS client to communicate with server
+/// KVS client to communicate with server.
+/// Every connection starts with HELLO agreeing on protocol version.
+/// One connection is reused for all commands; when the server closed it,
+/// like after the idle timeout, the command is sent on a new connection.
+/// Command lost with the connection after it was written is sent again
+/// only when running it twice does no harm
 pub struct KVSClient {
-    stream: TcpStream,
+    addr: String,
+    stream: Option<TcpStream>,
+    in_transaction: bool,
+    next_id: RequestId,
+    version: u16,
+    capabilities: Capabilities,
 }
 
 impl KVSClient {
     /// Create server connection
     pub fn new(addr: String) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(KVSClient { stream })
+        let mut client = KVSClient {
+            addr,
+            stream: None,
+            in_transaction: false,
+            next_id: 1,
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+        };
+        client.stream = Some(client.connect()?);
+        Ok(client)
     }
 
-    /// send command to server
+    /// Protocol version agreed with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.version
+    }
+
+    /// Capabilities granted by the server
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Open connection and agree on protocol version with the server.
+    /// Server which does not know HELLO usually closes the connection
+    fn connect(&mut self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        let hello = DBCommands::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        stream.write_all(&hello.to_packet_with_id(0)?)?;
+        stream.flush()?;
+        let resp = ServerResponse::next_from_stream(&mut stream).map_err(|e| {
+            KVSError::protocol(format!(
+                "handshake failed, server may speak older protocol: {}",
+                e
+            ))
+        })?;
+        match resp {
+            Some(ServerResponse::Hello {
+                version,
+                capabilities,
+            }) if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                self.version = version;
+                self.capabilities = capabilities;
+                Ok(stream)
+            }
+            Some(ServerResponse::Hello { version, .. }) => Err(KVSError::protocol(format!(
+                "server chose unsupported protocol version {}",
+                version
+            ))),
+            Some(ServerResponse::Failure { code, message }) => {
+                Err(KVSError::from_failure(code, message))
+            }
+            Some(resp) => Err(unexpected(resp)),
+            None => Err(KVSError::protocol(
+                "server closed connection in handshake, it may speak older protocol",
+            )),
+        }
+    }
+
+    /// send command to server.
+    /// Command is not sent again when the connection is lost in a transaction,
+    /// as the transaction is discarded with the connection
     pub fn send_cmd(&mut self, command: DBCommands) -> Result<ServerResponse> {
-        let cmd_packet = command.to_packet()?;
-        let _ = &self.stream.write_all(&cmd_packet)?;
-        let _ = &self.stream.flush()?;
+        self.send_one(command).map(|(_, resp)| resp)
+    }
+
+    /// Send command, return its request id with the response
+    fn send_one(&mut self, command: DBCommands) -> Result<(RequestId, ServerResponse)> {
+        let id = self.next_id;
+        let mut resps = self.send_all(vec![command])?;
+        Ok((id, resps.remove(0)))
+    }
+
+    /// Start pipeline of commands sent without waiting for responses
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Send all commands at once and return their responses in the same order
+    fn send_all(&mut self, commands: Vec<DBCommands>) -> Result<Vec<ServerResponse>> {
+        let first_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(commands.len() as RequestId);
+        let effects: Vec<_> = commands.iter().map(transaction_effect).collect();
+        let idempotent = commands.iter().all(is_idempotent);
+        let mut packets = Vec::new();
+        for (id, command) in (first_id..).zip(commands) {
+            packets.extend(command.to_packet_with_id(id)?);
+        }
+
+        if self.stream.as_ref().is_some_and(is_dropped) {
+            log::debug!("Connection closed by server, reconnecting to {}", self.addr);
+            self.stream = None;
+            if self.in_transaction {
+                return Err(self.closed());
+            }
+        }
+        let reused = self.stream.is_some();
+        let mut outcome = self.exchange(&packets, first_id, effects.len())?;
+        let resend = match outcome {
+            Exchange::NotSent => true,
+            Exchange::Lost => idempotent,
+            Exchange::Responses(_) => false,
+        };
+        if resend && reused && !self.in_transaction {
+            log::debug!("Connection lost, sending again to {}", self.addr);
+            outcome = self.exchange(&packets, first_id, effects.len())?;
+        }
+        let resps = match outcome {
+            Exchange::Responses(resps) => resps,
+            Exchange::NotSent | Exchange::Lost => return Err(self.closed()),
+        };
+        for (effect, resp) in effects.iter().zip(&resps) {
+            match (effect, resp) {
+                (Some(true), ServerResponse::Success { .. }) => self.in_transaction = true,
+                (Some(false), _) => self.in_transaction = false,
+                _ => {}
+            }
+        }
+        Ok(resps)
+    }
+
+    /// Write packets and read `count` responses, connecting when there is no connection
+    fn exchange(&mut self, packets: &[u8], first_id: RequestId, count: usize) -> Result<Exchange> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        let resps = if count == 1 {
+            if let Err(e) = stream.write_all(packets).and_then(|()| stream.flush()) {
+                return match e.kind() {
+                    ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted => Ok(Exchange::NotSent),
+                    _ => Err(e.into()),
+                };
+            }
+            self.read_responses(&mut stream, first_id, count)
+        } else {
+            // responses are read while the rest is written,
+            // so neither side blocks on full socket buffers
+            let mut writer = stream.try_clone()?;
+            thread::scope(|scope| {
+                scope.spawn(move || {
+                    if writer
+                        .write_all(packets)
+                        .and_then(|()| writer.flush())
+                        .is_err()
+                    {
+                        let _ = writer.shutdown(Shutdown::Both);
+                    }
+                });
+                let resps = self.read_responses(&mut stream, first_id, count);
+                if !matches!(resps, Ok(Some(_))) {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                resps
+            })
+        }?;
+        match resps {
+            Some(resps) => {
+                self.stream = Some(stream);
+                Ok(Exchange::Responses(resps))
+            }
+            None => Ok(Exchange::Lost),
+        }
+    }
+
+    /// Read `count` responses matched to requests by id
+    fn read_responses(
+        &mut self,
+        stream: &mut TcpStream,
+        first_id: RequestId,
+        count: usize,
+    ) -> Result<Option<Vec<ServerResponse>>> {
+        let mut slots: Vec<Option<ServerResponse>> = (0..count).map(|_| None).collect();
+        for received in 0..count {
+            let (id, resp) = match ServerResponse::next_with_id_from_stream(stream)? {
+                Some(response) => response,
+                None if received == 0 => return Ok(None),
+                None => return Err(self.closed()),
+            };
+            match slots.get_mut(id.wrapping_sub(first_id) as usize) {
+                Some(slot @ None) => *slot = Some(resp),
+                _ => return Err(KVSError::protocol(format!("unexpected response id {}", id))),
+            }
+        }
+        Ok(Some(slots.into_iter().flatten().collect()))
+    }
+
+    /// Error of the connection lost before all responses were read,
+    /// open transaction is lost with it
+    fn closed(&mut self) -> KVSError {
+        self.in_transaction = false;
+        io::Error::new(ErrorKind::ConnectionAborted, "connection closed by server").into()
+    }
+
+    /// Send command to server, failure response becomes typed error
+    /// like `KeyNotFoundError` or `PreconditionFailedError`
+    pub fn request(&mut self, command: DBCommands) -> Result<ServerResponse> {
+        self.send_cmd(command)?.into_result()
+    }
+
+    /// Get value by key, `None` for missing key
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(DBCommands::Get { key })? {
+            ServerResponse::Value { value } => Ok(Some(value)),
+            ServerResponse::NotFound => Ok(None),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get value by key with its version, `None` for missing key
+    pub fn get_versioned(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        match self.request(DBCommands::GetVersioned { key })? {
+            ServerResponse::Versioned { value, version } => Ok(Some((value, version))),
+            ServerResponse::NotFound => Ok(None),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set value of `len` bytes read from `value` in chunks,
+    /// so the client never holds the whole value
+    pub fn set_stream<R: Read>(&mut self, key: Vec<u8>, value: &mut R, len: u64) -> Result<()> {
+        self.send_stream(key, value, len, None)
+    }
+
+    /// Set value of `len` bytes read from `value` in chunks, expiring after ttl
+    pub fn set_stream_with_ttl<R: Read>(
+        &mut self,
+        key: Vec<u8>,
+        value: &mut R,
+        len: u64,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send_stream(key, value, len, Some(ttl))
+    }
 
-        ServerResponse::from_stream(&mut self.stream)
+    fn send_stream<R: Read>(
+        &mut self,
+        key: Vec<u8>,
+        value: &mut R,
+        len: u64,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.require(Capabilities::STREAMING)?;
+        // server answers once it is ready for the chunks, with their size
+        let (id, resp) = self.send_one(DBCommands::SetStream { key, len, ttl })?;
+        let chunk_size = match resp.into_result()? {
+            ServerResponse::Success { output } => output
+                .parse::<usize>()
+                .map_or(STREAM_CHUNK_SIZE, |size| size.clamp(1, STREAM_CHUNK_SIZE)),
+            resp => return Err(unexpected(resp)),
+        };
+        let res = self
+            .write_chunks(id, value, len, chunk_size)
+            .and_then(|()| self.next_response(id)?.into_result());
+        self.keep_if_ok(res).map(|_| ())
     }
+
+    /// Write value of the key to `out` in chunks, so the client never holds
+    /// the whole value. Return length of the value, `None` for missing key
+    pub fn get_stream<W: Write>(&mut self, key: Vec<u8>, out: &mut W) -> Result<Option<u64>> {
+        self.require(Capabilities::STREAMING)?;
+        let (id, resp) = self.send_one(DBCommands::GetStream { key })?;
+        let len = match resp.into_result()? {
+            ServerResponse::Stream { len } => len,
+            ServerResponse::NotFound => return Ok(None),
+            resp => return Err(unexpected(resp)),
+        };
+        let res = self.read_chunks(id, out, len);
+        self.keep_if_ok(res).map(|()| Some(len))
+    }
+
+    fn write_chunks<R: Read>(
+        &mut self,
+        id: RequestId,
+        value: &mut R,
+        len: u64,
+        chunk_size: usize,
+    ) -> Result<()> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Err(self.closed()),
+        };
+        let mut chunk = vec![0u8; len.min(chunk_size as u64) as usize];
+        let mut left = len;
+        while left > 0 {
+            let size = left.min(chunk.len() as u64) as usize;
+            value.read_exact(&mut chunk[..size])?;
+            let data = chunk[..size].to_vec();
+            stream.write_all(&DBCommands::Chunk { data }.to_packet_with_id(id)?)?;
+            left -= size as u64;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn read_chunks<W: Write>(&mut self, id: RequestId, out: &mut W, len: u64) -> Result<()> {
+        let mut left = len;
+        while left > 0 {
+            // server ends the chunks with failure when it cant read the value
+            match self.next_response(id)?.into_result()? {
+                ServerResponse::Chunk { data } if data.len() as u64 <= left => {
+                    out.write_all(&data)?;
+                    left -= data.len() as u64;
+                }
+                resp => return Err(unexpected(resp)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read next response of request `id` on the current connection
+    fn next_response(&mut self, id: RequestId) -> Result<ServerResponse> {
+        let next = match self.stream.as_mut() {
+            Some(stream) => ServerResponse::next_with_id_from_stream(stream)?,
+            None => None,
+        };
+        match next {
+            Some((resp_id, resp)) if resp_id == id => Ok(resp),
+            Some((resp_id, _)) => Err(KVSError::protocol(format!(
+                "unexpected response id {}",
+                resp_id
+            ))),
+            None => Err(self.closed()),
+        }
+    }
+
+    /// Drop the connection when streaming failed half way,
+    /// as the rest of the stream would be taken for next responses
+    fn keep_if_ok<T>(&mut self, res: Result<T>) -> Result<T> {
+        if res.is_err() {
+            self.stream = None;
+            self.in_transaction = false;
+        }
+        res
+    }
+
+    /// Fail unless the server granted the capability
+    fn require(&self, capability: Capabilities) -> Result<()> {
+        if !self.capabilities.contains(capability) {
+            return Err(KVSError::protocol(format!(
+                "server does not support {:?}",
+                capability
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Commands sent together by `KVSClient::pipeline`,
+/// the server serves them in order without waiting for the client
+pub struct Pipeline<'a> {
+    client: &'a mut KVSClient,
+    commands: Vec<DBCommands>,
+}
+
+impl Pipeline<'_> {
+    /// Add command to the pipeline
+    pub fn add(&mut self, command: DBCommands) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Send all commands and return their responses in order of the commands.
+    /// Failure of a command is its `Failure` response, not an error of the pipeline
+    pub fn execute(self) -> Result<Vec<ServerResponse>> {
+        if self.commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.commands.iter().any(|command| {
+            matches!(
+                command,
+                DBCommands::SetStream { .. }
+                    | DBCommands::GetStream { .. }
+                    | DBCommands::Chunk { .. }
+            )
+        }) {
+            return Err(KVSError::protocol("streamed set and get cant be pipelined"));
+        }
+        if !self.client.capabilities.contains(Capabilities::PIPELINING) {
+            // server serves one request at a time
+            return self
+                .commands
+                .into_iter()
+                .map(|command| self.client.send_cmd(command))
+                .collect();
+        }
+        self.client.send_all(self.commands)
+    }
+}
+
+/// Outcome of writing commands and reading their responses
+enum Exchange {
+    Responses(Vec<ServerResponse>),
+    /// Server closed the connection before the commands were written
+    NotSent,
+    /// Server closed the connection before the first response,
+    /// the commands may have run
+    Lost,
+}
+
+/// Connection closed by the server, or with bytes no request waits for,
+/// can't be used for the next request
+fn is_dropped(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    let dropped = stream.set_nonblocking(true).is_err()
+        || !matches!(stream.peek(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_err() || dropped
+}
+
+/// Running the command twice has the same effect and response as running it
+/// once, so it may be sent again when the connection was lost after writing it
+fn is_idempotent(command: &DBCommands) -> bool {
+    !matches!(
+        command,
+        DBCommands::Incr { .. }
+            | DBCommands::Batch { .. }
+            | DBCommands::Cas { .. }
+            | DBCommands::SetIfAbsent { .. }
+            | DBCommands::SetIfVersion { .. }
+            // second run reports missing key or snapshot
+            | DBCommands::Rm { .. }
+            | DBCommands::ReleaseSnapshot { .. }
+            // second run takes one more snapshot
+            | DBCommands::Snapshot
+    )
+}
+
+/// Whether the command starts (`Some(true)`) or ends (`Some(false)`) transaction
+fn transaction_effect(command: &DBCommands) -> Option<bool> {
+    match command {
+        DBCommands::Begin => Some(true),
+        DBCommands::Commit | DBCommands::Abort => Some(false),
+        _ => None,
+    }
+}
+
+/// Response of other kind than the command expects
+fn unexpected(resp: ServerResponse) -> KVSError {
+    KVSError::protocol(format!("unexpected response {:?}", resp))
 }
@@ -0,0 +1,579 @@
+// #![deny(missing_docs)]
+//! Gateway speaking HTTP/1.1 with JSON, so browsers and scripts
+//! can use the server with `curl`. Requests are mapped onto `KvsEngine`:
+//! `GET`, `PUT` and `DELETE` of `/keys/{key}`, listing with
+//! `GET /keys?prefix=&limit=` and atomic `POST /batch`
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::batch::WriteBatch;
+use crate::encoding::Encoding;
+use crate::engine::KvsEngine;
+use crate::error::{ErrorCode, KVSError, Result};
+use crate::expiry::parse_ttl;
+use crate::tcp::protocol::FrameLimits;
+use crate::tcp::server::{idle_as_closed, read_line, reject, ServerConfig, Workers};
+
+/// Entries listed without `limit`
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// Most entries listed, larger `limit` is cut to it
+const MAX_LIST_LIMIT: usize = 1000;
+/// Most header lines of a request
+const MAX_HEADERS: usize = 100;
+
+/// Start of the request up to its body
+#[derive(Debug)]
+struct Head {
+    method: String,
+    /// Path with query, as sent
+    target: String,
+    /// Header names in lower case
+    headers: Vec<(String, String)>,
+    content_length: usize,
+    keep_alive: bool,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Body is a value, limited by the value size rather than the frame size
+    fn is_value_upload(&self) -> bool {
+        self.method == "PUT" && self.target.starts_with("/keys/")
+    }
+}
+
+/// Response with its status, extra headers and body
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            content_type: "",
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn json(status: u16, value: serde_json::Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn bytes(value: Vec<u8>) -> Response {
+        Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            headers: Vec::new(),
+            body: value,
+        }
+    }
+
+    /// JSON body with code, kind and message of the error,
+    /// status follows from its code
+    fn error(e: &KVSError) -> Response {
+        let code = ErrorCode::from(e);
+        let status = match code {
+            ErrorCode::KeyNotFound | ErrorCode::SnapshotNotFound => 404,
+            ErrorCode::PreconditionFailed | ErrorCode::TransactionConflict => 409,
+            ErrorCode::TooLarge => 413,
+            ErrorCode::Protocol
+            | ErrorCode::NotAnInteger
+            | ErrorCode::InvalidCommand
+            | ErrorCode::UnsupportedVersion => 400,
+            ErrorCode::Internal | ErrorCode::Io | ErrorCode::Corrupted => 500,
+            ErrorCode::Busy => 503,
+        };
+        let body = json!({
+            "error": {
+                "code": code.as_u16(),
+                "kind": format!("{:?}", code),
+                "message": e.to_string(),
+            }
+        });
+        Response::json(status, body)
+    }
+
+    /// Error of the request which is not about the store, like unknown path
+    fn invalid(status: u16, message: impl Into<String>) -> Response {
+        let e = KVSError::ServerError {
+            code: ErrorCode::InvalidCommand,
+            message: message.into(),
+        };
+        Response {
+            status,
+            ..Response::error(&e)
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn to_bytes(&self, close: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if self.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+            head.push_str(&format!("Content-Type: {}\r\n", self.content_type));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if close {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        [head.into_bytes(), self.body.clone()].concat()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Error response for the connection which is closed without serving it
+pub(crate) fn error_reply(e: KVSError) -> Vec<u8> {
+    Response::error(&e).to_bytes(true)
+}
+
+/// Serve HTTP requests of the connection until client closes it, asks to close it
+/// or it is idle for the idle timeout. Malformed request or request with too
+/// large body gets an error and the connection is closed
+pub(crate) fn handle_connection<S: KvsEngine>(
+    store: S,
+    mut stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    let limits = &config.limits;
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let head = match idle_as_closed(read_head(&mut reader, limits.max_frame_size)) {
+            Ok(Some(head)) => head,
+            Ok(None) => break,
+            Err(e @ (KVSError::TooLargeError { .. } | KVSError::ProtocolError { .. })) => {
+                log::warn!("Rejecting HTTP request: {}", e);
+                reject(
+                    &mut reader,
+                    &mut stream,
+                    &Response::error(&e).to_bytes(true),
+                )?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(response) = check_body(&head, limits) {
+            // body is not read, so no other request can follow
+            reject(&mut reader, &mut stream, &response.to_bytes(true))?;
+            break;
+        }
+        if head
+            .header("expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+        {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        // buffer grows with received bytes, not with the declared length
+        let mut body = Vec::new();
+        (&mut reader)
+            .take(head.content_length as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < head.content_length {
+            return Err(KVSError::protocol("connection closed in request body"));
+        }
+        log::debug!("HTTP request - {} {}", head.method, head.target);
+
+        let keep_alive = head.keep_alive;
+        let response = {
+            let (store, limits) = (store.clone(), *limits);
+            workers.run(move || route(&store, &limits, &head, body))?
+        };
+        log::debug!("HTTP result - {}", response.status);
+        stream.write_all(&response.to_bytes(!keep_alive))?;
+        stream.flush()?;
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read request line and headers, `None` when the client closed the connection
+fn read_head<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Head>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => utf8(line)?,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+            (method, target, version)
+        }
+        _ => return Err(KVSError::protocol("malformed request line")),
+    };
+    if !matches!(version, "HTTP/1.1" | "HTTP/1.0") {
+        return Err(KVSError::protocol(format!("unsupported {}", version)));
+    }
+
+    let mut size = line.len();
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, max_size)
+            .and_then(|line| line.ok_or_else(|| KVSError::protocol("connection closed")))?;
+        if line.is_empty() {
+            break;
+        }
+        size += line.len();
+        if size > max_size || headers.len() == MAX_HEADERS {
+            return Err(KVSError::too_large(
+                "request head",
+                size as u64,
+                max_size as u64,
+            ));
+        }
+        let line = utf8(line)?;
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| KVSError::protocol("malformed header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+
+    let mut head = Head {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        headers,
+        content_length: 0,
+        keep_alive: version == "HTTP/1.1",
+    };
+    if let Some(length) = head.header("content-length") {
+        head.content_length = length
+            .parse()
+            .map_err(|_| KVSError::protocol("invalid Content-Length"))?;
+    }
+    match head.header("connection").map(str::to_ascii_lowercase) {
+        Some(connection) if connection == "close" => head.keep_alive = false,
+        Some(connection) if connection == "keep-alive" => head.keep_alive = true,
+        _ => {}
+    }
+    Ok(Some(head))
+}
+
+fn utf8(line: Vec<u8>) -> Result<String> {
+    String::from_utf8(line).map_err(|_| KVSError::protocol("request head is not UTF-8"))
+}
+
+/// Response rejecting the body before it is read, `None` when it can be read
+fn check_body(head: &Head, limits: &FrameLimits) -> Option<Response> {
+    if head.header("transfer-encoding").is_some() {
+        return Some(Response::invalid(
+            411,
+            "Chunked body is not supported, send Content-Length",
+        ));
+    }
+    let res = if head.is_value_upload() {
+        limits.check_value(head.content_length)
+    } else if head.content_length > limits.max_frame_size {
+        Err(KVSError::too_large(
+            "body",
+            head.content_length as u64,
+            limits.max_frame_size as u64,
+        ))
+    } else {
+        Ok(())
+    };
+    res.err().map(|e| Response::error(&e))
+}
+
+/// Run the request on the store
+fn route<S: KvsEngine>(store: &S, limits: &FrameLimits, head: &Head, body: Vec<u8>) -> Response {
+    let (path, query) = head
+        .target
+        .split_once('?')
+        .unwrap_or((head.target.as_str(), ""));
+    let query = match parse_query(query) {
+        Ok(query) => query,
+        Err(e) => return Response::error(&e),
+    };
+    let method = head.method.as_str();
+    let res = match path {
+        "/keys" => match method {
+            "GET" => list(store, limits, &query),
+            _ => return method_not_allowed("GET"),
+        },
+        "/batch" => match method {
+            "POST" => batch(store, limits, &query, &body),
+            _ => return method_not_allowed("POST"),
+        },
+        path => match path.strip_prefix("/keys/").map(percent_decode) {
+            Some(Ok(key)) if !key.is_empty() => match method {
+                "GET" => get(store, limits, key),
+                "PUT" => put(store, limits, head, &query, key, body),
+                "DELETE" => delete(store, limits, key),
+                _ => return method_not_allowed("GET, PUT, DELETE"),
+            },
+            Some(Err(e)) => Err(e),
+            _ => {
+                let message = format!("No resource at {}", path);
+                return Response::invalid(404, message);
+            }
+        },
+    };
+    res.unwrap_or_else(|e| Response::error(&e))
+}
+
+fn method_not_allowed(allowed: &str) -> Response {
+    Response::invalid(405, format!("Method must be one of: {}", allowed))
+        .with_header("Allow", allowed.to_owned())
+}
+
+/// Value as is with its version as ETag
+fn get<S: KvsEngine>(store: &S, limits: &FrameLimits, key: Vec<u8>) -> Result<Response> {
+    limits.check_key(&key)?;
+    match store.get_versioned(key)? {
+        Some((value, version)) => {
+            Ok(Response::bytes(value).with_header("ETag", format!("\"{}\"", version)))
+        }
+        None => Err(KVSError::KeyNotFoundError),
+    }
+}
+
+/// Set body as the value, expiring after `ttl` of the query when given.
+/// `If-None-Match: *` sets only a missing key, `If-Match` with ETag
+/// of `get` only the value of that version
+fn put<S: KvsEngine>(
+    store: &S,
+    limits: &FrameLimits,
+    head: &Head,
+    query: &[(String, Vec<u8>)],
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<Response> {
+    limits.check_key(&key)?;
+    let ttl = match param(query, "ttl") {
+        Some(ttl) => Some(parse_ttl(&String::from_utf8_lossy(ttl)).map_err(KVSError::protocol)?),
+        None => None,
+    };
+    let version = match (head.header("if-none-match"), head.header("if-match")) {
+        (Some("*"), None) => Some(set_if(store, key, value, ttl, None)?),
+        (None, Some(etag)) => {
+            let version = etag
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .parse()
+                .map_err(|_| KVSError::protocol(format!("invalid ETag {}", etag)))?;
+            Some(set_if(store, key, value, ttl, Some(version))?)
+        }
+        (None, None) => {
+            match ttl {
+                Some(ttl) => store.set_with_ttl(key, value, ttl)?,
+                None => store.set(key, value)?,
+            }
+            None
+        }
+        _ => {
+            let message = "Only If-None-Match: * or If-Match with ETag are supported";
+            return Err(KVSError::protocol(message));
+        }
+    };
+    let response = Response::no_content();
+    Ok(match version {
+        Some(version) => response.with_header("ETag", format!("\"{}\"", version)),
+        None => response,
+    })
+}
+
+/// Set when the key is missing for `None` version, or has the version
+fn set_if<S: KvsEngine>(
+    store: &S,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    ttl: Option<Duration>,
+    version: Option<u64>,
+) -> Result<u64> {
+    match (version, ttl) {
+        (None, None) => store.set_if_absent(key, value),
+        (None, Some(ttl)) => store.set_if_absent_with_ttl(key, value, ttl),
+        (Some(version), None) => store.set_if_version(key, value, version),
+        (Some(version), Some(ttl)) => store.set_if_version_with_ttl(key, value, version, ttl),
+    }
+}
+
+fn delete<S: KvsEngine>(store: &S, limits: &FrameLimits, key: Vec<u8>) -> Result<Response> {
+    limits.check_key(&key)?;
+    store.remove(key)?;
+    Ok(Response::no_content())
+}
+
+/// Entries with keys starting with `prefix` ordered by key, at most `limit` of them
+/// and never more than `MAX_LIST_LIMIT`. Keys and values are written in `encoding` of the query
+fn list<S: KvsEngine>(
+    store: &S,
+    limits: &FrameLimits,
+    query: &[(String, Vec<u8>)],
+) -> Result<Response> {
+    let prefix = param(query, "prefix").unwrap_or_default().to_vec();
+    limits.check_key(&prefix)?;
+    let limit = match param(query, "limit") {
+        Some(limit) => String::from_utf8_lossy(limit)
+            .parse()
+            .map_err(|_| KVSError::protocol("limit must be a number"))?,
+        None => DEFAULT_LIST_LIMIT,
+    };
+    let limit = limit.min(MAX_LIST_LIMIT);
+    let encoding = encoding(query)?;
+    let entries: Vec<_> = store
+        .scan_prefix(prefix, limit)?
+        .iter()
+        .map(|(key, value)| json!({"key": encoding.encode(key), "value": encoding.encode(value)}))
+        .collect();
+    Ok(Response::json(200, json!({ "entries": entries })))
+}
+
+/// Body of `POST /batch`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchRequest {
+    ops: Vec<BatchRequestOp>,
+}
+
+/// Write of the batch, applied only when the key still has `version` if given
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum BatchRequestOp {
+    Put {
+        key: String,
+        value: String,
+        version: Option<u64>,
+    },
+    Delete {
+        key: String,
+        version: Option<u64>,
+    },
+}
+
+/// Apply puts and deletes of the JSON body atomically. When some of them
+/// have a version, nothing is applied unless all those keys still have it
+fn batch<S: KvsEngine>(
+    store: &S,
+    limits: &FrameLimits,
+    query: &[(String, Vec<u8>)],
+    body: &[u8],
+) -> Result<Response> {
+    let request: BatchRequest = serde_json::from_slice(body)
+        .map_err(|e| KVSError::protocol(format!("invalid batch: {}", e)))?;
+    let encoding = encoding(query)?;
+    let decode = |text: &str| encoding.decode(text).map_err(KVSError::protocol);
+    let mut writes = WriteBatch::new();
+    let mut reads = Vec::new();
+    for op in request.ops {
+        let (key, version) = match op {
+            BatchRequestOp::Put {
+                key,
+                value,
+                version,
+            } => {
+                let (key, value) = (decode(&key)?, decode(&value)?);
+                limits.check_key(&key)?;
+                limits.check_value(value.len())?;
+                writes.put(key.clone(), value);
+                (key, version)
+            }
+            BatchRequestOp::Delete { key, version } => {
+                let key = decode(&key)?;
+                limits.check_key(&key)?;
+                writes.delete(key.clone());
+                (key, version)
+            }
+        };
+        if let Some(version) = version {
+            reads.push((key, Some(version)));
+        }
+    }
+    if reads.is_empty() {
+        store.apply_batch(writes)?;
+    } else {
+        store.commit_transaction(reads, writes)?;
+    }
+    Ok(Response::no_content())
+}
+
+fn encoding(query: &[(String, Vec<u8>)]) -> Result<Encoding> {
+    match param(query, "encoding") {
+        Some(encoding) => String::from_utf8_lossy(encoding)
+            .parse()
+            .map_err(KVSError::protocol),
+        None => Ok(Encoding::Utf8),
+    }
+}
+
+fn param<'a>(query: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    query
+        .iter()
+        .find(|(param, _)| param == name)
+        .map(|(_, value)| value.as_slice())
+}
+
+/// Names and decoded values of the query string
+fn parse_query(query: &str) -> Result<Vec<(String, Vec<u8>)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(&value.replace('+', " "))?;
+            Ok((String::from_utf8(percent_decode(name)?)?, value))
+        })
+        .collect()
+}
+
+/// Bytes of the text with `%XX` escapes
+fn percent_decode(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+        let escaped = tail
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| KVSError::protocol(format!("invalid escape in {}", text)))?;
+        bytes.push(escaped);
+        rest = &tail[2..];
+    }
+    Ok(bytes)
+}
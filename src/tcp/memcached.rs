@@ -0,0 +1,584 @@
+// #![deny(missing_docs)]
+//! Front end speaking the text protocol of memcached, so its clients
+//! can use the server. Commands are mapped onto `KvsEngine`,
+//! flags of items are not stored and are read as 0
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::batch::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::error::{KVSError, Result};
+use crate::tcp::protocol::FrameLimits;
+use crate::tcp::server::{idle_as_closed, read_line, reject, ServerConfig, Workers};
+
+/// Expiration times and flush_all delays longer than this are unix timestamps, as in memcached
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// Keys removed by one batch of flush_all
+const FLUSH_BATCH: usize = 1000;
+
+/// Counters of all memcached connections, reported by stats,
+/// and delayed flush_all
+pub struct MemcachedState {
+    started: Instant,
+    connected: AtomicU64,
+    connections: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// flush_all removes keys of all front ends, so it is off unless allowed
+    flush_allowed: bool,
+    pending_flush: Arc<PendingFlush>,
+}
+
+impl MemcachedState {
+    /// State of the listener, flush_all is refused unless `flush_allowed`
+    pub fn new(flush_allowed: bool) -> Self {
+        MemcachedState {
+            started: Instant::now(),
+            connected: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            sets: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            flush_allowed,
+            pending_flush: Arc::new(PendingFlush {
+                deadline: Mutex::new(None),
+                changed: Condvar::new(),
+                waiter: Once::new(),
+                stopped: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+impl Drop for MemcachedState {
+    fn drop(&mut self) {
+        self.pending_flush.stop();
+    }
+}
+
+/// Deadline of delayed flush_all, as in memcached later flush_all replaces it.
+/// One thread, started by the first delayed flush_all, waits for it
+/// until the state of the listener is dropped
+struct PendingFlush {
+    deadline: Mutex<Option<Instant>>,
+    changed: Condvar,
+    waiter: Once,
+    /// Ends the waiter, so it releases its handle of the store
+    stopped: AtomicBool,
+}
+
+impl PendingFlush {
+    /// Flush the store at `deadline`, `None` cancels the pending flush
+    fn schedule<S: KvsEngine>(self: &Arc<Self>, store: &S, deadline: Option<Instant>) {
+        *self.deadline.lock().unwrap() = deadline;
+        self.changed.notify_one();
+        if deadline.is_some() {
+            self.waiter.call_once(|| {
+                let (pending, store) = (Arc::clone(self), store.clone());
+                thread::spawn(move || pending.wait_and_flush(&store));
+            });
+        }
+    }
+
+    /// End the waiter, pending flush is dropped
+    fn stop(&self) {
+        // flag is set under the lock, so the waiter can't miss the wakeup
+        let _deadline = self.deadline.lock().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    /// Flush the store whenever the deadline passes, until stopped
+    fn wait_and_flush<S: KvsEngine>(&self, store: &S) {
+        let mut deadline = self.deadline.lock().unwrap();
+        while !self.stopped.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            deadline = match *deadline {
+                None => self.changed.wait(deadline).unwrap(),
+                Some(at) if at > now => self.changed.wait_timeout(deadline, at - now).unwrap().0,
+                Some(_) => {
+                    *deadline = None;
+                    drop(deadline);
+                    if let Err(e) = flush(store) {
+                        log::error!("Error flushing items: {}", e);
+                    }
+                    self.deadline.lock().unwrap()
+                }
+            };
+        }
+    }
+}
+
+/// How storage command treats the current item
+#[derive(Debug, Clone, Copy)]
+enum StoreMode {
+    Set,
+    /// Only when the key is missing
+    Add,
+    /// Only when the key exists
+    Replace,
+    /// Only when the item has the version from `gets`
+    Cas(u64),
+}
+
+/// Parsed command line
+#[derive(Debug)]
+enum Command {
+    Get {
+        keys: Vec<Vec<u8>>,
+        with_cas: bool,
+    },
+    /// Data block of `len` bytes follows the command line
+    Store {
+        mode: StoreMode,
+        key: Vec<u8>,
+        ttl: Option<Duration>,
+        len: usize,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Counter {
+        key: Vec<u8>,
+        delta: u64,
+        decrement: bool,
+    },
+    FlushAll {
+        delay: Duration,
+    },
+    Stats,
+}
+
+/// Serve memcached commands of the connection until client closes it
+/// or it is idle for the idle timeout. Too long line or data block
+/// gets an error and the connection is closed
+pub(crate) fn handle_connection<S: KvsEngine>(
+    store: S,
+    state: Arc<MemcachedState>,
+    stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    state.connected.fetch_add(1, Ordering::SeqCst);
+    state.connections.fetch_add(1, Ordering::SeqCst);
+    let res = serve(&store, &state, stream, workers, config);
+    state.connected.fetch_sub(1, Ordering::SeqCst);
+    res
+}
+
+/// Error reply for the connection which is closed without serving it
+pub(crate) fn error_reply(e: KVSError) -> Vec<u8> {
+    server_error(e)
+}
+
+fn serve<S: KvsEngine>(
+    store: &S,
+    state: &Arc<MemcachedState>,
+    mut stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    let limits = &config.limits;
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    loop {
+        let line = match idle_as_closed(read_line(&mut reader, limits.max_frame_size)) {
+            Ok(Some(line)) => line,
+            Ok(None) | Err(KVSError::ProtocolError { .. }) => break,
+            Err(e @ KVSError::TooLargeError { .. }) => {
+                log::warn!("Rejecting memcached command: {}", e);
+                writer.flush()?;
+                reject(&mut reader, &mut stream, b"CLIENT_ERROR line too long\r\n")?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let words: Vec<&[u8]> = line
+            .split(|byte| *byte == b' ')
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.is_empty() {
+            continue;
+        }
+        let noreply = words.len() > 1 && words[words.len() - 1] == b"noreply";
+        let words = if noreply {
+            &words[..words.len() - 1]
+        } else {
+            &words[..]
+        };
+
+        let reply = match parse(words, limits) {
+            Err(reply) => reply.into_bytes(),
+            Ok(Command::Store {
+                mode,
+                key,
+                ttl,
+                len,
+            }) => {
+                if let Err(e) = limits
+                    .check_value(len)
+                    .and_then(|()| check_data(len, limits.max_frame_size))
+                {
+                    log::warn!("Rejecting memcached value: {}", e);
+                    writer.flush()?;
+                    let reply = b"SERVER_ERROR object too large for cache\r\n";
+                    reject(&mut reader, &mut stream, reply)?;
+                    break;
+                }
+                let mut data = vec![0; len + 2];
+                reader.read_exact(&mut data)?;
+                if !data.ends_with(b"\r\n") {
+                    writer.flush()?;
+                    reject(&mut reader, &mut stream, b"CLIENT_ERROR bad data chunk\r\n")?;
+                    break;
+                }
+                data.truncate(len);
+                state.sets.fetch_add(1, Ordering::SeqCst);
+                let store = store.clone();
+                workers.run(move || {
+                    store_value(&store, mode, key, data, ttl).unwrap_or_else(server_error)
+                })?
+            }
+            Ok(cmd) => {
+                log::debug!("Memcached command - {:?}", cmd);
+                let (store, state) = (store.clone(), Arc::clone(state));
+                workers.run(move || invoke(&store, &state, cmd).unwrap_or_else(server_error))?
+            }
+        };
+        if !noreply {
+            writer.write_all(&reply)?;
+        }
+        // pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn check_data(len: usize, max_size: usize) -> Result<()> {
+    if len > max_size {
+        return Err(KVSError::too_large(
+            "data block",
+            len as u64,
+            max_size as u64,
+        ));
+    }
+    Ok(())
+}
+
+fn server_error(e: KVSError) -> Vec<u8> {
+    format!("SERVER_ERROR {}\r\n", e).into_bytes()
+}
+
+/// Parse the command line without `noreply`, error is the reply line
+fn parse(words: &[&[u8]], limits: &FrameLimits) -> std::result::Result<Command, String> {
+    let bad_format = || "CLIENT_ERROR bad command line format\r\n".to_owned();
+    let key = |word: &[u8]| match limits.check_key(word) {
+        Ok(()) => Ok(word.to_vec()),
+        Err(e) => Err(format!("CLIENT_ERROR {}\r\n", e)),
+    };
+    let number = |word: &[u8]| {
+        std::str::from_utf8(word)
+            .ok()
+            .and_then(|word| word.parse::<u64>().ok())
+            .ok_or_else(bad_format)
+    };
+    let cmd = match (words[0], &words[1..]) {
+        (b"get" | b"gets", keys) if !keys.is_empty() => Command::Get {
+            keys: keys
+                .iter()
+                .map(|k| key(k))
+                .collect::<std::result::Result<_, _>>()?,
+            with_cas: words[0] == b"gets",
+        },
+        (b"set" | b"add" | b"replace", [k, flags, exptime, len])
+        | (b"cas", [k, flags, exptime, len, _]) => {
+            number(flags)?;
+            let mode = match words[0] {
+                b"set" => StoreMode::Set,
+                b"add" => StoreMode::Add,
+                b"replace" => StoreMode::Replace,
+                _ => StoreMode::Cas(number(words[5])?),
+            };
+            Command::Store {
+                mode,
+                key: key(k)?,
+                ttl: parse_exptime(exptime).ok_or_else(bad_format)?,
+                len: usize::try_from(number(len)?).map_err(|_| bad_format())?,
+            }
+        }
+        (b"delete", [k]) => Command::Delete { key: key(k)? },
+        (b"incr" | b"decr", [k, delta]) => Command::Counter {
+            key: key(k)?,
+            delta: number(delta)
+                .map_err(|_| "CLIENT_ERROR invalid numeric delta argument\r\n".to_owned())?,
+            decrement: words[0] == b"decr",
+        },
+        (b"flush_all", []) => Command::FlushAll {
+            delay: Duration::ZERO,
+        },
+        (b"flush_all", [delay]) => Command::FlushAll {
+            delay: flush_delay(number(delay)?),
+        },
+        (b"stats", _) => Command::Stats,
+        (b"get" | b"gets" | b"set" | b"add" | b"replace" | b"cas" | b"delete", _)
+        | (b"incr" | b"decr" | b"flush_all", _) => return Err(bad_format()),
+        _ => return Err("ERROR\r\n".to_owned()),
+    };
+    Ok(cmd)
+}
+
+/// Delay of flush_all, which is a unix timestamp when longer than 30 days
+/// like exptime. Past time flushes at once
+fn flush_delay(delay: u64) -> Duration {
+    let delay = Duration::from_secs(delay);
+    if delay.as_secs() <= MAX_RELATIVE_EXPTIME as u64 {
+        return delay;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    delay.saturating_sub(now)
+}
+
+/// Lifetime of the item by exptime of memcached: 0 never expires,
+/// up to 30 days it is relative, later a unix timestamp.
+/// Negative or past time expires the item at once
+fn parse_exptime(word: &[u8]) -> Option<Option<Duration>> {
+    let exptime: i64 = std::str::from_utf8(word).ok()?.parse().ok()?;
+    let ttl = match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(Duration::ZERO),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        timestamp => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+            Some(Duration::from_secs(timestamp as u64).saturating_sub(now))
+        }
+    };
+    Some(ttl)
+}
+
+/// Run storage command with its data block, reply is the result line
+fn store_value<S: KvsEngine>(
+    store: &S,
+    mode: StoreMode,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    ttl: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let reply: &[u8] = match mode {
+        StoreMode::Set => {
+            match ttl {
+                Some(ttl) => store.set_with_ttl(key, value, ttl)?,
+                None => store.set(key, value)?,
+            }
+            b"STORED\r\n"
+        }
+        StoreMode::Add => {
+            let res = match ttl {
+                Some(ttl) => store.set_if_absent_with_ttl(key, value, ttl),
+                None => store.set_if_absent(key, value),
+            };
+            if precondition(res)? {
+                b"STORED\r\n"
+            } else {
+                b"NOT_STORED\r\n"
+            }
+        }
+        StoreMode::Replace => loop {
+            let version = match store.get_versioned(key.clone())? {
+                Some((_, version)) => version,
+                None => break b"NOT_STORED\r\n",
+            };
+            // the key was changed since it was read, check again
+            if precondition(set_version(store, &key, &value, version, ttl))? {
+                break b"STORED\r\n";
+            }
+        },
+        StoreMode::Cas(version) => {
+            if precondition(set_version(store, &key, &value, version, ttl))? {
+                b"STORED\r\n"
+            } else if store.get_versioned(key)?.is_some() {
+                b"EXISTS\r\n"
+            } else {
+                b"NOT_FOUND\r\n"
+            }
+        }
+    };
+    Ok(reply.to_vec())
+}
+
+fn set_version<S: KvsEngine>(
+    store: &S,
+    key: &[u8],
+    value: &[u8],
+    version: u64,
+    ttl: Option<Duration>,
+) -> Result<u64> {
+    let (key, value) = (key.to_vec(), value.to_vec());
+    match ttl {
+        Some(ttl) => store.set_if_version_with_ttl(key, value, version, ttl),
+        None => store.set_if_version(key, value, version),
+    }
+}
+
+/// Conditional write happened, failed condition is `false`
+fn precondition(res: Result<u64>) -> Result<bool> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(KVSError::PreconditionFailedError) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Run command without data block, reply is the result lines
+fn invoke<S: KvsEngine>(store: &S, state: &MemcachedState, cmd: Command) -> Result<Vec<u8>> {
+    let reply = match cmd {
+        Command::Get { keys, with_cas } => {
+            let mut reply = Vec::new();
+            for key in keys {
+                state.gets.fetch_add(1, Ordering::SeqCst);
+                let (value, version) = match store.get_versioned(key.clone())? {
+                    Some(item) => item,
+                    None => {
+                        state.misses.fetch_add(1, Ordering::SeqCst);
+                        continue;
+                    }
+                };
+                state.hits.fetch_add(1, Ordering::SeqCst);
+                reply.extend_from_slice(b"VALUE ");
+                reply.extend_from_slice(&key);
+                let header = if with_cas {
+                    format!(" 0 {} {}\r\n", value.len(), version)
+                } else {
+                    format!(" 0 {}\r\n", value.len())
+                };
+                reply.extend_from_slice(header.as_bytes());
+                reply.extend_from_slice(&value);
+                reply.extend_from_slice(b"\r\n");
+            }
+            reply.extend_from_slice(b"END\r\n");
+            return Ok(reply);
+        }
+        Command::Delete { key } => match store.remove(key) {
+            Ok(()) => "DELETED\r\n".to_owned(),
+            Err(KVSError::KeyNotFoundError) => "NOT_FOUND\r\n".to_owned(),
+            Err(e) => return Err(e),
+        },
+        Command::Counter {
+            key,
+            delta,
+            decrement,
+        } => count(store, key, delta, decrement)?,
+        Command::FlushAll { .. } if !state.flush_allowed => {
+            "CLIENT_ERROR flush_all not allowed\r\n".to_owned()
+        }
+        Command::FlushAll { delay } if delay.is_zero() => {
+            state.pending_flush.schedule(store, None);
+            flush(store)?;
+            "OK\r\n".to_owned()
+        }
+        Command::FlushAll { delay } => {
+            // deadline past the range of `Instant` never comes
+            let deadline = Instant::now().checked_add(delay);
+            state.pending_flush.schedule(store, deadline);
+            "OK\r\n".to_owned()
+        }
+        Command::Stats => stats(state),
+        Command::Store { .. } => unreachable!("storage command needs its data block"),
+    };
+    Ok(reply.into_bytes())
+}
+
+/// Add delta to the value as decimal u64, wrapping on overflow,
+/// or subtract it down to 0. TTL of the item is kept
+fn count<S: KvsEngine>(store: &S, key: Vec<u8>, delta: u64, decrement: bool) -> Result<String> {
+    loop {
+        let (value, version) = match store.get_versioned(key.clone())? {
+            Some(item) => item,
+            None => return Ok("NOT_FOUND\r\n".to_owned()),
+        };
+        let current = match std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+        {
+            Some(current) => current,
+            None => {
+                let message = "cannot increment or decrement non-numeric value";
+                return Ok(format!("CLIENT_ERROR {}\r\n", message));
+            }
+        };
+        let counter = if decrement {
+            current.saturating_sub(delta)
+        } else {
+            current.wrapping_add(delta)
+        };
+        let ttl = match store.ttl(key.clone()) {
+            Ok(ttl) => ttl,
+            Err(KVSError::KeyNotFoundError) => continue,
+            Err(e) => return Err(e),
+        };
+        let value = counter.to_string().into_bytes();
+        // the key was changed since it was read, count again
+        if precondition(set_version(store, &key, &value, version, ttl))? {
+            return Ok(format!("{}\r\n", counter));
+        }
+    }
+}
+
+/// Remove all keys in batches, keys written meanwhile may be kept
+fn flush<S: KvsEngine>(store: &S) -> Result<()> {
+    let mut start = Vec::new();
+    loop {
+        let entries = store.scan(start, None, FLUSH_BATCH)?;
+        let mut batch = WriteBatch::new();
+        for (key, _) in &entries {
+            batch.delete(key.to_owned());
+        }
+        let done = entries.len() < FLUSH_BATCH;
+        start = match entries.last() {
+            Some((key, _)) => [key.as_slice(), &[0]].concat(),
+            None => return Ok(()),
+        };
+        store.apply_batch(batch)?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// General statistics of memcached
+fn stats(state: &MemcachedState) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let load = |counter: &AtomicU64| counter.load(Ordering::SeqCst).to_string();
+    let stats = [
+        ("pid", std::process::id().to_string()),
+        ("uptime", state.started.elapsed().as_secs().to_string()),
+        ("time", time.to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_owned()),
+        ("curr_connections", load(&state.connected)),
+        ("total_connections", load(&state.connections)),
+        ("cmd_get", load(&state.gets)),
+        ("cmd_set", load(&state.sets)),
+        ("get_hits", load(&state.hits)),
+        ("get_misses", load(&state.misses)),
+    ];
+    let mut reply: String = stats
+        .iter()
+        .map(|(name, value)| format!("STAT {} {}\r\n", name, value))
+        .collect();
+    reply.push_str("END\r\n");
+    reply
+}
@@ -1,240 +1,1112 @@
-use clap::Subcommand;
 use crc16::{State, ARC};
 use serde::{Deserialize, Serialize};
-use std::io::Read;
-use std::net::TcpStream;
+use std::io::{self, Read};
+use std::ops::BitOr;
+use std::time::Duration;
 
+use crate::batch::{BatchOp, WriteBatch};
 use crate::engine::KvsEngine;
-use crate::error::{KVSError, Result};
+use crate::error::{ErrorCode, KVSError, Result};
 
 const CMD_HEAD: &[u8] = &[27, 59];
 const LEN_SIZE: usize = 4;
-/// Type of integer length of key, value (for DBCommands)
-/// or output, message (for ServerResponse)
+/// Type of integer length of fields of the packet
+/// (key, value for DBCommands or output, message for ServerResponse)
 pub type CommandLenType = u32;
+/// Id of the request, echoed in its response so pipelined
+/// responses can be matched with their commands
+pub type RequestId = u64;
+
+/// Largest value chunk of streamed set and get, smaller when the frame
+/// limit is lower, see `FrameLimits::chunk_size`
+pub const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+/// Bytes of `Chunk` packet besides the data: head, type, id,
+/// field count, field length and checksum
+const CHUNK_PACKET_OVERHEAD: usize = CMD_HEAD.len() + 1 + 8 + LEN_SIZE + LEN_SIZE + 2;
+
+/// Largest sizes the server accepts, packets are checked before their fields
+/// are allocated. Values over the frame size are sent with streamed set and get
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// Whole packet, including all its fields
+    pub max_frame_size: usize,
+}
+
+impl FrameLimits {
+    /// No limits, used to read packets of a trusted peer
+    pub const UNLIMITED: FrameLimits = FrameLimits {
+        max_key_size: usize::MAX,
+        max_value_size: usize::MAX,
+        max_frame_size: usize::MAX,
+    };
+
+    /// Key is not larger than the limit
+    pub fn check_key(&self, key: &[u8]) -> Result<()> {
+        check_size("key", key.len(), self.max_key_size)
+    }
+
+    /// Value of `len` bytes is not larger than the limit
+    pub fn check_value(&self, len: usize) -> Result<()> {
+        check_size("value", len, self.max_value_size)
+    }
+
+    /// Size of value chunks whose `Chunk` packets fit the frame limit
+    pub fn chunk_size(&self) -> usize {
+        self.max_frame_size
+            .saturating_sub(CHUNK_PACKET_OVERHEAD)
+            .clamp(1, STREAM_CHUNK_SIZE)
+    }
+
+    /// Check sizes of keys and values of the command
+    pub fn check(&self, cmd: &DBCommands) -> Result<()> {
+        let key = |key: &Vec<u8>| self.check_key(key);
+        let value = |len: usize| self.check_value(len);
+        match cmd {
+            DBCommands::Set {
+                key: k, value: v, ..
+            }
+            | DBCommands::SetIfAbsent { key: k, value: v }
+            | DBCommands::SetIfVersion {
+                key: k, value: v, ..
+            } => {
+                key(k)?;
+                value(v.len())
+            }
+            DBCommands::SetStream { key: k, len, .. } => {
+                key(k)?;
+                value(usize::try_from(*len).unwrap_or(usize::MAX))
+            }
+            DBCommands::Get { key: k }
+            | DBCommands::Rm { key: k }
+            | DBCommands::Ttl { key: k }
+            | DBCommands::GetVersioned { key: k }
+            | DBCommands::Incr { key: k, .. }
+            | DBCommands::SnapshotGet { key: k, .. }
+            | DBCommands::GetStream { key: k }
+            | DBCommands::ScanPrefix { prefix: k, .. }
+            | DBCommands::SnapshotScanPrefix { prefix: k, .. } => key(k),
+            DBCommands::Scan { start, end, .. } | DBCommands::SnapshotScan { start, end, .. } => {
+                key(start)?;
+                end.iter().try_for_each(key)
+            }
+            DBCommands::Cas {
+                key: k,
+                expected,
+                new,
+            } => {
+                key(k)?;
+                expected.iter().chain(new).try_for_each(|v| value(v.len()))
+            }
+            DBCommands::Batch { batch } => batch.ops().iter().try_for_each(|op| match op {
+                BatchOp::Put { key: k, value: v } => {
+                    key(k)?;
+                    value(v.len())
+                }
+                BatchOp::Delete { key: k } => key(k),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_size(what: &str, size: usize, limit: usize) -> Result<()> {
+    if size > limit {
+        return Err(KVSError::too_large(what, size as u64, limit as u64));
+    }
+    Ok(())
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_key_size: 64 * 1024,
+            max_value_size: 512 * 1024 * 1024,
+            max_frame_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Version of the protocol spoken by this crate, sent in HELLO.
+/// Version 1 had request ids but no handshake
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest version the server still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional features of the protocol, client asks for some in HELLO
+/// and the server grants those it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Keys and values are arbitrary bytes, not only UTF-8
+    pub const BINARY_VALUES: Capabilities = Capabilities(1);
+    /// Requests may be sent without waiting for responses
+    pub const PIPELINING: Capabilities = Capabilities(1 << 1);
+    // 1 << 2 is reserved for compression of values on the wire
+    /// Range and prefix scans
+    pub const SCANS: Capabilities = Capabilities(1 << 3);
+    /// Values may be set and got in chunks
+    pub const STREAMING: Capabilities = Capabilities(1 << 4);
+    /// Capabilities implemented by this crate
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Self::BINARY_VALUES.0 | Self::PIPELINING.0 | Self::SCANS.0 | Self::STREAMING.0,
+    );
+
+    /// Flags as sent over the protocol
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Capabilities by flags, unknown flags of newer peers are kept
+    pub fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    /// All capabilities of `other` are in self
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Capabilities in both self and `other`
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
 
 fn check_head(head: &[u8]) -> Result<()> {
     if CMD_HEAD[0] != head[0] || CMD_HEAD[1] != head[1] {
         log::error!("Head not matched: {:?}, received {:?}", CMD_HEAD, head);
-        return Err(KVSError::GeneralKVSError);
+        return Err(KVSError::protocol("head of packet not matched"));
     }
     Ok(())
 }
-/// Enumeration to define a commands to KVS engine
-#[derive(Debug, Serialize, Deserialize, Subcommand)]
+
+/// Pack packet: HEAD, type byte, request id, number of fields,
+/// length-prefixed fields and CRC-ARC hashsum of all of it
+fn pack(kind: u8, id: RequestId, fields: Vec<Vec<u8>>) -> Vec<u8> {
+    let packet = [CMD_HEAD.to_vec(), vec![kind], id.to_be_bytes().to_vec()].concat();
+    pack_fields(packet, fields)
+}
+
+/// Pack packet of the protocol before version 1, which has no request id
+fn pack_unnumbered(kind: u8, fields: Vec<Vec<u8>>) -> Vec<u8> {
+    pack_fields([CMD_HEAD.to_vec(), vec![kind]].concat(), fields)
+}
+
+/// Append fields and hashsum to the start of packet
+fn pack_fields(mut packet: Vec<u8>, fields: Vec<Vec<u8>>) -> Vec<u8> {
+    packet.extend_from_slice(&(fields.len() as CommandLenType).to_be_bytes());
+    for field in fields {
+        packet.extend_from_slice(&(field.len() as CommandLenType).to_be_bytes());
+        packet.extend(field);
+    }
+    let checksum = State::<ARC>::calculate(&packet).to_be_bytes();
+    [packet, checksum.to_vec()].concat()
+}
+
+/// Unpack packet from stream, check HEAD and CRC-ARC.
+/// Packet larger than `max_size` is rejected before its fields are read.
+/// Return type byte, request id and fields
+fn unpack<R: Read>(stream: &mut R, max_size: usize) -> Result<(u8, RequestId, Vec<Vec<u8>>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    check_head(&head)?;
+
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind)?;
+    let mut id = [0u8; 8];
+    stream.read_exact(&mut id)?;
+    let count = read_len(stream)?;
+
+    let mut packet = [CMD_HEAD.to_vec(), kind.to_vec(), id.to_vec()].concat();
+    packet.extend_from_slice(&(count as CommandLenType).to_be_bytes());
+    let mut fields = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let len = read_len(stream)?;
+        // rest of the packet is at least the length of the field and checksum
+        let size = (packet.len() + LEN_SIZE + 2).saturating_add(len);
+        if size > max_size {
+            return Err(KVSError::too_large("packet", size as u64, max_size as u64));
+        }
+        let mut field = vec![0u8; len];
+        stream.read_exact(&mut field)?;
+        packet.extend_from_slice(&(len as CommandLenType).to_be_bytes());
+        packet.extend_from_slice(&field);
+        fields.push(field);
+    }
+
+    // check hashsum of the data
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum)?;
+    let calculated = State::<ARC>::calculate(&packet).to_be_bytes();
+    if calculated != checksum {
+        log::error!(
+            "Checksum of packet not matched, must be {:?}, received {:?}",
+            calculated,
+            checksum
+        );
+        return Err(KVSError::protocol("checksum of packet not matched"));
+    }
+    Ok((kind[0], RequestId::from_be_bytes(id), fields))
+}
+
+fn read_len<R: Read>(stream: &mut R) -> Result<usize> {
+    let mut len_coded = [0u8; LEN_SIZE];
+    stream.read_exact(&mut len_coded)?;
+    Ok(CommandLenType::from_be_bytes(len_coded) as usize)
+}
+
+/// First packet of the connection
+pub(crate) enum Opening {
+    /// Handshake of the client
+    Hello {
+        id: RequestId,
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// Request of protocol version 1 client, which has no handshake.
+    /// `start` holds the bytes of the packet read so far, up to the end of its id
+    Request { id: RequestId, start: Vec<u8> },
+    /// Packet without request id, sent by client of the protocol before version 1
+    Unnumbered,
+}
+
+/// Read first packet of the connection, only HELLO is read in full.
+/// Packets without HELLO have the request id after the type byte since
+/// version 1, before it they have the number of fields. Ids of version 1
+/// clients are below 2^32, so the packet has no id when its first 4 bytes
+/// after the type byte are not zero or when they are followed by the
+/// checksum of a packet without fields.
+/// `None` when the stream is closed before the packet starts
+pub(crate) fn read_opening<R: Read>(stream: &mut R, max_size: usize) -> Result<Option<Opening>> {
+    let first = match read_first(stream)? {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    let mut start = vec![first, 0, 0];
+    stream.read_exact(&mut start[1..])?;
+    check_head(&start)?;
+    if start[2] == HELLO_BYTE {
+        return match DBCommands::read_with_id(&mut start.as_slice().chain(stream), max_size)? {
+            (
+                id,
+                DBCommands::Hello {
+                    version,
+                    capabilities,
+                },
+            ) => Ok(Some(Opening::Hello {
+                id,
+                version,
+                capabilities,
+            })),
+            _ => unreachable!("packet of HELLO type is parsed as HELLO"),
+        };
+    }
+
+    let mut word = [0u8; LEN_SIZE];
+    stream.read_exact(&mut word)?;
+    if word != [0; LEN_SIZE] {
+        return Ok(Some(Opening::Unnumbered));
+    }
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum)?;
+    start.extend(word);
+    if State::<ARC>::calculate(&start).to_be_bytes() == checksum {
+        return Ok(Some(Opening::Unnumbered));
+    }
+    // packet of version 1 is longer than its head, type, id and count
+    start.extend(checksum);
+    let mut rest = [0u8; 2];
+    stream.read_exact(&mut rest)?;
+    start.extend(rest);
+    let id = RequestId::from_be_bytes(start[3..].try_into().expect("id is 8 bytes"));
+    Ok(Some(Opening::Request { id, start }))
+}
+
+/// First byte of the next packet, `None` when the peer closed
+/// or reset the connection before the packet starts
+fn read_first<R: Read>(stream: &mut R) -> Result<Option<u8>> {
+    let mut first = [0u8; 1];
+    match stream.read(&mut first) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(first[0])),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Take bytes field by index
+fn bytes_field(fields: &[Vec<u8>], idx: usize) -> Result<Vec<u8>> {
+    match fields.get(idx) {
+        Some(field) => Ok(field.to_owned()),
+        None => Err(KVSError::protocol(format!("missing field {}", idx))),
+    }
+}
+
+/// Take UTF-8 string field by index
+fn string_field(fields: &[Vec<u8>], idx: usize) -> Result<String> {
+    Ok(String::from_utf8(bytes_field(fields, idx)?)?)
+}
+
+/// Take 16 bit integer field by index
+fn u16_field(fields: &[Vec<u8>], idx: usize) -> Result<u16> {
+    match fields.get(idx).map(|field| field.as_slice().try_into()) {
+        Some(Ok(bytes)) => Ok(u16::from_be_bytes(bytes)),
+        _ => Err(KVSError::protocol(format!("field {} is not u16", idx))),
+    }
+}
+
+/// Take integer field by index
+fn u32_field(fields: &[Vec<u8>], idx: usize) -> Result<u32> {
+    match fields.get(idx).map(|field| field.as_slice().try_into()) {
+        Some(Ok(bytes)) => Ok(u32::from_be_bytes(bytes)),
+        _ => Err(KVSError::protocol(format!("field {} is not u32", idx))),
+    }
+}
+
+/// Take 64 bit integer field by index
+fn u64_field(fields: &[Vec<u8>], idx: usize) -> Result<u64> {
+    match fields.get(idx).map(|field| field.as_slice().try_into()) {
+        Some(Ok(bytes)) => Ok(u64::from_be_bytes(bytes)),
+        _ => Err(KVSError::protocol(format!("field {} is not u64", idx))),
+    }
+}
+
+/// Take 64 bit integer field by index, `None` when the packet ends before it
+fn trailing_u64_field(fields: &[Vec<u8>], idx: usize) -> Result<Option<u64>> {
+    match fields.get(idx) {
+        Some(_) => u64_field(fields, idx).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Marks present value of optional field, empty field means `None`
+const SOME_MARK: u8 = 1;
+
+/// Pack optional bytes into field
+fn optional_field(value: Option<Vec<u8>>) -> Vec<u8> {
+    match value {
+        Some(value) => [vec![SOME_MARK], value].concat(),
+        None => Vec::new(),
+    }
+}
+
+/// Take optional bytes field by index
+fn optional_bytes_field(fields: &[Vec<u8>], idx: usize) -> Result<Option<Vec<u8>>> {
+    match fields.get(idx).map(Vec::as_slice) {
+        Some([]) => Ok(None),
+        Some([SOME_MARK, value @ ..]) => Ok(Some(value.to_vec())),
+        _ => Err(KVSError::protocol(format!(
+            "field {} is not optional value",
+            idx
+        ))),
+    }
+}
+
+/// Enumeration to define a commands to KVS engine,
+/// keys and values are sent as raw bytes
+#[derive(Debug, Serialize, Deserialize)]
 pub enum DBCommands {
-    /// Set up value by key into KVS
-    Set { key: String, value: String },
+    /// Set up value by key into KVS, expiring after ttl if given
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
     /// Get value by key
-    Get { key: String },
+    Get { key: Vec<u8> },
     /// Removes value by key
-    Rm { key: String },
+    Rm { key: Vec<u8> },
+    /// Remaining lifetime of the key
+    Ttl { key: Vec<u8> },
+    /// Get pairs with keys from start (inclusive) to end (exclusive)
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    /// Get pairs with keys starting with prefix
+    ScanPrefix { prefix: Vec<u8>, limit: u32 },
+    /// Apply puts and deletes atomically
+    Batch { batch: WriteBatch },
+    /// Get value by key with its version
+    GetVersioned { key: Vec<u8> },
+    /// Write new value only when current one equals expected,
+    /// `None` expected means missing key and `None` new removes the key
+    Cas {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    /// Set value only when the key is missing
+    SetIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    /// Set value only when current value has given version
+    SetIfVersion {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    },
+    /// Start transaction of the connection, following Get, Set and Rm go into it
+    Begin,
+    /// Commit transaction of the connection
+    Commit,
+    /// Discard transaction of the connection
+    Abort,
+    /// Take snapshot of the server store, returns its id
+    Snapshot,
+    /// Drop snapshot by id
+    ReleaseSnapshot { id: u64 },
+    /// Get value by key from snapshot
+    SnapshotGet { id: u64, key: Vec<u8> },
+    /// Get pairs of snapshot with keys from start (inclusive) to end (exclusive)
+    SnapshotScan {
+        id: u64,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    /// Get pairs of snapshot with keys starting with prefix
+    SnapshotScanPrefix {
+        id: u64,
+        prefix: Vec<u8>,
+        limit: u32,
+    },
+    /// Add delta to the counter, negative delta decrements it
+    Incr { key: Vec<u8>, delta: i64 },
+    /// Handshake starting the connection: protocol version of the client
+    /// and capabilities it asks for
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// Set value of `len` bytes sent in `Chunk` packets after the server
+    /// answered with success, the value is set after the last chunk
+    SetStream {
+        key: Vec<u8>,
+        len: u64,
+        ttl: Option<Duration>,
+    },
+    /// Get value as `Stream` response followed by `Chunk` responses
+    GetStream { key: Vec<u8> },
+    /// Part of the value of `SetStream`
+    Chunk { data: Vec<u8> },
 }
 
 const GET_BYTE: u8 = 1;
 const SET_BYTE: u8 = 2;
 const RM_BYTE: u8 = 3;
+const SCAN_BYTE: u8 = 4;
+const SCAN_PREFIX_BYTE: u8 = 5;
+const TTL_BYTE: u8 = 6;
+const BATCH_BYTE: u8 = 7;
+const GET_VERSIONED_BYTE: u8 = 8;
+const CAS_BYTE: u8 = 9;
+const SET_IF_ABSENT_BYTE: u8 = 10;
+const SET_IF_VERSION_BYTE: u8 = 11;
+const BEGIN_BYTE: u8 = 12;
+const COMMIT_BYTE: u8 = 13;
+const ABORT_BYTE: u8 = 14;
+const SNAPSHOT_BYTE: u8 = 15;
+const RELEASE_SNAPSHOT_BYTE: u8 = 16;
+const SNAPSHOT_GET_BYTE: u8 = 17;
+const SNAPSHOT_SCAN_BYTE: u8 = 18;
+const SNAPSHOT_SCAN_PREFIX_BYTE: u8 = 19;
+const INCR_BYTE: u8 = 20;
+const HELLO_BYTE: u8 = 21;
+const SET_STREAM_BYTE: u8 = 22;
+const GET_STREAM_BYTE: u8 = 23;
+const CHUNK_BYTE: u8 = 24;
+
+/// Every write of the batch is sent as this byte field followed by key (and value)
+const PUT_OP_BYTE: u8 = 1;
+const DELETE_OP_BYTE: u8 = 2;
 
 impl DBCommands {
     /// Invoke command on KvsEngine and return ServerResponse
-    pub fn invoke_cmd<S: KvsEngine>(&self, store: &mut S) -> ServerResponse {
-        match self {
-            DBCommands::Get { key } => {
-                if let Ok(res) = store.get(key.to_owned()) {
-                    match res {
-                        Some(value) => ServerResponse::Success { output: value },
-                        None => ServerResponse::Success {
-                            output: String::from("Key not found"),
-                        },
-                        // None => ServerResponse::Failure{ message: String::from("Key not found")},
-                    }
-                } else {
-                    ServerResponse::Failure {
-                        message: String::from("Internal error"),
-                    }
+    pub fn invoke_cmd<S: KvsEngine>(&self, store: &S) -> ServerResponse {
+        let res = match self {
+            DBCommands::Get { key } => store.get(key.to_owned()).map(|res| match res {
+                Some(value) => ServerResponse::Value { value },
+                None => ServerResponse::NotFound,
+            }),
+            DBCommands::Set { key, value, ttl } => match ttl {
+                Some(ttl) => store.set_with_ttl(key.to_owned(), value.to_owned(), *ttl),
+                None => store.set(key.to_owned(), value.to_owned()),
+            }
+            .map(|()| ServerResponse::success()),
+            DBCommands::Rm { key } => store
+                .remove(key.to_owned())
+                .map(|()| ServerResponse::success()),
+            DBCommands::Ttl { key } => match store.ttl(key.to_owned()) {
+                Ok(Some(ttl)) => Ok(ServerResponse::Success {
+                    output: format!("{:.3}s", ttl.as_secs_f64()),
+                }),
+                Ok(None) => Ok(ServerResponse::Success {
+                    output: String::from("No expiration"),
+                }),
+                Err(KVSError::KeyNotFoundError) => Ok(ServerResponse::NotFound),
+                Err(e) => Err(e),
+            },
+            DBCommands::Scan { start, end, limit } => store
+                .scan(start.to_owned(), end.to_owned(), *limit as usize)
+                .map(|entries| ServerResponse::Entries { entries }),
+            DBCommands::ScanPrefix { prefix, limit } => store
+                .scan_prefix(prefix.to_owned(), *limit as usize)
+                .map(|entries| ServerResponse::Entries { entries }),
+            DBCommands::Batch { batch } => store
+                .apply_batch(batch.to_owned())
+                .map(|()| ServerResponse::success()),
+            DBCommands::GetVersioned { key } => {
+                store.get_versioned(key.to_owned()).map(|res| match res {
+                    Some((value, version)) => ServerResponse::Versioned { value, version },
+                    None => ServerResponse::NotFound,
+                })
+            }
+            DBCommands::Cas { key, expected, new } => store
+                .compare_and_swap(key.to_owned(), expected.to_owned(), new.to_owned())
+                .map(|()| ServerResponse::success()),
+            DBCommands::SetIfAbsent { key, value } => store
+                .set_if_absent(key.to_owned(), value.to_owned())
+                .map(|version| ServerResponse::Version { version }),
+            DBCommands::SetIfVersion {
+                key,
+                value,
+                version,
+            } => store
+                .set_if_version(key.to_owned(), value.to_owned(), *version)
+                .map(|version| ServerResponse::Version { version }),
+            DBCommands::Incr { key, delta } => store
+                .incr(key.to_owned(), *delta)
+                .map(|value| ServerResponse::Integer { value }),
+            DBCommands::Begin
+            | DBCommands::Commit
+            | DBCommands::Abort
+            | DBCommands::Snapshot
+            | DBCommands::ReleaseSnapshot { .. }
+            | DBCommands::SnapshotGet { .. }
+            | DBCommands::SnapshotScan { .. }
+            | DBCommands::SnapshotScanPrefix { .. } => Ok(ServerResponse::failure(
+                ErrorCode::InvalidCommand,
+                "Command needs a server session",
+            )),
+            DBCommands::Hello { .. } => Ok(ServerResponse::failure(
+                ErrorCode::InvalidCommand,
+                "Handshake is only allowed at connection start",
+            )),
+            DBCommands::SetStream { .. } | DBCommands::GetStream { .. } => Ok(
+                ServerResponse::failure(ErrorCode::InvalidCommand, "Command needs a connection"),
+            ),
+            DBCommands::Chunk { .. } => Ok(ServerResponse::failure(
+                ErrorCode::InvalidCommand,
+                "Chunk without streamed set",
+            )),
+        };
+        res.unwrap_or_else(|e| ServerResponse::error(&e))
+    }
+    /// Pack DBCommands to bytes follow the protocol (consuming self)
+    /// with HEAD and CRC-ARC hashsum, request id is 0
+    pub fn to_packet(self) -> Result<Vec<u8>> {
+        self.to_packet_with_id(0)
+    }
+    /// Pack DBCommands with the request id
+    pub fn to_packet_with_id(self, id: RequestId) -> Result<Vec<u8>> {
+        let (cmd, fields) = match self {
+            DBCommands::Get { key } => (GET_BYTE, vec![key]),
+            DBCommands::Rm { key } => (RM_BYTE, vec![key]),
+            DBCommands::Ttl { key } => (TTL_BYTE, vec![key]),
+            DBCommands::Set { key, value, ttl } => {
+                let mut fields = vec![key, value];
+                // missing ttl field means value never expires
+                if let Some(ttl) = ttl {
+                    fields.push((ttl.as_millis() as u64).to_be_bytes().to_vec());
                 }
+                (SET_BYTE, fields)
             }
-            DBCommands::Set { key, value } => {
-                if let Ok(_res) = store.set(key.to_owned(), value.to_owned()) {
-                    ServerResponse::Success {
-                        output: String::from(""),
-                    }
-                } else {
-                    ServerResponse::Failure {
-                        message: String::from("Cant set"),
-                    }
+            DBCommands::Scan { start, end, limit } => {
+                let mut fields = vec![start, limit.to_be_bytes().to_vec()];
+                // missing end field means scan to the last key
+                if let Some(end) = end {
+                    fields.push(end);
                 }
+                (SCAN_BYTE, fields)
             }
-            DBCommands::Rm { key } => {
-                if let Ok(_res) = store.remove(key.to_owned()) {
-                    ServerResponse::Success {
-                        output: String::new(),
-                    }
-                } else {
-                    ServerResponse::Failure {
-                        message: String::from("Key not found"),
+            DBCommands::ScanPrefix { prefix, limit } => {
+                (SCAN_PREFIX_BYTE, vec![prefix, limit.to_be_bytes().to_vec()])
+            }
+            DBCommands::Batch { batch } => {
+                let mut fields = Vec::with_capacity(batch.len() * 3);
+                for op in batch {
+                    match op {
+                        BatchOp::Put { key, value } => {
+                            fields.extend([vec![PUT_OP_BYTE], key, value]);
+                        }
+                        BatchOp::Delete { key } => {
+                            fields.extend([vec![DELETE_OP_BYTE], key]);
+                        }
                     }
                 }
+                (BATCH_BYTE, fields)
             }
-        }
-    }
-    /// Pack DBCommands to bytes follow the protocol (consuming self)
-    /// with HEAD and CRC-ARC hashsum
-    pub fn to_packet(self) -> Result<Vec<u8>> {
-        let (cmd, key, value) = match self {
-            DBCommands::Get { key } => (GET_BYTE, key, String::from("")),
-            DBCommands::Rm { key } => (RM_BYTE, key, String::from("")),
-            DBCommands::Set { key, value } => (SET_BYTE, key, value),
+            DBCommands::GetVersioned { key } => (GET_VERSIONED_BYTE, vec![key]),
+            DBCommands::Cas { key, expected, new } => (
+                CAS_BYTE,
+                vec![key, optional_field(expected), optional_field(new)],
+            ),
+            DBCommands::SetIfAbsent { key, value } => (SET_IF_ABSENT_BYTE, vec![key, value]),
+            DBCommands::SetIfVersion {
+                key,
+                value,
+                version,
+            } => (
+                SET_IF_VERSION_BYTE,
+                vec![key, value, version.to_be_bytes().to_vec()],
+            ),
+            DBCommands::Begin => (BEGIN_BYTE, Vec::new()),
+            DBCommands::Commit => (COMMIT_BYTE, Vec::new()),
+            DBCommands::Abort => (ABORT_BYTE, Vec::new()),
+            DBCommands::Snapshot => (SNAPSHOT_BYTE, Vec::new()),
+            DBCommands::ReleaseSnapshot { id } => {
+                (RELEASE_SNAPSHOT_BYTE, vec![id.to_be_bytes().to_vec()])
+            }
+            DBCommands::SnapshotGet { id, key } => {
+                (SNAPSHOT_GET_BYTE, vec![id.to_be_bytes().to_vec(), key])
+            }
+            DBCommands::SnapshotScan {
+                id,
+                start,
+                end,
+                limit,
+            } => {
+                let mut fields = vec![
+                    id.to_be_bytes().to_vec(),
+                    start,
+                    limit.to_be_bytes().to_vec(),
+                ];
+                // missing end field means scan to the last key
+                if let Some(end) = end {
+                    fields.push(end);
+                }
+                (SNAPSHOT_SCAN_BYTE, fields)
+            }
+            DBCommands::SnapshotScanPrefix { id, prefix, limit } => (
+                SNAPSHOT_SCAN_PREFIX_BYTE,
+                vec![
+                    id.to_be_bytes().to_vec(),
+                    prefix,
+                    limit.to_be_bytes().to_vec(),
+                ],
+            ),
+            DBCommands::Incr { key, delta } => (INCR_BYTE, vec![key, delta.to_be_bytes().to_vec()]),
+            DBCommands::Hello {
+                version,
+                capabilities,
+            } => (
+                HELLO_BYTE,
+                vec![
+                    version.to_be_bytes().to_vec(),
+                    capabilities.bits().to_be_bytes().to_vec(),
+                ],
+            ),
+            DBCommands::SetStream { key, len, ttl } => {
+                let mut fields = vec![key, len.to_be_bytes().to_vec()];
+                // missing ttl field means value never expires
+                if let Some(ttl) = ttl {
+                    fields.push((ttl.as_millis() as u64).to_be_bytes().to_vec());
+                }
+                (SET_STREAM_BYTE, fields)
+            }
+            DBCommands::GetStream { key } => (GET_STREAM_BYTE, vec![key]),
+            DBCommands::Chunk { data } => (CHUNK_BYTE, vec![data]),
         };
-        let k_len = key.len() as CommandLenType;
-        let v_len = value.len() as CommandLenType;
-
-        let k_len_enc = k_len.to_be_bytes().to_vec();
-        let v_len_enc = v_len.to_be_bytes().to_vec();
-        let cmd_vec = vec![cmd];
-        let packet = [
-            CMD_HEAD.to_vec(),
-            cmd_vec,
-            k_len_enc,
-            v_len_enc,
-            key.into_bytes(),
-            value.into_bytes(),
-        ]
-        .concat();
-        let checksum = State::<ARC>::calculate(&packet).to_be_bytes();
-        let packet = [packet, checksum.to_vec()].concat();
-        Ok(packet)
+        Ok(pack(cmd, id, fields))
     }
     /// Unpack DBCommands from incomming stream by protocol
     /// Check HEAD and CRC-ARC of packet
-    pub fn from_stream(stream: &mut TcpStream) -> Result<Self> {
-        let mut head = [0u8; 2];
-        let _ = &stream.read_exact(&mut head)?;
-
-        check_head(&head)?;
-
-        let mut cmd_array = [0u8; 1];
-        let _ = &stream.read_exact(&mut cmd_array)?;
-        let cmd = cmd_array[0];
-
-        let mut key_len_coded = [0u8; LEN_SIZE];
-        stream.read_exact(&mut key_len_coded)?;
-        let mut val_len_coded = [0u8; LEN_SIZE];
-        stream.read_exact(&mut val_len_coded)?;
-        let key_len = CommandLenType::from_be_bytes(key_len_coded);
-        let val_len = CommandLenType::from_be_bytes(val_len_coded);
-
-        let mut key_buff = vec![0u8; key_len as usize];
-        stream.read_exact(&mut key_buff)?;
-        let key = String::from_utf8_lossy(&key_buff).into_owned();
-        let mut value_buff = vec![0u8; val_len as usize];
-        stream.read_exact(&mut value_buff)?;
-        let value = String::from_utf8_lossy(&value_buff).into_owned();
-
-        // check hashsum of the data
-        let mut checksum = vec![0u8; 2];
-        stream.read_exact(&mut checksum)?;
-        let packet = [
-            CMD_HEAD.to_vec(),
-            cmd_array.to_vec(),
-            key_len_coded.to_vec(),
-            val_len_coded.to_vec(),
-            key_buff,
-            value_buff,
-        ]
-        .concat();
-        let calculated = State::<ARC>::calculate(&packet).to_be_bytes();
-        if calculated[0] != checksum[0] && calculated[1] != checksum[1] {
-            log::error!(
-                "Checksum of command not matched, must be {:?}, received {:?}",
-                calculated,
-                checksum
-            );
-            return Err(KVSError::GeneralKVSError);
-        }
-
+    pub fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Self::from_stream_with_id(stream).map(|(_, cmd)| cmd)
+    }
+    /// Unpack DBCommands with its request id
+    pub fn from_stream_with_id<R: Read>(stream: &mut R) -> Result<(RequestId, Self)> {
+        Self::read_with_id(stream, usize::MAX)
+    }
+    fn read_with_id<R: Read>(stream: &mut R, max_size: usize) -> Result<(RequestId, Self)> {
+        let (cmd, id, fields) = unpack(stream, max_size)?;
+        Self::from_fields(cmd, fields).map(|cmd| (id, cmd))
+    }
+    fn from_fields(cmd: u8, fields: Vec<Vec<u8>>) -> Result<Self> {
         match cmd {
-            GET_BYTE => Ok(DBCommands::Get { key }),
-            SET_BYTE => Ok(DBCommands::Set { key, value }),
-            RM_BYTE => Ok(DBCommands::Rm { key }),
-            _ => Err(KVSError::GeneralKVSError),
+            GET_BYTE => Ok(DBCommands::Get {
+                key: bytes_field(&fields, 0)?,
+            }),
+            SET_BYTE => Ok(DBCommands::Set {
+                key: bytes_field(&fields, 0)?,
+                value: bytes_field(&fields, 1)?,
+                ttl: trailing_u64_field(&fields, 2)?.map(Duration::from_millis),
+            }),
+            RM_BYTE => Ok(DBCommands::Rm {
+                key: bytes_field(&fields, 0)?,
+            }),
+            TTL_BYTE => Ok(DBCommands::Ttl {
+                key: bytes_field(&fields, 0)?,
+            }),
+            SCAN_BYTE => Ok(DBCommands::Scan {
+                start: bytes_field(&fields, 0)?,
+                limit: u32_field(&fields, 1)?,
+                end: bytes_field(&fields, 2).ok(),
+            }),
+            SCAN_PREFIX_BYTE => Ok(DBCommands::ScanPrefix {
+                prefix: bytes_field(&fields, 0)?,
+                limit: u32_field(&fields, 1)?,
+            }),
+            BATCH_BYTE => Ok(DBCommands::Batch {
+                batch: batch_from_fields(fields)?,
+            }),
+            GET_VERSIONED_BYTE => Ok(DBCommands::GetVersioned {
+                key: bytes_field(&fields, 0)?,
+            }),
+            CAS_BYTE => Ok(DBCommands::Cas {
+                key: bytes_field(&fields, 0)?,
+                expected: optional_bytes_field(&fields, 1)?,
+                new: optional_bytes_field(&fields, 2)?,
+            }),
+            SET_IF_ABSENT_BYTE => Ok(DBCommands::SetIfAbsent {
+                key: bytes_field(&fields, 0)?,
+                value: bytes_field(&fields, 1)?,
+            }),
+            SET_IF_VERSION_BYTE => Ok(DBCommands::SetIfVersion {
+                key: bytes_field(&fields, 0)?,
+                value: bytes_field(&fields, 1)?,
+                version: u64_field(&fields, 2)?,
+            }),
+            BEGIN_BYTE => Ok(DBCommands::Begin),
+            COMMIT_BYTE => Ok(DBCommands::Commit),
+            ABORT_BYTE => Ok(DBCommands::Abort),
+            SNAPSHOT_BYTE => Ok(DBCommands::Snapshot),
+            RELEASE_SNAPSHOT_BYTE => Ok(DBCommands::ReleaseSnapshot {
+                id: u64_field(&fields, 0)?,
+            }),
+            SNAPSHOT_GET_BYTE => Ok(DBCommands::SnapshotGet {
+                id: u64_field(&fields, 0)?,
+                key: bytes_field(&fields, 1)?,
+            }),
+            SNAPSHOT_SCAN_BYTE => Ok(DBCommands::SnapshotScan {
+                id: u64_field(&fields, 0)?,
+                start: bytes_field(&fields, 1)?,
+                limit: u32_field(&fields, 2)?,
+                end: bytes_field(&fields, 3).ok(),
+            }),
+            SNAPSHOT_SCAN_PREFIX_BYTE => Ok(DBCommands::SnapshotScanPrefix {
+                id: u64_field(&fields, 0)?,
+                prefix: bytes_field(&fields, 1)?,
+                limit: u32_field(&fields, 2)?,
+            }),
+            INCR_BYTE => Ok(DBCommands::Incr {
+                key: bytes_field(&fields, 0)?,
+                delta: u64_field(&fields, 1)? as i64,
+            }),
+            HELLO_BYTE => Ok(DBCommands::Hello {
+                version: u16_field(&fields, 0)?,
+                capabilities: Capabilities::from_bits(u32_field(&fields, 1)?),
+            }),
+            SET_STREAM_BYTE => Ok(DBCommands::SetStream {
+                key: bytes_field(&fields, 0)?,
+                len: u64_field(&fields, 1)?,
+                ttl: trailing_u64_field(&fields, 2)?.map(Duration::from_millis),
+            }),
+            GET_STREAM_BYTE => Ok(DBCommands::GetStream {
+                key: bytes_field(&fields, 0)?,
+            }),
+            CHUNK_BYTE => Ok(DBCommands::Chunk {
+                data: bytes_field(&fields, 0)?,
+            }),
+            cmd => Err(KVSError::protocol(format!("unknown command {}", cmd))),
+        }
+    }
+    /// Unpack next DBCommands of the connection,
+    /// `None` when the stream is closed before the packet starts
+    pub fn next_from_stream<R: Read>(stream: &mut R) -> Result<Option<Self>> {
+        Ok(
+            Self::next_with_id_from_stream_with_limits(stream, &FrameLimits::UNLIMITED)?
+                .map(|(_, cmd)| cmd),
+        )
+    }
+    /// Unpack next DBCommands of the connection with its request id,
+    /// packet larger than the default frame limit is `TooLargeError`
+    pub fn next_with_id_from_stream<R: Read>(stream: &mut R) -> Result<Option<(RequestId, Self)>> {
+        Self::next_with_id_from_stream_with_limits(stream, &FrameLimits::default())
+    }
+    /// Unpack next DBCommands of the connection with its request id,
+    /// packet larger than the frame limit of `limits` is `TooLargeError`
+    pub fn next_with_id_from_stream_with_limits<R: Read>(
+        stream: &mut R,
+        limits: &FrameLimits,
+    ) -> Result<Option<(RequestId, Self)>> {
+        match read_first(stream)? {
+            Some(first) => {
+                let max_size = limits.max_frame_size;
+                Self::read_with_id(&mut [first].as_slice().chain(stream), max_size).map(Some)
+            }
+            None => Ok(None),
         }
     }
 }
 
+/// Unpack writes of the batch: op byte, key and value for put
+fn batch_from_fields(fields: Vec<Vec<u8>>) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut fields = fields.into_iter();
+    while let Some(op) = fields.next() {
+        match (op.as_slice(), fields.next()) {
+            ([PUT_OP_BYTE], Some(key)) => match fields.next() {
+                Some(value) => batch.put(key, value),
+                None => return Err(KVSError::protocol("missing value of batch put")),
+            },
+            ([DELETE_OP_BYTE], Some(key)) => batch.delete(key),
+            _ => return Err(KVSError::protocol("unknown batch operation")),
+        };
+    }
+    Ok(batch)
+}
+
 const SUCCESS_BYTE: u8 = 100;
 const FAILURE_BYTE: u8 = 101;
+const ENTRIES_BYTE: u8 = 102;
+const VALUE_BYTE: u8 = 103;
+const VERSIONED_BYTE: u8 = 104;
+const VERSION_BYTE: u8 = 105;
+const SNAPSHOT_ID_BYTE: u8 = 108;
+const INTEGER_BYTE: u8 = 109;
+const NOT_FOUND_BYTE: u8 = 110;
+const HELLO_RESPONSE_BYTE: u8 = 111;
+const STREAM_BYTE: u8 = 112;
+const CHUNK_RESPONSE_BYTE: u8 = 113;
 
 /// Type to mark success or failure of command invokation
 #[derive(Debug)]
 pub enum ServerResponse {
-    Success { output: String },
-    Failure { message: String },
+    Success {
+        output: String,
+    },
+    /// Command failed, `code` tells the class of the error
+    Failure {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Raw value of get
+    Value {
+        value: Vec<u8>,
+    },
+    /// Key of get is missing, told apart from any value
+    NotFound,
+    /// Key-value pairs of scan
+    Entries {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// Raw value of versioned get with its version
+    Versioned {
+        value: Vec<u8>,
+        version: u64,
+    },
+    /// Version of the value set by conditional write
+    Version {
+        version: u64,
+    },
+    /// Id of the taken snapshot
+    Snapshot {
+        id: u64,
+    },
+    /// New value of the counter
+    Integer {
+        value: i64,
+    },
+    /// Handshake accepted: agreed protocol version and granted capabilities
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// Value of streamed get has `len` bytes, sent in following `Chunk` responses
+    Stream {
+        len: u64,
+    },
+    /// Part of the value of streamed get
+    Chunk {
+        data: Vec<u8>,
+    },
 }
 
 impl ServerResponse {
+    /// Success without output
+    pub fn success() -> Self {
+        ServerResponse::Success {
+            output: String::new(),
+        }
+    }
+
+    /// Failure of given class which has no `KVSError`
+    pub fn failure(code: ErrorCode, message: &str) -> Self {
+        ServerResponse::Failure {
+            code,
+            message: String::from(message),
+        }
+    }
+
+    /// Failure carrying code of the error, failures of the server itself are logged
+    pub fn error(e: &KVSError) -> Self {
+        let code = ErrorCode::from(e);
+        if code.is_server_fault() {
+            log::error!("Command failed: {}", e);
+        }
+        ServerResponse::Failure {
+            code,
+            message: e.to_string(),
+        }
+    }
+
+    /// Turn failure into typed error, see `KVSError::from_failure`
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            ServerResponse::Failure { code, message } => Err(KVSError::from_failure(code, message)),
+            resp => Ok(resp),
+        }
+    }
+
     /// Pack ServerResponse into bytes by protocol (consuming self)
-    /// with HEAD and CRC-ARC hashsum
+    /// with HEAD and CRC-ARC hashsum, request id is 0
     pub fn to_packet(self) -> Result<Vec<u8>> {
-        let (resp_byte, msg) = match self {
-            ServerResponse::Success { output } => (SUCCESS_BYTE, output),
-            ServerResponse::Failure { message } => (FAILURE_BYTE, message),
-        };
-        let msg_len = msg.len() as CommandLenType;
-
-        let msg_len_enc = msg_len.to_be_bytes().to_vec();
-        let resp_vec = vec![resp_byte];
-        let packet = [CMD_HEAD.to_vec(), resp_vec, msg_len_enc, msg.into_bytes()].concat();
-        let checksum = State::<ARC>::calculate(&packet).to_be_bytes();
-        let packet = [packet, checksum.to_vec()].concat();
-        Ok(packet)
+        self.to_packet_with_id(0)
+    }
+    /// Pack ServerResponse answering request with the id
+    pub fn to_packet_with_id(self, id: RequestId) -> Result<Vec<u8>> {
+        let (resp_byte, fields) = self.into_fields();
+        Ok(pack(resp_byte, id, fields))
+    }
+    /// Pack ServerResponse readable by clients of the protocol before version 1
+    pub(crate) fn into_unnumbered_packet(self) -> Vec<u8> {
+        let (resp_byte, fields) = self.into_fields();
+        pack_unnumbered(resp_byte, fields)
+    }
+    fn into_fields(self) -> (u8, Vec<Vec<u8>>) {
+        match self {
+            ServerResponse::Success { output } => (SUCCESS_BYTE, vec![output.into_bytes()]),
+            ServerResponse::Failure { code, message } => (
+                FAILURE_BYTE,
+                vec![message.into_bytes(), code.as_u16().to_be_bytes().to_vec()],
+            ),
+            ServerResponse::Value { value } => (VALUE_BYTE, vec![value]),
+            ServerResponse::NotFound => (NOT_FOUND_BYTE, Vec::new()),
+            ServerResponse::Entries { entries } => (
+                ENTRIES_BYTE,
+                entries
+                    .into_iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect(),
+            ),
+            ServerResponse::Versioned { value, version } => {
+                (VERSIONED_BYTE, vec![value, version.to_be_bytes().to_vec()])
+            }
+            ServerResponse::Version { version } => {
+                (VERSION_BYTE, vec![version.to_be_bytes().to_vec()])
+            }
+            ServerResponse::Snapshot { id } => (SNAPSHOT_ID_BYTE, vec![id.to_be_bytes().to_vec()]),
+            ServerResponse::Integer { value } => (INTEGER_BYTE, vec![value.to_be_bytes().to_vec()]),
+            ServerResponse::Hello {
+                version,
+                capabilities,
+            } => (
+                HELLO_RESPONSE_BYTE,
+                vec![
+                    version.to_be_bytes().to_vec(),
+                    capabilities.bits().to_be_bytes().to_vec(),
+                ],
+            ),
+            ServerResponse::Stream { len } => (STREAM_BYTE, vec![len.to_be_bytes().to_vec()]),
+            ServerResponse::Chunk { data } => (CHUNK_RESPONSE_BYTE, vec![data]),
+        }
     }
     /// Unpack ServerResponse from stream of bytes
     /// Check HEAD and CRC-ARC of packet
-    pub fn from_stream(stream: &mut TcpStream) -> Result<Self> {
-        let mut head = [0u8; 2];
-        let _ = &stream.read_exact(&mut head)?;
-
-        check_head(&head)?;
-
-        let mut resp_byte = [0u8; 1];
-        let _ = &stream.read_exact(&mut resp_byte)?;
-
-        let resp_type = resp_byte[0];
-
-        let mut msg_len_coded = [0u8; LEN_SIZE];
-        stream.read_exact(&mut msg_len_coded)?;
-
-        let msg_len = CommandLenType::from_be_bytes(msg_len_coded);
-
-        let mut msg_vec = vec![0u8; msg_len as usize];
-        stream.read_exact(&mut msg_vec)?;
-        let msg = String::from_utf8_lossy(&msg_vec).into_owned();
-
-        // check hashsum of the data
-        let mut checksum = vec![0u8; 2];
-        stream.read_exact(&mut checksum)?;
-        let packet = [
-            CMD_HEAD.to_vec(),
-            resp_byte.to_vec(),
-            msg_len_coded.to_vec(),
-            msg_vec,
-        ]
-        .concat();
-        let calculated = State::<ARC>::calculate(&packet).to_be_bytes();
-        if calculated[0] != checksum[0] && calculated[1] != checksum[1] {
-            log::error!(
-                "Response checksum is not matched, must be {:?}, received {:?}",
-                calculated,
-                checksum
-            );
-            return Err(KVSError::GeneralKVSError);
-        }
-
+    pub fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Self::from_stream_with_id(stream).map(|(_, resp)| resp)
+    }
+    /// Unpack ServerResponse with id of the request it answers
+    pub fn from_stream_with_id<R: Read>(stream: &mut R) -> Result<(RequestId, Self)> {
+        let (resp_type, id, fields) = unpack(stream, usize::MAX)?;
+        Self::from_fields(resp_type, fields).map(|resp| (id, resp))
+    }
+    fn from_fields(resp_type: u8, fields: Vec<Vec<u8>>) -> Result<Self> {
         match resp_type {
-            SUCCESS_BYTE => Ok(ServerResponse::Success { output: msg }),
-            FAILURE_BYTE => Ok(ServerResponse::Failure { message: msg }),
-            _ => Err(KVSError::GeneralKVSError),
+            SUCCESS_BYTE => Ok(ServerResponse::Success {
+                output: string_field(&fields, 0)?,
+            }),
+            FAILURE_BYTE => Ok(ServerResponse::Failure {
+                message: string_field(&fields, 0)?,
+                // failures of older servers have no code
+                code: u16_field(&fields, 1).map_or(ErrorCode::Internal, ErrorCode::from_u16),
+            }),
+            VALUE_BYTE => Ok(ServerResponse::Value {
+                value: bytes_field(&fields, 0)?,
+            }),
+            NOT_FOUND_BYTE => Ok(ServerResponse::NotFound),
+            ENTRIES_BYTE if fields.len().is_multiple_of(2) => {
+                let mut fields = fields.into_iter();
+                let mut entries = Vec::with_capacity(fields.len() / 2);
+                while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+                    entries.push((key, value));
+                }
+                Ok(ServerResponse::Entries { entries })
+            }
+            VERSIONED_BYTE => Ok(ServerResponse::Versioned {
+                value: bytes_field(&fields, 0)?,
+                version: u64_field(&fields, 1)?,
+            }),
+            VERSION_BYTE => Ok(ServerResponse::Version {
+                version: u64_field(&fields, 0)?,
+            }),
+            SNAPSHOT_ID_BYTE => Ok(ServerResponse::Snapshot {
+                id: u64_field(&fields, 0)?,
+            }),
+            INTEGER_BYTE => Ok(ServerResponse::Integer {
+                value: u64_field(&fields, 0)? as i64,
+            }),
+            HELLO_RESPONSE_BYTE => Ok(ServerResponse::Hello {
+                version: u16_field(&fields, 0)?,
+                capabilities: Capabilities::from_bits(u32_field(&fields, 1)?),
+            }),
+            STREAM_BYTE => Ok(ServerResponse::Stream {
+                len: u64_field(&fields, 0)?,
+            }),
+            CHUNK_RESPONSE_BYTE => Ok(ServerResponse::Chunk {
+                data: bytes_field(&fields, 0)?,
+            }),
+            resp_type => Err(KVSError::protocol(format!(
+                "unknown response {}",
+                resp_type
+            ))),
+        }
+    }
+    /// Unpack next ServerResponse of the connection,
+    /// `None` when the stream is closed before the packet starts
+    pub fn next_from_stream<R: Read>(stream: &mut R) -> Result<Option<Self>> {
+        Ok(Self::next_with_id_from_stream(stream)?.map(|(_, resp)| resp))
+    }
+    /// Unpack next ServerResponse of the connection with its request id
+    pub fn next_with_id_from_stream<R: Read>(stream: &mut R) -> Result<Option<(RequestId, Self)>> {
+        match read_first(stream)? {
+            Some(first) => {
+                Self::from_stream_with_id(&mut [first].as_slice().chain(stream)).map(Some)
+            }
+            None => Ok(None),
         }
     }
 }
@@ -0,0 +1,651 @@
+// #![deny(missing_docs)]
+//! Front end speaking RESP, the protocol of Redis, so its clients and tools
+//! can use the server. Commands are mapped onto `KvsEngine`
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::batch::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::error::{KVSError, Result};
+use crate::tcp::protocol::FrameLimits;
+use crate::tcp::server::{idle_as_closed, read_line, reject, ServerConfig, Workers};
+
+/// Keys examined by SCAN without COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// SCAN cursors kept, older ones are forgotten
+const MAX_CURSORS: usize = 1024;
+
+/// Reply of a RESP command
+#[derive(Debug)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            // line break inside would end the error early and start a forged reply
+            Reply::Error(message) => {
+                let message = message.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", message).as_bytes())
+            }
+            Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.write_to(out));
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out);
+        out
+    }
+
+    fn error(message: impl Into<String>) -> Reply {
+        Reply::Error(message.into())
+    }
+}
+
+impl From<KVSError> for Reply {
+    fn from(e: KVSError) -> Reply {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+/// Error of the command is a reply too, so `?` works on engine calls
+type CommandResult = std::result::Result<Reply, Reply>;
+
+/// State shared by all RESP connections: SCAN cursors and counters of INFO
+pub struct RespState {
+    started: Instant,
+    connected: AtomicUsize,
+    connections: AtomicU64,
+    commands: AtomicU64,
+    cursors: Mutex<Cursors>,
+}
+
+impl Default for RespState {
+    fn default() -> Self {
+        RespState {
+            started: Instant::now(),
+            connected: AtomicUsize::new(0),
+            connections: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+            cursors: Mutex::new(Cursors::default()),
+        }
+    }
+}
+
+/// SCAN cursors by number, each remembers the key the next call starts at.
+/// Cursors are shared by connections, as clients may continue a scan
+/// on other connection of their pool
+#[derive(Default)]
+struct Cursors {
+    last_id: u64,
+    start_keys: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl Cursors {
+    fn save(&mut self, start: Vec<u8>) -> u64 {
+        self.last_id += 1;
+        self.start_keys.insert(self.last_id, start);
+        self.order.push_back(self.last_id);
+        if self.order.len() > MAX_CURSORS {
+            let oldest = self.order.pop_front().unwrap();
+            self.start_keys.remove(&oldest);
+        }
+        self.last_id
+    }
+
+    fn resume(&self, id: u64) -> Option<Vec<u8>> {
+        self.start_keys.get(&id).cloned()
+    }
+}
+
+/// Serve RESP commands of the connection until client closes it or it is idle
+/// for the idle timeout. Malformed or too large request gets an error
+/// and the connection is closed
+pub(crate) fn handle_connection<S: KvsEngine>(
+    store: S,
+    state: Arc<RespState>,
+    stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    state.connected.fetch_add(1, Ordering::SeqCst);
+    state.connections.fetch_add(1, Ordering::SeqCst);
+    let res = serve(&store, &state, stream, workers, config);
+    state.connected.fetch_sub(1, Ordering::SeqCst);
+    res
+}
+
+/// Error reply for the connection which is closed without serving it
+pub(crate) fn error_reply(e: KVSError) -> Vec<u8> {
+    Reply::from(e).to_bytes()
+}
+
+fn serve<S: KvsEngine>(
+    store: &S,
+    state: &Arc<RespState>,
+    mut stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    loop {
+        let args = match idle_as_closed(read_request(&mut reader, config.limits.max_frame_size)) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e @ (KVSError::TooLargeError { .. } | KVSError::ProtocolError { .. })) => {
+                log::warn!("Rejecting RESP request: {}", e);
+                writer.flush()?;
+                reject(&mut reader, &mut stream, &Reply::from(e).to_bytes())?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        state.commands.fetch_add(1, Ordering::SeqCst);
+        let reply = {
+            let (store, state, limits) = (store.clone(), Arc::clone(state), config.limits);
+            workers.run(move || invoke(&store, &state, &limits, args).unwrap_or_else(|e| e))?
+        };
+        log::debug!("RESP result - {:?}", reply);
+        writer.write_all(&reply.to_bytes())?;
+        // pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read arguments of the next command, sent as array of bulk strings
+/// or as inline command. `None` when the client closed the connection.
+/// Request larger than `max_size` is rejected before it is allocated
+fn read_request<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_length(&line[1..])?;
+    let mut size = line.len();
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader, max_size)
+            .and_then(|line| line.ok_or_else(|| KVSError::protocol("connection closed")))?;
+        if line.first() != Some(&b'$') {
+            return Err(KVSError::protocol("expected '$'"));
+        }
+        let len = parse_length(&line[1..])?;
+        size = size.saturating_add(line.len() + 4).saturating_add(len);
+        if size > max_size {
+            return Err(KVSError::too_large("request", size as u64, max_size as u64));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(KVSError::protocol("bulk string does not end with CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn parse_length(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .map(|len| len.max(0) as usize)
+        .ok_or_else(|| KVSError::protocol("invalid length"))
+}
+
+/// Run the command on the store, name is case insensitive
+fn invoke<S: KvsEngine>(
+    store: &S,
+    state: &RespState,
+    limits: &FrameLimits,
+    args: Vec<Vec<u8>>,
+) -> CommandResult {
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let args = &args[1..];
+    let wrong_arity = || {
+        Reply::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ))
+    };
+    let arity = |min: usize, max: Option<usize>| {
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            return Err(wrong_arity());
+        }
+        Ok(())
+    };
+    match name.as_str() {
+        "ping" => {
+            arity(0, Some(1))?;
+            Ok(match args.first() {
+                Some(message) => Reply::Bulk(message.to_owned()),
+                None => Reply::Status("PONG"),
+            })
+        }
+        "get" => {
+            arity(1, Some(1))?;
+            limits.check_key(&args[0])?;
+            Ok(store
+                .get(args[0].to_owned())?
+                .map_or(Reply::Nil, Reply::Bulk))
+        }
+        "set" => {
+            arity(2, None)?;
+            set(store, limits, args)
+        }
+        "del" => {
+            arity(1, None)?;
+            let mut removed = 0;
+            for key in args {
+                limits.check_key(key)?;
+                match store.remove(key.to_owned()) {
+                    Ok(()) => removed += 1,
+                    Err(KVSError::KeyNotFoundError) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        "exists" => {
+            arity(1, None)?;
+            let mut found = 0;
+            for key in args {
+                limits.check_key(key)?;
+                match store.ttl(key.to_owned()) {
+                    Ok(_) => found += 1,
+                    Err(KVSError::KeyNotFoundError) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        "mget" => {
+            arity(1, None)?;
+            let mut values = Vec::with_capacity(args.len());
+            for key in args {
+                limits.check_key(key)?;
+                values.push(store.get(key.to_owned())?.map_or(Reply::Nil, Reply::Bulk));
+            }
+            Ok(Reply::Array(values))
+        }
+        "mset" => {
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return Err(wrong_arity());
+            }
+            let mut batch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                limits.check_key(&pair[0])?;
+                limits.check_value(pair[1].len())?;
+                batch.put(pair[0].to_owned(), pair[1].to_owned());
+            }
+            store.apply_batch(batch)?;
+            Ok(Reply::Status("OK"))
+        }
+        "incr" => {
+            arity(1, Some(1))?;
+            limits.check_key(&args[0])?;
+            Ok(Reply::Integer(store.incr(args[0].to_owned(), 1)?))
+        }
+        "scan" => {
+            arity(1, None)?;
+            scan(store, state, limits, args)
+        }
+        "info" => {
+            arity(0, Some(1))?;
+            let section = args
+                .first()
+                .map(|arg| String::from_utf8_lossy(arg).to_lowercase());
+            Ok(Reply::Bulk(info(state, section.as_deref()).into_bytes()))
+        }
+        _ => Err(Reply::error(format!(
+            "ERR unknown command '{}'",
+            printable(&name)
+        ))),
+    }
+}
+
+/// Argument echoed in the error, with control characters escaped
+/// and cut to 128 characters like Redis does
+fn printable(arg: &str) -> String {
+    arg.chars()
+        .take(128)
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Condition of SET
+#[derive(Clone, Copy)]
+enum Condition {
+    /// NX, key must be missing
+    Absent,
+    /// XX, key must exist
+    Present,
+}
+
+/// SET key value [NX|XX] [EX seconds|PX milliseconds],
+/// Nil reply when the condition does not hold
+fn set<S: KvsEngine>(store: &S, limits: &FrameLimits, args: &[Vec<u8>]) -> CommandResult {
+    let (key, value) = (args[0].to_owned(), args[1].to_owned());
+    limits.check_key(&key)?;
+    limits.check_value(value.len())?;
+    let mut condition = None;
+    let mut ttl = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" if condition.is_none() => condition = Some(Condition::Absent),
+            b"XX" if condition.is_none() => condition = Some(Condition::Present),
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount = options.next().ok_or_else(syntax_error)?;
+                let amount = match parse_integer(amount)? {
+                    amount if amount > 0 => amount as u64,
+                    _ => return Err(Reply::error("ERR invalid expire time in 'set' command")),
+                };
+                ttl = Some(match unit {
+                    b"EX" => Duration::from_secs(amount),
+                    _ => Duration::from_millis(amount),
+                });
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let written = match (condition, ttl) {
+        (None, None) => store.set(key, value).map(|()| true)?,
+        (None, Some(ttl)) => store.set_with_ttl(key, value, ttl).map(|()| true)?,
+        (Some(Condition::Absent), None) => precondition(store.set_if_absent(key, value))?,
+        (Some(Condition::Absent), Some(ttl)) => {
+            precondition(store.set_if_absent_with_ttl(key, value, ttl))?
+        }
+        (Some(Condition::Present), ttl) => loop {
+            let version = match store.get_versioned(key.clone())? {
+                Some((_, version)) => version,
+                None => break false,
+            };
+            let res = match ttl {
+                Some(ttl) => {
+                    store.set_if_version_with_ttl(key.clone(), value.clone(), version, ttl)
+                }
+                None => store.set_if_version(key.clone(), value.clone(), version),
+            };
+            // the key was changed since it was read, check again
+            if precondition(res)? {
+                break true;
+            }
+        },
+    };
+    Ok(if written {
+        Reply::Status("OK")
+    } else {
+        Reply::Nil
+    })
+}
+
+/// Conditional write happened, failed condition is `false`
+fn precondition(res: Result<u64>) -> Result<bool> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(KVSError::PreconditionFailedError) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count], COUNT keys are examined
+/// in key order and those matching the pattern are returned.
+/// Cursor 0 starts the scan and is returned when it is done
+fn scan<S: KvsEngine>(
+    store: &S,
+    state: &RespState,
+    limits: &FrameLimits,
+    args: &[Vec<u8>],
+) -> CommandResult {
+    let cursor = match parse_integer(&args[0]) {
+        Ok(cursor) if cursor >= 0 => cursor as u64,
+        _ => return Err(Reply::error("ERR invalid cursor")),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(syntax_error)?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => {
+                limits.check_key(value)?;
+                pattern = Some(value.as_slice());
+            }
+            b"COUNT" => match parse_integer(value)? {
+                value if value > 0 => count = value as usize,
+                _ => return Err(syntax_error()),
+            },
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let mut start = match cursor {
+        0 => Vec::new(),
+        cursor => {
+            let cursors = state.cursors.lock().unwrap();
+            cursors
+                .resume(cursor)
+                .ok_or_else(|| Reply::error("ERR invalid cursor"))?
+        }
+    };
+    // keys matching the pattern start with its literal prefix
+    let prefix = pattern.map_or(&[][..], literal_prefix);
+    if start.as_slice() < prefix {
+        start = prefix.to_vec();
+    }
+    let entries = store.scan(start, prefix_end(prefix), count)?;
+
+    let next = match entries.last() {
+        Some((key, _)) if entries.len() == count => {
+            let mut start = key.to_owned();
+            start.push(0);
+            state.cursors.lock().unwrap().save(start)
+        }
+        _ => 0,
+    };
+    let keys = entries
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(Reply::Bulk)
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(next.to_string().into_bytes()),
+        Reply::Array(keys),
+    ]))
+}
+
+/// Part of the pattern before its first special character
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// First key after all keys starting with the prefix, `None` when there is no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&byte| byte != u8::MAX)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+/// Match text with glob-style pattern of Redis: `*`, `?`, `[abc]`, `[^a-z]`
+/// and `\` escaping the next character. Every element but `*` matches one byte,
+/// so on mismatch only the last `*` has to take one more byte: work is bounded
+/// by pattern length times text length, without exponential backtracking
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // pattern after the last `*` and text position it resumes from
+    let mut last_star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            last_star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = element_match(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        match last_star {
+            Some((after_star, from)) => {
+                p = after_star;
+                t = from + 1;
+                last_star = Some((after_star, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Length of the first pattern element when it matches the byte
+fn element_match(pattern: &[u8], byte: u8) -> Option<usize> {
+    let len = match pattern.split_first()? {
+        (b'*', _) => return None,
+        (b'?', _) => 1,
+        (b'[', class) if class.contains(&b']') => match class_match(class, byte) {
+            (true, len) => 1 + len,
+            (false, _) => return None,
+        },
+        (b'\\', [escaped, ..]) if *escaped == byte => 2,
+        (b'\\', [_, ..]) => return None,
+        (literal, _) if *literal == byte => 1,
+        _ => return None,
+    };
+    Some(len)
+}
+
+/// Whether byte is in the class following `[`, with length of the class and its `]`
+fn class_match(class: &[u8], byte: u8) -> (bool, usize) {
+    let negated = class.first() == Some(&b'^');
+    let mut at = usize::from(negated);
+    let mut matched = false;
+    while at < class.len() && class[at] != b']' {
+        match &class[at..] {
+            [b'\\', escaped, ..] => {
+                matched |= *escaped == byte;
+                at += 2;
+            }
+            [from, b'-', to, ..] if *to != b']' => {
+                let (low, high) = (*from.min(to), *from.max(to));
+                matched |= (low..=high).contains(&byte);
+                at += 3;
+            }
+            [literal, ..] => {
+                matched |= *literal == byte;
+                at += 1;
+            }
+            [] => break,
+        }
+    }
+    (matched != negated, (at + 1).min(class.len()))
+}
+
+/// Sections of INFO, `None` or "all" for all of them
+fn info(state: &RespState, section: Option<&str>) -> String {
+    let sections = [
+        (
+            "server",
+            format!(
+                "kvs_version:{}\r\nuptime_in_seconds:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                state.started.elapsed().as_secs()
+            ),
+        ),
+        (
+            "clients",
+            format!(
+                "connected_clients:{}\r\n",
+                state.connected.load(Ordering::SeqCst)
+            ),
+        ),
+        (
+            "stats",
+            format!(
+                "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n",
+                state.connections.load(Ordering::SeqCst),
+                state.commands.load(Ordering::SeqCst)
+            ),
+        ),
+    ];
+    sections
+        .iter()
+        .filter(|(name, _)| {
+            matches!(section, None | Some("all" | "default")) || section == Some(*name)
+        })
+        .map(|(name, fields)| {
+            format!(
+                "# {}{}\r\n{}",
+                &name[..1].to_uppercase(),
+                &name[1..],
+                fields
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+fn parse_integer(arg: &[u8]) -> std::result::Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| Reply::error("ERR value is not an integer or out of range"))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
@@ -1,51 +1,662 @@
-use crate::engine::KvsEngine;
-use crate::error::Result;
-use crate::tcp::protocol::DBCommands;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use crate::engine::{KvsEngine, ValueReader};
+use crate::error::{ErrorCode, KVSError, Result};
+use crate::tcp::http;
+use crate::tcp::memcached::{self, MemcachedState};
+use crate::tcp::protocol::{
+    read_opening, Capabilities, DBCommands, FrameLimits, Opening, RequestId, ServerResponse,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::tcp::resp::{self, RespState};
+use crate::tcp::session::{Session, Snapshots, DEFAULT_MAX_SNAPSHOTS, DEFAULT_SNAPSHOT_TTL};
+use crate::thread_pool::ThreadPool;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Connection without requests for this long is closed by the server
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most connections served at once, by all listeners together
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Connections over the limit waiting for their error reply,
+/// more are closed without it
+const MAX_REJECTED: usize = 64;
+/// Time the connection over the limit has to send its first request
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Options of the server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Close connections without requests for this long, `None` keeps them open
+    pub idle_timeout: Option<Duration>,
+    /// Most connections served at once, more get an error and are closed
+    pub max_connections: usize,
+    /// Largest keys, values and packets accepted from clients
+    pub limits: FrameLimits,
+    /// Release snapshots not used for this long
+    pub snapshot_ttl: Duration,
+    /// Most snapshots kept at once
+    pub max_snapshots: usize,
+    /// Address of the RESP listener for Redis clients, `None` disables it
+    pub resp_addr: Option<String>,
+    /// Address of the listener for memcached clients, `None` disables it
+    pub memcached_addr: Option<String>,
+    /// Let memcached clients run flush_all, which removes the keys
+    /// of all front ends from the shared engine
+    pub memcached_flush: bool,
+    /// Address of the HTTP/JSON gateway, `None` disables it
+    pub http_addr: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            limits: FrameLimits::default(),
+            snapshot_ttl: DEFAULT_SNAPSHOT_TTL,
+            max_snapshots: DEFAULT_MAX_SNAPSHOTS,
+            resp_addr: None,
+            memcached_addr: None,
+            memcached_flush: false,
+            http_addr: None,
+        }
+    }
+}
+
+/// Protocol spoken on the listener which accepted the connection
+#[derive(Debug, Clone, Copy)]
+enum Frontend {
+    Native,
+    Resp,
+    Memcached,
+    Http,
+}
 
 /// Struct for server with configurable backend (kvs or sled)
-pub struct KvsServer<S: KvsEngine> {
+/// and pool of threads serving requests.
+/// Every connection has own thread waiting for its requests until the client
+/// closes it or it is idle for the idle timeout, so open transactions live
+/// in the session of the connection. Requests are run in the pool, so idle
+/// connections hold no pool thread
+pub struct KvsServer<S: KvsEngine, P: ThreadPool> {
     addr: String,
     store: S,
+    pool: Arc<Mutex<P>>,
+    /// Number of connections being served
+    connections: Arc<AtomicUsize>,
+    snapshots: Arc<Snapshots<S>>,
+    resp_state: Arc<RespState>,
+    memcached_state: Arc<MemcachedState>,
+    config: ServerConfig,
 }
 
-impl<S: KvsEngine> KvsServer<S> {
-    /// Creates new server object with KvsEngine object
-    pub fn new(addr: String, store: S) -> Result<Self> {
-        let obj = KvsServer { addr, store };
+impl<S: KvsEngine, P: ThreadPool + Send + 'static> KvsServer<S, P> {
+    /// Creates new server object with KvsEngine object and default options,
+    /// idle connections are closed after `DEFAULT_IDLE_TIMEOUT`
+    pub fn new(addr: String, store: S, pool: P) -> Result<Self> {
+        Self::with_config(addr, store, pool, ServerConfig::default())
+    }
+
+    /// Creates new server closing connections idle for `idle_timeout`,
+    /// `None` keeps them open until the client closes them
+    pub fn with_idle_timeout(
+        addr: String,
+        store: S,
+        pool: P,
+        idle_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let config = ServerConfig {
+            idle_timeout,
+            ..ServerConfig::default()
+        };
+        Self::with_config(addr, store, pool, config)
+    }
+
+    /// Creates new server with given options
+    pub fn with_config(addr: String, store: S, pool: P, config: ServerConfig) -> Result<Self> {
+        let obj = KvsServer {
+            addr,
+            store,
+            pool: Arc::new(Mutex::new(pool)),
+            connections: Arc::new(AtomicUsize::new(0)),
+            snapshots: Arc::new(Snapshots::new(config.snapshot_ttl, config.max_snapshots)?),
+            resp_state: Arc::new(RespState::default()),
+            memcached_state: Arc::new(MemcachedState::new(config.memcached_flush)),
+            config,
+        };
         log::info!("Version -- {}", env!("CARGO_PKG_VERSION"));
         log::info!("Created KVSStore successful");
         Ok(obj)
     }
-    /// Run listener for incomming requests
-    pub fn listen(&mut self) {
-        let listener = TcpListener::bind(&self.addr).unwrap();
-        log::info!("Running Server on {}", &self.addr);
+    /// Run listener for incomming requests, and the RESP, memcached
+    /// and HTTP listeners when configured, every connection is served in own thread.
+    /// Fails when any of the listeners can't bind its address
+    pub fn listen(&self) -> Result<()> {
+        let mut listeners = vec![(Frontend::Native, bind(&self.addr, Frontend::Native)?)];
+        let optional = [
+            (Frontend::Resp, &self.config.resp_addr),
+            (Frontend::Memcached, &self.config.memcached_addr),
+            (Frontend::Http, &self.config.http_addr),
+        ];
+        for (frontend, addr) in optional {
+            if let Some(addr) = addr {
+                listeners.push((frontend, bind(addr, frontend)?));
+            }
+        }
+        let (sender, receiver) = mpsc::channel();
+        for (frontend, listener) in listeners {
+            accept(listener, frontend, sender.clone());
+        }
+        drop(sender);
+        let workers = Workers::new(Arc::clone(&self.pool));
+        let rejecter = spawn_rejecter(
+            self.config.max_connections,
+            self.config.limits.max_frame_size,
+        )?;
+        for (frontend, stream) in receiver {
+            self.serve(frontend, stream, &workers, &rejecter);
+        }
+        Ok(())
+    }
+
+    /// Serve the connection in own thread with the protocol of its listener,
+    /// connection over the limit is passed to the rejecter
+    fn serve(
+        &self,
+        frontend: Frontend,
+        stream: TcpStream,
+        workers: &Workers,
+        rejecter: &mpsc::SyncSender<(Frontend, TcpStream)>,
+    ) {
+        let max_connections = self.config.max_connections;
+        if self.connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            self.connections.fetch_sub(1, Ordering::SeqCst);
+            log::warn!(
+                "Rejecting {:?} connection over limit of {}",
+                frontend,
+                max_connections
+            );
+            if rejecter.try_send((frontend, stream)).is_err() {
+                log::warn!("Too many rejected connections, closing without reply");
+            }
+            return;
+        }
+        let guard = ConnectionGuard(Arc::clone(&self.connections));
+        let config = self.config.clone();
+        let workers = workers.clone();
+        let store = self.store.clone();
+        let snapshots = Arc::clone(&self.snapshots);
+        let resp_state = Arc::clone(&self.resp_state);
+        let memcached_state = Arc::clone(&self.memcached_state);
+        let spawned = thread::Builder::new().spawn(move || {
+            let _guard = guard;
+            let res = match frontend {
+                Frontend::Native => {
+                    let session = Session::new(store, snapshots);
+                    handle_connection(session, stream, &workers, &config)
+                }
+                Frontend::Resp => {
+                    resp::handle_connection(store, resp_state, stream, &workers, &config)
+                }
+                Frontend::Memcached => {
+                    memcached::handle_connection(store, memcached_state, stream, &workers, &config)
+                }
+                Frontend::Http => http::handle_connection(store, stream, &workers, &config),
+            };
+            if let Err(e) = res {
+                log::error!("Error serving {:?} connection: {}", frontend, e);
+            }
+        });
+        if let Err(e) = spawned {
+            log::error!("Cant spawn thread of {:?} connection: {}", frontend, e);
+        }
+    }
+}
+
+/// Releases place of the connection when its thread ends
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Handle of the pool for connection threads, which run their requests
+/// in the pool and wait for the result
+#[derive(Clone)]
+pub(crate) struct Workers(Arc<dyn Fn(Job) + Send + Sync>);
+
+impl Workers {
+    fn new<P: ThreadPool + Send + 'static>(pool: Arc<Mutex<P>>) -> Self {
+        Workers(Arc::new(move |job: Job| pool.lock().unwrap().spawn(job)))
+    }
+
+    /// Run the request in the pool and return its result
+    pub(crate) fn run<T, F>(&self, request: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        (self.0)(Box::new(move || {
+            let _ = sender.send(request());
+        }));
+        receiver.recv().map_err(|_| KVSError::ServerError {
+            code: ErrorCode::Internal,
+            message: "request panicked in the pool".to_owned(),
+        })
+    }
+}
+
+/// Thread answering connections over the limit with an error in the protocol
+/// of their listener, one by one, so they take no more threads
+fn spawn_rejecter(
+    max_connections: usize,
+    max_size: usize,
+) -> Result<mpsc::SyncSender<(Frontend, TcpStream)>> {
+    let (sender, receiver) = mpsc::sync_channel::<(Frontend, TcpStream)>(MAX_REJECTED);
+    thread::Builder::new().spawn(move || {
+        for (frontend, stream) in receiver {
+            if let Err(e) = reject_busy(frontend, stream, max_connections, max_size) {
+                log::debug!("Cant reject {:?} connection: {}", frontend, e);
+            }
+        }
+    })?;
+    Ok(sender)
+}
+
+/// Send `Busy` error and close the connection. Native clients get it
+/// in reply to their first packet, so it has the id they wait for
+fn reject_busy(
+    frontend: Frontend,
+    mut stream: TcpStream,
+    max_connections: usize,
+    max_size: usize,
+) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    stream.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let e = KVSError::ServerError {
+        code: ErrorCode::Busy,
+        message: format!("Too many connections, limit is {}", max_connections),
+    };
+    let packet = match frontend {
+        Frontend::Native => match read_opening(&mut reader, max_size)? {
+            Some(Opening::Hello { id, .. }) | Some(Opening::Request { id, .. }) => {
+                ServerResponse::error(&e).to_packet_with_id(id)?
+            }
+            Some(Opening::Unnumbered) => ServerResponse::error(&e).into_unnumbered_packet(),
+            None => return Ok(()),
+        },
+        Frontend::Resp => resp::error_reply(e),
+        Frontend::Memcached => memcached::error_reply(e),
+        Frontend::Http => http::error_reply(e),
+    };
+    reject(&mut reader, &mut stream, &packet)
+}
+
+/// Listener of the protocol bound to the address
+fn bind(addr: &str, frontend: Frontend) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        log::error!("Cant bind {:?} listener to {}: {}", frontend, addr, e);
+        e
+    })?;
+    log::info!("Running {:?} listener on {}", frontend, addr);
+    Ok(listener)
+}
+
+/// Accept connections of the listener in own thread and pass them
+/// to the server with the protocol of the listener
+fn accept(listener: TcpListener, frontend: Frontend, sender: mpsc::Sender<(Frontend, TcpStream)>) {
+    thread::spawn(move || {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    if let Err(e) = self.handle_connection(stream) {
-                        log::error!("Error serving command: {}", e);
-                    };
+                    if sender.send((frontend, stream)).is_err() {
+                        break;
+                    }
                 }
                 Err(e) => {
                     log::error!("Stream listener error: {}", e)
                 }
             }
         }
-    }
-    /// Parse request from stream, invoke command by engine and return response
-    fn handle_connection(&mut self, mut stream: TcpStream) -> Result<()> {
-        let cmd = DBCommands::from_stream(&mut stream)?;
-        log::debug!("Command - {:?}", cmd);
+    });
+}
+
+/// Serve requests of the connection until client closes it or it is idle
+/// for the idle timeout: agree on protocol version, then parse request
+/// from stream, invoke command in the session and return response
+/// with id of the request.
+/// Pipelined requests are served one by one in order they were sent
+fn handle_connection<S: KvsEngine>(
+    mut session: Session<S>,
+    mut stream: TcpStream,
+    workers: &Workers,
+    config: &ServerConfig,
+) -> Result<()> {
+    let max_size = config.limits.max_frame_size;
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (version, start) = match idle_as_closed(handshake(&mut reader, &mut stream, max_size))? {
+        Some(agreed) => agreed,
+        None => return Ok(()),
+    };
+    log::debug!("Protocol version {}", version);
+    // first request of version 1 client is partly read by the handshake
+    let mut reader = io::Cursor::new(start).chain(reader);
+
+    loop {
+        let (id, cmd) = match idle_as_closed(DBCommands::next_with_id_from_stream_with_limits(
+            &mut reader,
+            &config.limits,
+        )) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e @ KVSError::TooLargeError { .. }) => {
+                // rest of the packet is not read, so no other request can follow
+                log::warn!("Rejecting packet: {}", e);
+                reject(
+                    &mut reader,
+                    &mut stream,
+                    &ServerResponse::error(&e).to_packet()?,
+                )?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        log::debug!("Command {} - {:?}", id, cmd);
 
-        let resp = cmd.invoke_cmd(&mut self.store);
+        // streamed values go at the pace of the client,
+        // so they are moved on the connection thread, not in the pool
+        let resp = match (config.limits.check(&cmd), cmd) {
+            (Err(e), _) => ServerResponse::error(&e),
+            (Ok(()), DBCommands::SetStream { key, len, ttl }) => {
+                // client sends chunks of the size in the output
+                let ready = ServerResponse::Success {
+                    output: config.limits.chunk_size().to_string(),
+                };
+                write_response(&mut stream, id, ready, max_size)?;
+                let mut chunks = ChunkReader::new(&mut reader, len, &config.limits);
+                let resp = receive_value(&mut session, &mut chunks, key, len, ttl);
+                // rest of the value is read, so the next packet is a request
+                chunks.finish()?;
+                resp
+            }
+            (Ok(()), DBCommands::GetStream { key }) => {
+                send_value(&mut session, &mut stream, id, key, &config.limits)?;
+                continue;
+            }
+            (Ok(()), cmd) => {
+                // session goes to the pool with the command and comes back
+                let (returned, resp) = workers.run(move || {
+                    let resp = session.handle(cmd);
+                    (session, resp)
+                })?;
+                session = returned;
+                resp
+            }
+        };
         log::debug!("Result - {:?}", resp);
+        write_response(&mut stream, id, resp, max_size)?;
+    }
+    Ok(())
+}
 
-        let resp_bytes = resp.to_packet()?;
-        stream.write_all(&resp_bytes)?;
-        stream.flush()?;
-        Ok(())
+/// Send response, response larger than `max_size` is replaced by `TooLarge` failure
+fn write_response(
+    stream: &mut TcpStream,
+    id: RequestId,
+    resp: ServerResponse,
+    max_size: usize,
+) -> Result<()> {
+    let mut resp_bytes = resp.to_packet_with_id(id)?;
+    if resp_bytes.len() > max_size {
+        let e = KVSError::too_large("response", resp_bytes.len() as u64, max_size as u64);
+        resp_bytes = ServerResponse::error(&e).to_packet_with_id(id)?;
+    }
+    stream.write_all(&resp_bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Set streamed value in the engine as its chunks are read, or in the open
+/// transaction of the session, which holds it whole until commit
+fn receive_value<S: KvsEngine, R: Read>(
+    session: &mut Session<S>,
+    chunks: &mut ChunkReader<R>,
+    key: Vec<u8>,
+    len: u64,
+    ttl: Option<Duration>,
+) -> ServerResponse {
+    if session.in_transaction() {
+        let mut value = Vec::new();
+        return match chunks.read_to_end(&mut value) {
+            Ok(_) => session.handle(DBCommands::Set { key, value, ttl }),
+            Err(e) => ServerResponse::error(&e.into()),
+        };
+    }
+    match session.store().set_from_reader(key, chunks, len, ttl) {
+        Ok(()) => ServerResponse::success(),
+        Err(e) => ServerResponse::error(&e),
+    }
+}
+
+/// Send value of streamed get as `Stream` response followed by `Chunk` responses
+/// which fit the frame limit, read from the engine as they are sent.
+/// Failure of reading the value ends the chunks
+fn send_value<S: KvsEngine>(
+    session: &mut Session<S>,
+    stream: &mut TcpStream,
+    id: RequestId,
+    key: Vec<u8>,
+    limits: &FrameLimits,
+) -> Result<()> {
+    let max_size = limits.max_frame_size;
+    let found = if session.in_transaction() {
+        match session.handle(DBCommands::Get { key }) {
+            ServerResponse::Value { value } => {
+                let len = value.len() as u64;
+                Ok(Some((len, Box::new(io::Cursor::new(value)) as ValueReader)))
+            }
+            ServerResponse::NotFound => Ok(None),
+            resp => return write_response(stream, id, resp, max_size),
+        }
+    } else {
+        session.store().get_reader(key)
+    };
+    let (len, mut value) = match found {
+        Ok(Some(found)) => found,
+        Ok(None) => return write_response(stream, id, ServerResponse::NotFound, max_size),
+        Err(e) => return write_response(stream, id, ServerResponse::error(&e), max_size),
+    };
+    log::debug!("Result - stream of {} bytes", len);
+    write_response(stream, id, ServerResponse::Stream { len }, max_size)?;
+    let mut chunk = vec![0u8; len.min(limits.chunk_size() as u64) as usize];
+    let mut left = len;
+    while left > 0 {
+        let size = left.min(chunk.len() as u64) as usize;
+        if let Err(e) = value.read_exact(&mut chunk[..size]) {
+            log::error!("Cant read streamed value: {}", e);
+            let resp = ServerResponse::error(&e.into());
+            return write_response(stream, id, resp, max_size);
+        }
+        let data = chunk[..size].to_vec();
+        write_response(stream, id, ServerResponse::Chunk { data }, max_size)?;
+        left -= size as u64;
+    }
+    Ok(())
+}
+
+/// Value of streamed set read from `Chunk` packets as they arrive.
+/// Chunk which does not fit the value or other packet breaks the stream,
+/// which closes the connection once the value is taken
+struct ChunkReader<'a, R> {
+    reader: &'a mut R,
+    limits: &'a FrameLimits,
+    /// Bytes of the value not received yet
+    left: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    broken: Option<KVSError>,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    fn new(reader: &'a mut R, len: u64, limits: &'a FrameLimits) -> Self {
+        ChunkReader {
+            reader,
+            limits,
+            left: len,
+            chunk: Vec::new(),
+            pos: 0,
+            broken: None,
+        }
+    }
+
+    /// Receive chunks the engine did not read, fail when the stream is broken
+    fn finish(mut self) -> Result<()> {
+        while self.broken.is_none() && self.left > 0 {
+            self.next_chunk();
+        }
+        match self.broken {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn next_chunk(&mut self) {
+        let next = DBCommands::next_with_id_from_stream_with_limits(self.reader, self.limits);
+        match next {
+            Ok(Some((_, DBCommands::Chunk { data }))) if data.len() as u64 <= self.left => {
+                self.left -= data.len() as u64;
+                self.chunk = data;
+                self.pos = 0;
+            }
+            Ok(Some(_)) => {
+                self.broken = Some(KVSError::protocol("expected chunk of streamed value"))
+            }
+            Ok(None) => {
+                self.broken = Some(KVSError::protocol("connection closed in streamed value"))
+            }
+            Err(e) => self.broken = Some(e),
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() && self.left > 0 && self.broken.is_none() {
+            self.next_chunk();
+        }
+        if let Some(e) = &self.broken {
+            return Err(io::Error::new(ErrorKind::InvalidData, e.to_string()));
+        }
+        let size = buf.len().min(self.chunk.len() - self.pos);
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+/// Answer HELLO of the client with agreed version and capabilities.
+/// Client of version 1 sends requests without HELLO, it gets version 1
+/// and the bytes of its first request read so far.
+/// `None` when the client is rejected or closed the connection
+fn handshake<R: Read>(
+    reader: &mut R,
+    stream: &mut TcpStream,
+    max_size: usize,
+) -> Result<Option<(u16, Vec<u8>)>> {
+    let (id, version, capabilities) = match read_opening(reader, max_size)? {
+        Some(Opening::Hello {
+            id,
+            version,
+            capabilities,
+        }) => (id, version, capabilities),
+        Some(Opening::Request { start, .. }) => return Ok(Some((1, start))),
+        Some(Opening::Unnumbered) => {
+            log::warn!("Rejecting client without request ids");
+            let message = format!(
+                "Client must start with HELLO, server speaks protocol versions {} to {}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            );
+            let resp = ServerResponse::failure(ErrorCode::UnsupportedVersion, &message);
+            reject(reader, stream, &resp.into_unnumbered_packet())?;
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+    if version < MIN_PROTOCOL_VERSION {
+        log::warn!("Rejecting client of protocol version {}", version);
+        let message = format!(
+            "Protocol version {} is not supported, server speaks versions {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        let resp = ServerResponse::failure(ErrorCode::UnsupportedVersion, &message);
+        reject(reader, stream, &resp.to_packet_with_id(id)?)?;
+        return Ok(None);
+    }
+
+    let version = version.min(PROTOCOL_VERSION);
+    let resp = ServerResponse::Hello {
+        version,
+        capabilities: capabilities.intersection(Capabilities::SUPPORTED),
+    };
+    stream.write_all(&resp.to_packet_with_id(id)?)?;
+    stream.flush()?;
+    Ok(Some((version, Vec::new())))
+}
+
+/// Send rejection and close the connection. Rest of the request is read first,
+/// otherwise the client may get reset before reading the rejection
+pub(crate) fn reject<R: Read>(reader: &mut R, stream: &mut TcpStream, packet: &[u8]) -> Result<()> {
+    stream.write_all(packet)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    let _ = io::copy(&mut reader.take(64 * 1024), &mut io::sink());
+    Ok(())
+}
+
+/// Line of text protocols without CRLF, `None` at the end of the stream.
+/// Line longer than `max_size` is rejected before it is read
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = max_size.saturating_add(2) as u64;
+    reader.take(limit).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() as u64 + 1 >= limit {
+            return Err(KVSError::too_large(
+                "request line",
+                line.len() as u64 + 1,
+                max_size as u64,
+            ));
+        }
+        return Err(KVSError::protocol("connection closed"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Connection idle for the read timeout counts as closed
+pub(crate) fn idle_as_closed<T>(res: Result<Option<T>>) -> Result<Option<T>> {
+    match res {
+        Err(KVSError::IOError { source, .. })
+            if matches!(source.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+        {
+            log::debug!("Closing idle connection");
+            Ok(None)
+        }
+        res => res,
     }
 }
@@ -0,0 +1,260 @@
+// #![deny(missing_docs)]
+//! State of the client connection and snapshots shared by all connections
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::engine::KvsEngine;
+use crate::error::{ErrorCode, Result};
+use crate::expiry::{Sweeper, DEFAULT_SWEEP_INTERVAL};
+use crate::snapshot::KvsSnapshot;
+use crate::tcp::protocol::{DBCommands, ServerResponse};
+use crate::transaction::Transaction;
+
+/// Snapshot not used for this long is released by the server
+pub const DEFAULT_SNAPSHOT_TTL: Duration = Duration::from_secs(5 * 60);
+/// Most snapshots the server keeps at once
+pub const DEFAULT_MAX_SNAPSHOTS: usize = 64;
+
+/// Snapshot with the end of its lease, every use of the snapshot renews it
+struct Lease<T> {
+    snapshot: T,
+    expires: Instant,
+}
+
+type Leases<T> = Arc<Mutex<HashMap<u64, Lease<T>>>>;
+
+/// Snapshots of the server by id, so a snapshot taken on one connection
+/// can be read on another. Ids are random, so other clients cant guess them.
+/// Snapshot lives until released, or until it is not used for its TTL,
+/// and only a limited number of them live at once
+pub struct Snapshots<S: KvsEngine> {
+    live: Leases<S::Snapshot>,
+    /// Keyed hasher turning sequence numbers into ids
+    ids: RandomState,
+    taken: AtomicU64,
+    ttl: Duration,
+    max_count: usize,
+    /// Held only to stop the sweeper with the server
+    _sweeper: Sweeper,
+}
+
+impl<S: KvsEngine> Snapshots<S> {
+    /// Create registry releasing snapshots unused for `ttl`
+    /// and keeping at most `max_count` of them
+    pub fn new(ttl: Duration, max_count: usize) -> Result<Self> {
+        let live: Leases<S::Snapshot> = Arc::new(Mutex::new(HashMap::new()));
+        let sweeper = {
+            let live = Arc::clone(&live);
+            Sweeper::spawn(DEFAULT_SWEEP_INTERVAL, move || {
+                Ok(remove_expired(&mut live.lock().unwrap()))
+            })?
+        };
+        Ok(Snapshots {
+            live,
+            ids: RandomState::new(),
+            taken: AtomicU64::new(0),
+            ttl,
+            max_count,
+            _sweeper: sweeper,
+        })
+    }
+
+    /// Invoke snapshot command, reads go to a clone of the snapshot without lock
+    fn invoke_cmd(&self, store: &S, cmd: DBCommands) -> ServerResponse {
+        match cmd {
+            DBCommands::Snapshot => match self.take(store) {
+                Ok(Some(id)) => ServerResponse::Snapshot { id },
+                Ok(None) => ServerResponse::failure(
+                    ErrorCode::InvalidCommand,
+                    "Too many live snapshots, release one first",
+                ),
+                Err(e) => ServerResponse::error(&e),
+            },
+            DBCommands::ReleaseSnapshot { id } => {
+                let now = Instant::now();
+                match self.live.lock().unwrap().remove(&id) {
+                    Some(lease) if lease.expires > now => ServerResponse::success(),
+                    _ => snapshot_not_found(),
+                }
+            }
+            DBCommands::SnapshotGet { id, key } => match self.get(id).map(|s| s.get(key)) {
+                Some(Ok(Some(value))) => ServerResponse::Value { value },
+                Some(Ok(None)) => ServerResponse::NotFound,
+                Some(Err(e)) => ServerResponse::error(&e),
+                None => snapshot_not_found(),
+            },
+            DBCommands::SnapshotScan {
+                id,
+                start,
+                end,
+                limit,
+            } => match self.get(id) {
+                Some(snapshot) => entries_response(snapshot.scan(start, end, limit as usize)),
+                None => snapshot_not_found(),
+            },
+            DBCommands::SnapshotScanPrefix { id, prefix, limit } => match self.get(id) {
+                Some(snapshot) => entries_response(snapshot.scan_prefix(prefix, limit as usize)),
+                None => snapshot_not_found(),
+            },
+            cmd => cmd.invoke_cmd(store),
+        }
+    }
+
+    /// Take snapshot and register it under new id, `None` when too many are live.
+    /// Snapshot is taken without lock, as it may take a while
+    fn take(&self, store: &S) -> Result<Option<u64>> {
+        if self.is_full(&mut self.live.lock().unwrap()) {
+            return Ok(None);
+        }
+        let snapshot = store.snapshot()?;
+        let mut live = self.live.lock().unwrap();
+        // others could take snapshots meanwhile
+        if self.is_full(&mut live) {
+            return Ok(None);
+        }
+        let id = loop {
+            let id = self.ids.hash_one(self.taken.fetch_add(1, Ordering::SeqCst));
+            if id != 0 && !live.contains_key(&id) {
+                break id;
+            }
+        };
+        let expires = Instant::now() + self.ttl;
+        live.insert(id, Lease { snapshot, expires });
+        Ok(Some(id))
+    }
+
+    fn is_full(&self, live: &mut HashMap<u64, Lease<S::Snapshot>>) -> bool {
+        remove_expired(live);
+        live.len() >= self.max_count
+    }
+
+    /// Snapshot by id with its lease renewed
+    fn get(&self, id: u64) -> Option<S::Snapshot> {
+        let now = Instant::now();
+        let mut live = self.live.lock().unwrap();
+        match live.get_mut(&id) {
+            Some(lease) if lease.expires > now => {
+                lease.expires = now + self.ttl;
+                Some(lease.snapshot.clone())
+            }
+            Some(_) => {
+                live.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Release snapshots with ended lease, return their number
+fn remove_expired<T>(live: &mut HashMap<u64, Lease<T>>) -> usize {
+    let now = Instant::now();
+    let count = live.len();
+    live.retain(|_, lease| lease.expires > now);
+    count - live.len()
+}
+
+/// Commands of one connection, holding its open transaction.
+/// Transaction left open when the connection closes is discarded
+pub struct Session<S: KvsEngine> {
+    store: S,
+    snapshots: Arc<Snapshots<S>>,
+    transaction: Option<Transaction<S>>,
+}
+
+impl<S: KvsEngine> Session<S> {
+    /// Create session without transaction
+    pub fn new(store: S, snapshots: Arc<Snapshots<S>>) -> Self {
+        Session {
+            store,
+            snapshots,
+            transaction: None,
+        }
+    }
+
+    /// Engine of the session
+    pub(crate) fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Whether commands go to an open transaction
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Invoke command in the open transaction if any, otherwise on the store.
+    /// Snapshot commands do not depend on the transaction
+    pub fn handle(&mut self, cmd: DBCommands) -> ServerResponse {
+        match cmd {
+            DBCommands::Snapshot
+            | DBCommands::ReleaseSnapshot { .. }
+            | DBCommands::SnapshotGet { .. }
+            | DBCommands::SnapshotScan { .. }
+            | DBCommands::SnapshotScanPrefix { .. } => self.snapshots.invoke_cmd(&self.store, cmd),
+            cmd => self.handle_in_transaction(cmd),
+        }
+    }
+
+    fn handle_in_transaction(&mut self, cmd: DBCommands) -> ServerResponse {
+        match (cmd, self.transaction.as_mut()) {
+            (DBCommands::Begin, None) => {
+                self.transaction = Some(self.store.begin());
+                ServerResponse::success()
+            }
+            (DBCommands::Begin, Some(_)) => {
+                ServerResponse::failure(ErrorCode::InvalidCommand, "Transaction already started")
+            }
+            (DBCommands::Commit | DBCommands::Abort, None) => {
+                ServerResponse::failure(ErrorCode::InvalidCommand, "No transaction started")
+            }
+            (DBCommands::Commit, Some(_)) => match self.transaction.take().unwrap().commit() {
+                Ok(()) => ServerResponse::success(),
+                Err(e) => ServerResponse::error(&e),
+            },
+            (DBCommands::Abort, Some(_)) => {
+                self.transaction = None;
+                ServerResponse::success()
+            }
+            (DBCommands::Get { key }, Some(transaction)) => match transaction.get(key) {
+                Ok(Some(value)) => ServerResponse::Value { value },
+                Ok(None) => ServerResponse::NotFound,
+                Err(e) => ServerResponse::error(&e),
+            },
+            (
+                DBCommands::Set {
+                    key,
+                    value,
+                    ttl: None,
+                },
+                Some(transaction),
+            ) => {
+                transaction.set(key, value);
+                ServerResponse::success()
+            }
+            (DBCommands::Rm { key }, Some(transaction)) => {
+                transaction.remove(key);
+                ServerResponse::success()
+            }
+            (_, Some(_)) => ServerResponse::failure(
+                ErrorCode::InvalidCommand,
+                "Command is not supported in transaction",
+            ),
+            (cmd, None) => cmd.invoke_cmd(&self.store),
+        }
+    }
+}
+
+fn entries_response(res: Result<Vec<(Vec<u8>, Vec<u8>)>>) -> ServerResponse {
+    match res {
+        Ok(entries) => ServerResponse::Entries { entries },
+        Err(e) => ServerResponse::error(&e),
+    }
+}
+
+fn snapshot_not_found() -> ServerResponse {
+    ServerResponse::failure(ErrorCode::SnapshotNotFound, "Snapshot not found")
+}
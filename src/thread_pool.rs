@@ -0,0 +1,13 @@
+use crate::error::Result;
+
+/// General interface for pools which run server jobs
+pub trait ThreadPool {
+    /// Creates new pool with given number of threads
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+    /// Run job in the pool, panic inside job must not break the pool
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,23 @@
+// #![deny(missing_docs)]
+//! Thread per job pool
+use std::thread;
+
+use crate::error::Result;
+use crate::thread_pool::ThreadPool;
+
+/// Not really a pool, spawns new thread for every job
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    /// Number of threads is ignored
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+    /// Spawn new thread for the job
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,27 @@
+// #![deny(missing_docs)]
+//! Pool on top of rayon
+use crate::error::Result;
+use crate::thread_pool::ThreadPool;
+
+/// Wrapper of the rayon work stealing pool
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    /// Build rayon pool with `threads` workers
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| log::error!("Job of the rayon pool panicked"))
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+    /// Spawn job into rayon pool
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job)
+    }
+}
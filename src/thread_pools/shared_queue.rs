@@ -0,0 +1,68 @@
+// #![deny(missing_docs)]
+//! Fixed size pool with one shared job queue
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::Result;
+use crate::thread_pool::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Pool of fixed number of workers taking jobs from the shared queue.
+/// Worker which panicked is replaced by the new one
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Start `threads` workers
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let job_receiver = JobReceiver(Arc::clone(&receiver));
+            thread::Builder::new().spawn(move || run_jobs(job_receiver))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+    /// Put job to the queue
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("All workers of the pool are dead");
+    }
+}
+
+/// Worker side of the queue, respawns worker on panic
+#[derive(Clone)]
+struct JobReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for JobReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let job_receiver = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_jobs(job_receiver)) {
+                log::error!("Cant respawn pool worker: {}", e);
+            }
+        }
+    }
+}
+
+/// Take jobs from queue until pool is dropped
+fn run_jobs(job_receiver: JobReceiver) {
+    loop {
+        // lock is released before job runs, so panic wont poison it
+        let job = job_receiver.0.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => {
+                log::debug!("Pool is dropped, worker exits");
+                break;
+            }
+        }
+    }
+}
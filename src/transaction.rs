@@ -0,0 +1,91 @@
+// #![deny(missing_docs)]
+//! Optimistic transactions over any engine
+use std::collections::BTreeMap;
+
+use crate::batch::WriteBatch;
+use crate::engine::KvsEngine;
+use crate::error::Result;
+
+/// How many times `KvsEngine::transact` runs the transaction before giving up on conflicts
+pub const DEFAULT_TRANSACTION_ATTEMPTS: usize = 10;
+
+/// Read-modify-write over several keys
+///
+/// Writes are buffered until commit and visible to reads of the same
+/// transaction. Commit applies them atomically only when no key read by the
+/// transaction was changed meanwhile, otherwise it fails with
+/// `TransactionConflictError` and nothing is written.
+/// Dropping the transaction without commit discards its writes.
+///
+/// ```rust
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// use kvs::{KvStore, KvsEngine};
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set(b"from".to_vec(), b"10".to_vec())?;
+///
+/// let mut transaction = store.begin();
+/// let from = transaction.get(b"from".to_vec())?;
+/// assert_eq!(from, Some(b"10".to_vec()));
+/// transaction.set(b"from".to_vec(), b"0".to_vec());
+/// transaction.set(b"to".to_vec(), b"10".to_vec());
+/// transaction.commit()?;
+///
+/// assert_eq!(store.get(b"to".to_vec())?, Some(b"10".to_vec()));
+/// #     Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<S: KvsEngine> {
+    store: S,
+    /// Version of every read key as first seen, `None` for missing key
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    /// Last write of every key, `None` for removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<S: KvsEngine> Transaction<S> {
+    /// Start transaction over the store
+    pub fn begin(store: S) -> Self {
+        Transaction {
+            store,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Get value by key, seeing writes of this transaction
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let versioned = self.store.get_versioned(key.clone())?;
+        let version = versioned.as_ref().map(|(_, version)| *version);
+        self.reads.entry(key).or_insert(version);
+        Ok(versioned.map(|(value, _)| value))
+    }
+
+    /// Set value by key on commit
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove key on commit, missing key is not an error
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Apply writes atomically if keys read were not changed by anybody else
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        self.store
+            .commit_transaction(self.reads.into_iter().collect(), batch)
+    }
+}
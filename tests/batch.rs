@@ -0,0 +1,121 @@
+use kvs::{KvStore, KvsEngine, Result, SledStore, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// Writes of the batch should be applied in order on any engine
+fn check_batch<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(b"old".to_vec(), b"value".to_vec())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"key1".to_vec(), b"value1".to_vec())
+        .put(b"key2".to_vec(), b"value2".to_vec())
+        .put(b"key3".to_vec(), b"value3".to_vec())
+        .delete(b"key3".to_vec())
+        .delete(b"old".to_vec())
+        .delete(b"missing".to_vec())
+        .put(b"key1".to_vec(), b"value1b".to_vec());
+    store.apply_batch(batch)?;
+
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1b".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, None);
+    assert_eq!(store.get(b"old".to_vec())?, None);
+
+    store.apply_batch(WriteBatch::new())?;
+    store.remove(b"key2".to_vec())?;
+    assert_eq!(
+        store.scan(Vec::new(), None, 10)?,
+        vec![(b"key1".to_vec(), b"value1b".to_vec())]
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1b".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"old".to_vec())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(&SledStore::open(temp_dir.path())?)
+}
+
+fn last_log(path: &Path) -> Result<PathBuf> {
+    let mut logs: Vec<(u64, PathBuf)> = fs::read_dir(path)?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter(|path| fs::metadata(path).unwrap().len() > 5)
+        .map(|path| {
+            let gen = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (gen, path)
+        })
+        .collect();
+    logs.sort();
+    Ok(logs.pop().expect("log with records").1)
+}
+
+// Batch interrupted in the middle of write should be dropped as a whole
+#[test]
+fn torn_batch_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"key1".to_vec(), b"value2".to_vec())
+        .put(b"key2".to_vec(), b"value2".to_vec());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // cut the batch record after its first write
+    let path = last_log(temp_dir.path())?;
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 20)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    Ok(())
+}
+
+// Values written by batches should survive compaction
+#[test]
+fn batch_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..200 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..100 {
+            batch.put(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter).into_bytes(),
+            );
+        }
+        batch.delete(b"key0".to_vec());
+        store.apply_batch(batch)?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key0".to_vec())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{:0>100}", 199).into_bytes())
+        );
+    }
+    Ok(())
+}
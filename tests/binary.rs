@@ -0,0 +1,46 @@
+use kvs::{KvStore, KvsEngine, Result, SledStore};
+use tempfile::TempDir;
+
+// Not UTF-8 bytes, including zero and 0xff which starts value headers of sled
+const KEY: &[u8] = &[0xff, 0x00, 0xc3, 0x28];
+const VALUE: &[u8] = &[0xff, 0xfe, 0x00, 0x01, 0x80];
+
+// Keys and values should come back byte to byte on any engine
+fn check_binary<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(KEY.to_vec(), VALUE.to_vec())?;
+    store.set(b"empty".to_vec(), Vec::new())?;
+    store.set(vec![0xff], vec![0x00])?;
+
+    assert_eq!(store.get(KEY.to_vec())?, Some(VALUE.to_vec()));
+    assert_eq!(store.get(b"empty".to_vec())?, Some(Vec::new()));
+    assert_eq!(
+        store.scan_prefix(vec![0xff], 10)?,
+        vec![(vec![0xff], vec![0x00]), (KEY.to_vec(), VALUE.to_vec())]
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_binary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_binary(&store)?;
+    // enough stale data to compact binary keys into hint file
+    for iter in 0..200 {
+        for key_id in 0u8..100 {
+            store.set(vec![0xfe, key_id], vec![iter as u8; 100])?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(KEY.to_vec())?, Some(VALUE.to_vec()));
+    assert_eq!(store.get(vec![0xfe, 7])?, Some(vec![199; 100]));
+    Ok(())
+}
+
+#[test]
+fn sled_binary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary(&SledStore::open(temp_dir.path())?)
+}
@@ -1,6 +1,7 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpListener;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +200,93 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// Data should go into the data directory, leaving files of the working directory alone
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("notes.compact"), b"notes").unwrap();
+    fs::write(temp_dir.path().join("1.hint"), b"not a hint").unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4039"])
+        .args(["--data-dir", "store"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4039", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+
+    assert!(temp_dir.path().join("notes.compact").is_file());
+    assert!(temp_dir.path().join("1.hint").is_file());
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(temp_dir.path().join("store").join("1.log").is_file());
+
+    // engine is kept with the data
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4039"])
+        .args(["--data-dir", "store"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Server should fail to start when any of its listeners can't bind the address
+#[test]
+fn cli_bind_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    let _taken = TcpListener::bind("127.0.0.1:4045").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4044"])
+        .args(["--resp-addr", "127.0.0.1:4045"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("127.0.0.1:4045"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +294,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +302,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +310,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +318,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +326,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +334,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +342,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +355,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +393,663 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_scan_server() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in [("user:2", "b"), ("user:1", "a"), ("other", "c")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan-prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\ta\nuser:2\tb\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan", "a", "user:2", "--limit", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("other\tc\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_ttl_server() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1", "--ttl", "500ms"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key2", "value2", "--ttl", "30s"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("29."));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiration\n");
+
+    thread::sleep(Duration::from_millis(600));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key4", "value4", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_binary_server() {
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--input", "hex", "set", "ff00", "c328ff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "--input", "hex", "--output", "hex", "get", "ff00",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c328ff\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "get", "/wA=", "--input", "base64", "--output", "base64",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("wyj/\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "--input", "hex", "--output", "hex", "scan", "00",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ff00\tc328ff\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--input", "hex", "get", "not-hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid hex"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_batch_server() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "batch", "set", "key1", "value1", "set", "key2", "value2", "rm", "key3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "batch", "set", "key4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Missing value"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_versions_server() {
+    let addr = "127.0.0.1:4010";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Precondition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-version", "key1", "value2", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get-versioned", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\t2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key1",
+            "--expected",
+            "value1",
+            "--new",
+            "value3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Precondition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key1",
+            "--expected",
+            "value2",
+            "--new",
+            "value3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "cas", "key1", "--expected", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get-versioned", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_txn_server() {
+    let addr = "127.0.0.1:4012";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "txn", "get", "key1", "set", "key2", "value2", "rm", "key1", "get",
+            "key1", "get", "key2",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "txn", "get"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Transaction expects"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_snapshot_server() {
+    let addr = "127.0.0.1:4013";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // snapshot outlives the connection which took it
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "snapshot"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let id = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+    id.parse::<u64>().expect("snapshot id is not a number");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr", addr, "batch", "set", "key1", "value2", "set", "key2", "value2",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1", "--snapshot", &id])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2", "--snapshot", &id])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan", "key", "--snapshot", &id])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan-prefix", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\nkey2\tvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "release-snapshot", &id])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1", "--snapshot", &id])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Snapshot not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_incr_server() {
+    let addr = "127.0.0.1:4014";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "counter", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("11\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "counter", "-20"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-9\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "decr", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-10\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-10\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Exit code of the client should tell the class of the error
+#[test]
+fn cli_exit_codes() {
+    let addr = "127.0.0.1:4015";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--input", "hex", "get", "not-hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr("Precondition failed\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(5)
+        .stderr(contains("not an integer"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "release-snapshot", "7"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(5)
+        .stderr("Snapshot not found\n");
+
+    // nothing listens there
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4099", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(6);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
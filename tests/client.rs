@@ -0,0 +1,410 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    Capabilities, DBCommands, ErrorCode, KVSClient, KVSError, Result, ServerResponse,
+    PROTOCOL_VERSION,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Server answering HELLO and then success to every command, closing each connection
+// after `per_connection` commands. Return its address and counter of accepted connections
+fn fake_server(per_connection: usize) -> (String, Arc<AtomicUsize>) {
+    let (addr, accepted, _) = lossy_server(per_connection, false);
+    (addr, accepted)
+}
+
+// Fake server which also reads one more command after the answered ones and closes
+// the connection without answering it when `lose_last`. Counter of received commands
+// is returned too
+fn lossy_server(
+    per_connection: usize,
+    lose_last: bool,
+) -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let received = Arc::new(AtomicUsize::new(0));
+    let (counter, commands) = (Arc::clone(&accepted), Arc::clone(&received));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let (id, _) = DBCommands::from_stream_with_id(&mut stream).unwrap();
+            let hello = ServerResponse::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::SUPPORTED,
+            };
+            stream
+                .write_all(&hello.to_packet_with_id(id).unwrap())
+                .unwrap();
+            for _ in 0..per_connection {
+                match DBCommands::next_with_id_from_stream(&mut stream) {
+                    Ok(Some((id, _))) => {
+                        commands.fetch_add(1, Ordering::SeqCst);
+                        let resp = ServerResponse::success().to_packet_with_id(id).unwrap();
+                        stream.write_all(&resp).unwrap();
+                    }
+                    _ => break,
+                }
+            }
+            if lose_last {
+                if let Ok(Some(_)) = DBCommands::next_with_id_from_stream(&mut stream) {
+                    commands.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+    });
+    (addr, accepted, received)
+}
+
+fn set(client: &mut KVSClient, key: &str) -> Result<ServerResponse> {
+    client.request(DBCommands::Set {
+        key: key.as_bytes().to_vec(),
+        value: b"value".to_vec(),
+        ttl: None,
+    })
+}
+
+// All commands of the client should share one connection
+#[test]
+fn client_reuses_connection() -> Result<()> {
+    let (addr, accepted) = fake_server(usize::MAX);
+    let mut client = KVSClient::new(addr)?;
+    for i in 0..100 {
+        set(&mut client, &format!("key{}", i))?;
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+// Command should be sent again on a new connection when the server closed the old one
+#[test]
+fn client_reconnects_after_close() -> Result<()> {
+    let (addr, accepted) = fake_server(3);
+    let mut client = KVSClient::new(addr)?;
+    for i in 0..10 {
+        set(&mut client, &format!("key{}", i))?;
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    Ok(())
+}
+
+// Command lost with the connection after it was written should be sent again
+// only when running it twice does no harm
+#[test]
+fn client_resends_idempotent_commands() -> Result<()> {
+    let incr = || DBCommands::Incr {
+        key: b"counter".to_vec(),
+        delta: 1,
+    };
+
+    // connection closed before the command is written is replaced first
+    let (addr, accepted) = fake_server(1);
+    let mut client = KVSClient::new(addr)?;
+    set(&mut client, "key")?;
+    thread::sleep(Duration::from_millis(100));
+    client.request(incr())?;
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    let (addr, accepted, received) = lossy_server(1, true);
+    let mut client = KVSClient::new(addr)?;
+    set(&mut client, "key")?;
+    match client.request(incr()) {
+        Err(KVSError::IOError { source, .. }) => {
+            assert_eq!(source.kind(), ErrorKind::ConnectionAborted)
+        }
+        res => panic!("lost increment returned {:?}", res),
+    }
+    assert_eq!(received.load(Ordering::SeqCst), 2);
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    set(&mut client, "key")?;
+    client.request(DBCommands::Get {
+        key: b"key".to_vec(),
+    })?;
+    assert_eq!(received.load(Ordering::SeqCst), 5);
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+// Missing key should be `None` for library users, even when some value
+// looks like the message printed by the CLI
+#[test]
+fn client_get_missing_key() -> Result<()> {
+    let addr = "127.0.0.1:4017";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    client.request(DBCommands::Set {
+        key: b"key1".to_vec(),
+        value: b"Key not found".to_vec(),
+        ttl: None,
+    })?;
+    client.request(DBCommands::Set {
+        key: b"key2".to_vec(),
+        value: Vec::new(),
+        ttl: None,
+    })?;
+
+    assert_eq!(
+        client.get(b"key1".to_vec())?,
+        Some(b"Key not found".to_vec())
+    );
+    assert_eq!(client.get(b"key2".to_vec())?, Some(Vec::new()));
+    assert_eq!(client.get(b"key3".to_vec())?, None);
+    let (value, _) = client.get_versioned(b"key1".to_vec())?.unwrap();
+    assert_eq!(value, b"Key not found".to_vec());
+    assert_eq!(client.get_versioned(b"key3".to_vec())?, None);
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Server should close connections without requests for the idle timeout,
+// the client reconnects unless the lost connection had an open transaction
+#[test]
+fn server_idle_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4018";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--threads",
+            "4",
+            "--idle-timeout",
+            "300ms",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut raw = TcpStream::connect(addr)?;
+    raw.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = [0u8; 1];
+    assert_eq!(raw.read(&mut buf)?, 0, "idle connection is not closed");
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    set(&mut client, "key1")?;
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(client.get(b"key1".to_vec())?, Some(b"value".to_vec()));
+
+    client.request(DBCommands::Begin)?;
+    set(&mut client, "key2")?;
+    thread::sleep(Duration::from_secs(1));
+    match client.request(DBCommands::Commit) {
+        Err(KVSError::IOError { source, .. }) => {
+            assert_eq!(source.kind(), ErrorKind::ConnectionAborted)
+        }
+        res => panic!("commit of lost transaction returned {:?}", res),
+    }
+    assert_eq!(client.get(b"key2".to_vec())?, None);
+    client.request(DBCommands::Commit).unwrap_err();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Idle connections of all front ends should not keep other clients waiting
+// for a pool thread, connections over the limit get an error and are closed
+#[test]
+fn idle_connections_over_threads() -> Result<()> {
+    let addr = "127.0.0.1:4041";
+    let resp_addr = "127.0.0.1:4042";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--resp-addr", resp_addr])
+        .args(["--threads", "2", "--max-connections", "6"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut idle = Vec::new();
+    for _ in 0..2 {
+        idle.push(TcpStream::connect(addr)?);
+        idle.push(TcpStream::connect(resp_addr)?);
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    let (done, served) = mpsc::channel();
+    thread::spawn(move || {
+        let mut client = KVSClient::new(addr.to_owned()).unwrap();
+        let res = set(&mut client, "key").and_then(|_| client.get(b"key".to_vec()));
+        done.send(res.map_err(|e| e.to_string())).unwrap();
+        thread::sleep(Duration::from_secs(5)); // keep the connection open
+    });
+    let value = served
+        .recv_timeout(Duration::from_secs(5))
+        .expect("client waits behind idle connections");
+    assert_eq!(value.unwrap(), Some(b"value".to_vec()));
+
+    let ping = |stream: &mut TcpStream| -> Result<Vec<u8>> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"PING\r\n")?;
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf)?;
+        Ok(buf[..n].to_vec())
+    };
+    let mut sixth = TcpStream::connect(resp_addr)?;
+    assert_eq!(ping(&mut sixth)?, b"+PONG\r\n");
+    let mut over = TcpStream::connect(resp_addr)?;
+    assert_eq!(
+        ping(&mut over)?,
+        b"-ERR Too many connections, limit is 6\r\n"
+    );
+    match KVSClient::new(addr.to_owned()) {
+        Err(KVSError::ServerError { code, message }) => {
+            assert_eq!(code, ErrorCode::Busy);
+            assert_eq!(message, "Too many connections, limit is 6");
+        }
+        res => panic!("Unexpected result {:?}", res.map(|_| ())),
+    }
+
+    idle.pop();
+    thread::sleep(Duration::from_millis(200));
+    let mut freed = TcpStream::connect(resp_addr)?;
+    assert_eq!(ping(&mut freed)?, b"+PONG\r\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Pipelined commands should be served in order with responses matched by id,
+// failure of one command does not stop the others
+#[test]
+fn client_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // request ids are echoed by the server
+    let mut raw = TcpStream::connect(addr)?;
+    let hello = DBCommands::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    raw.write_all(&hello.to_packet()?)?;
+    ServerResponse::from_stream(&mut raw)?;
+    let mut packets = DBCommands::Get {
+        key: b"missing".to_vec(),
+    }
+    .to_packet_with_id(7)?;
+    packets.extend(DBCommands::Snapshot.to_packet_with_id(9)?);
+    raw.write_all(&packets)?;
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut raw)?;
+    assert_eq!(id, 7);
+    assert!(matches!(resp, ServerResponse::NotFound));
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut raw)?;
+    assert_eq!(id, 9);
+    assert!(matches!(resp, ServerResponse::Snapshot { .. }));
+    drop(raw);
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.add(DBCommands::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{:0>1000}", i).into_bytes(),
+            ttl: None,
+        });
+        pipeline.add(DBCommands::Get {
+            key: format!("key{}", i).into_bytes(),
+        });
+    }
+    pipeline
+        .add(DBCommands::Incr {
+            key: b"key1".to_vec(),
+            delta: 1,
+        })
+        .add(DBCommands::Rm {
+            key: b"key0".to_vec(),
+        });
+    let resps = pipeline.execute()?;
+
+    assert_eq!(resps.len(), 2002);
+    for (i, pair) in resps[..2000].chunks(2).enumerate() {
+        assert!(matches!(pair[0], ServerResponse::Success { .. }));
+        match &pair[1] {
+            ServerResponse::Value { value } => {
+                assert_eq!(value, &format!("value{:0>1000}", i).into_bytes())
+            }
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    assert!(matches!(
+        resps[2000],
+        ServerResponse::Failure {
+            code: ErrorCode::NotAnInteger,
+            ..
+        }
+    ));
+    assert!(matches!(resps[2001], ServerResponse::Success { .. }));
+
+    assert!(client.pipeline().execute()?.is_empty());
+    assert_eq!(client.get(b"key0".to_vec())?, None);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
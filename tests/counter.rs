@@ -0,0 +1,84 @@
+use kvs::{KVSError, KvStore, KvsEngine, Result, SledStore};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Counters should behave the same on any engine
+fn check_counter<S: KvsEngine>(store: &S) -> Result<()> {
+    // missing key counts as 0
+    assert_eq!(store.incr(b"counter".to_vec(), 1)?, 1);
+    assert_eq!(store.incr(b"counter".to_vec(), 5)?, 6);
+    assert_eq!(store.decr(b"counter".to_vec(), 10)?, -4);
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"-4".to_vec()));
+    assert_eq!(store.incr(b"counter".to_vec(), -1)?, -5);
+
+    store.set(b"counter".to_vec(), b"41".to_vec())?;
+    assert_eq!(store.incr(b"counter".to_vec(), 1)?, 42);
+
+    // non-numeric value and overflow keep the value
+    store.set(b"text".to_vec(), b"value1".to_vec())?;
+    assert!(matches!(
+        store.incr(b"text".to_vec(), 1),
+        Err(KVSError::NotAnIntegerError)
+    ));
+    assert_eq!(store.get(b"text".to_vec())?, Some(b"value1".to_vec()));
+    store.set(b"max".to_vec(), i64::MAX.to_string().into_bytes())?;
+    assert!(matches!(
+        store.incr(b"max".to_vec(), 1),
+        Err(KVSError::NotAnIntegerError)
+    ));
+    assert!(matches!(
+        store.decr(b"counter".to_vec(), i64::MIN),
+        Err(KVSError::NotAnIntegerError)
+    ));
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"42".to_vec()));
+
+    // TTL of the counter is kept
+    store.set_with_ttl(b"limit".to_vec(), b"1".to_vec(), Duration::from_secs(60))?;
+    assert_eq!(store.incr(b"limit".to_vec(), 1)?, 2);
+    assert!(store.ttl(b"limit".to_vec())?.is_some());
+    Ok(())
+}
+
+#[test]
+fn kvs_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_counter(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_counter(&SledStore::open(temp_dir.path())?)
+}
+
+// Increments from many threads should not be lost
+fn check_concurrent_incr<S: KvsEngine>(store: S) -> Result<()> {
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr(b"counter".to_vec(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"400".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_incr(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_incr(SledStore::open(temp_dir.path())?)
+}
@@ -0,0 +1,80 @@
+use kvs::{Durability, KvStore, KvStoreConfig, KvsEngine, Result, SledStore};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const MODES: [Durability; 4] = [
+    Durability::None,
+    Durability::EveryWrite,
+    Durability::Interval(10),
+    Durability::GroupCommit,
+];
+
+// Concurrent writes should be readable after reopen with every durability mode
+fn check_engine<S, F>(open: F) -> Result<()>
+where
+    S: KvsEngine,
+    F: Fn(&TempDir, Durability) -> Result<S>,
+{
+    for durability in MODES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(&temp_dir, durability)?;
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..20 {
+                        let key = format!("key{}_{}", thread_id, i).into_bytes();
+                        store.set(key, format!("value{}", i).into_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        store.remove(b"key0_0".to_vec())?;
+        // let interval sync run at least once
+        thread::sleep(Duration::from_millis(20));
+
+        drop(store);
+        let store = open(&temp_dir, durability)?;
+        assert_eq!(store.get(b"key0_0".to_vec())?, None);
+        for thread_id in 0..8 {
+            for i in 1..20 {
+                let key = format!("key{}_{}", thread_id, i).into_bytes();
+                assert_eq!(store.get(key)?, Some(format!("value{}", i).into_bytes()));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_durability_modes() -> Result<()> {
+    check_engine(|temp_dir, durability| {
+        let config = KvStoreConfig {
+            durability,
+            ..KvStoreConfig::default()
+        };
+        KvStore::with_config(temp_dir.path().to_owned(), config)
+    })
+}
+
+#[test]
+fn sled_durability_modes() -> Result<()> {
+    check_engine(|temp_dir, durability| {
+        SledStore::open_with_durability(temp_dir.path(), durability)
+    })
+}
+
+#[test]
+fn parse_durability() {
+    for durability in MODES {
+        assert_eq!(durability.to_string().parse(), Ok(durability));
+    }
+    assert_eq!("interval:250".parse(), Ok(Durability::Interval(250)));
+    assert!("interval:0".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+}
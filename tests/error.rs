@@ -0,0 +1,203 @@
+use assert_cmd::prelude::*;
+use crc16::{State, ARC};
+use kvs::{
+    DBCommands, ErrorCode, KVSClient, KVSError, KvStore, KvsEngine, Result, ServerResponse,
+    SledStore,
+};
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Missing key should be told apart from other failures on any engine
+fn check_missing_key<S: KvsEngine>(store: &S) -> Result<()> {
+    assert!(matches!(
+        store.remove(b"key1".to_vec()),
+        Err(KVSError::KeyNotFoundError)
+    ));
+    assert!(matches!(
+        store.ttl(b"key1".to_vec()),
+        Err(KVSError::KeyNotFoundError)
+    ));
+    Ok(())
+}
+
+#[test]
+fn kvs_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_missing_key(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_missing_key(&SledStore::open(temp_dir.path())?)
+}
+
+// IO error should keep the path and its source
+#[test]
+fn io_error_with_path() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("file");
+    fs::write(&file_path, b"not a directory").unwrap();
+
+    let err = KvStore::open(&file_path).unwrap_err();
+    match &err {
+        KVSError::IOError { path, .. } => assert_eq!(path.as_deref(), Some(file_path.as_path())),
+        err => panic!("Unexpected error {:?}", err),
+    }
+    assert!(err.to_string().contains("file"));
+    let source = err.source().expect("IO error has no source");
+    assert!(source.downcast_ref::<std::io::Error>().is_some());
+}
+
+// Log of unknown format should be reported as corrupted
+#[test]
+fn unknown_log_format() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), b"KVSL\x63").unwrap();
+
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, KVSError::CorruptedDataError { .. }));
+    assert!(err.to_string().contains("version 99"));
+    assert!(err.source().is_none());
+}
+
+// Malformed packets should be protocol violations
+#[test]
+fn protocol_violation() -> Result<()> {
+    let res = DBCommands::from_stream(&mut &b"garbage packet"[..]);
+    assert!(matches!(res, Err(KVSError::ProtocolError { .. })));
+
+    let mut packet = DBCommands::Get {
+        key: b"key1".to_vec(),
+    }
+    .to_packet()?;
+    let last = packet.len() - 1;
+    packet[last] ^= 0xff;
+    let err = DBCommands::from_stream(&mut packet.as_slice()).unwrap_err();
+    assert!(matches!(err, KVSError::ProtocolError { .. }));
+    assert!(err.to_string().contains("checksum"));
+
+    // truncated packet is an IO error of the stream
+    let res = DBCommands::from_stream(&mut &packet[..5]);
+    assert!(matches!(res, Err(KVSError::IOError { path: None, .. })));
+
+    // malformed ttl must not be taken for value without expiration
+    let packet = DBCommands::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+        ttl: Some(Duration::from_secs(1)),
+    }
+    .to_packet()?;
+    // replace 8 byte ttl field and checksum with 3 byte field
+    let mut packet = packet[..packet.len() - 2 - 8 - 4].to_vec();
+    packet.extend_from_slice(&3u32.to_be_bytes());
+    packet.extend_from_slice(&[1, 2, 3]);
+    let checksum = State::<ARC>::calculate(&packet);
+    packet.extend_from_slice(&checksum.to_be_bytes());
+    let err = DBCommands::from_stream(&mut packet.as_slice()).unwrap_err();
+    assert!(matches!(err, KVSError::ProtocolError { .. }));
+    assert!(err.to_string().contains("field 2"));
+    Ok(())
+}
+
+// Failure code should survive the packet
+#[test]
+fn failure_code_on_wire() -> Result<()> {
+    let packet = ServerResponse::error(&KVSError::KeyNotFoundError).to_packet()?;
+    let resp = ServerResponse::from_stream(&mut packet.as_slice())?;
+    assert!(matches!(
+        resp,
+        ServerResponse::Failure { code: ErrorCode::KeyNotFound, ref message } if message == "Key not found"
+    ));
+    assert!(matches!(
+        resp.into_result(),
+        Err(KVSError::KeyNotFoundError)
+    ));
+
+    let resp = ServerResponse::failure(ErrorCode::SnapshotNotFound, "Snapshot not found");
+    let packet = resp.to_packet()?;
+    let err = ServerResponse::from_stream(&mut packet.as_slice())?
+        .into_result()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        KVSError::ServerError {
+            code: ErrorCode::SnapshotNotFound,
+            ..
+        }
+    ));
+    assert_eq!(err.to_string(), "Snapshot not found");
+    Ok(())
+}
+
+// Client should turn failures of the server into typed errors
+#[test]
+fn client_typed_errors() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    client.request(DBCommands::SetIfAbsent {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+    })?;
+    assert!(matches!(
+        client.request(DBCommands::SetIfAbsent {
+            key: b"key1".to_vec(),
+            value: b"value2".to_vec(),
+        }),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    assert!(matches!(
+        client.request(DBCommands::Rm {
+            key: b"key2".to_vec()
+        }),
+        Err(KVSError::KeyNotFoundError)
+    ));
+    assert!(matches!(
+        client.request(DBCommands::Incr {
+            key: b"key1".to_vec(),
+            delta: 1
+        }),
+        Err(KVSError::NotAnIntegerError)
+    ));
+    assert!(matches!(
+        client.request(DBCommands::Commit),
+        Err(KVSError::ServerError {
+            code: ErrorCode::InvalidCommand,
+            ..
+        })
+    ));
+    // raw response keeps the code
+    assert!(matches!(
+        client.send_cmd(DBCommands::Rm {
+            key: b"key2".to_vec()
+        })?,
+        ServerResponse::Failure {
+            code: ErrorCode::KeyNotFound,
+            ..
+        }
+    ));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    Capabilities, DBCommands, ErrorCode, KVSClient, KVSError, Result, ServerResponse, WriteBatch,
+    PROTOCOL_VERSION,
+};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start server with extra arguments, killed when the sender is sent to
+fn start_server(
+    addr: &str,
+    temp_dir: &TempDir,
+    args: &[&str],
+) -> (mpsc::SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+fn too_large<T: std::fmt::Debug>(res: Result<T>) -> String {
+    match res {
+        Err(KVSError::TooLargeError { reason }) => reason,
+        res => panic!("expected too large error, got {:?}", res),
+    }
+}
+
+// Packet declaring a huge field should be rejected before the field is read,
+// keys and values over the limit are rejected while the connection goes on
+#[test]
+fn reject_too_large() -> Result<()> {
+    let addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(
+        addr,
+        &temp_dir,
+        &["--max-key-size", "16", "--max-value-size", "1024"],
+    );
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let hello = DBCommands::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    stream.write_all(&hello.to_packet()?)?;
+    ServerResponse::from_stream(&mut stream)?;
+    // head, GET, request id, one field of 4 GiB - 1 and only few bytes of it
+    let mut packet = vec![27, 59, 1];
+    packet.extend_from_slice(&1u64.to_be_bytes());
+    packet.extend_from_slice(&1u32.to_be_bytes());
+    packet.extend_from_slice(&u32::MAX.to_be_bytes());
+    packet.extend_from_slice(b"key");
+    stream.write_all(&packet)?;
+    match ServerResponse::from_stream(&mut stream)? {
+        ServerResponse::Failure {
+            code: ErrorCode::TooLarge,
+            message,
+        } => assert!(message.contains("packet"), "{}", message),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with unread packet is open");
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    let reason = too_large(client.request(DBCommands::Set {
+        key: vec![b'k'; 17],
+        value: b"value".to_vec(),
+        ttl: None,
+    }));
+    assert!(reason.contains("key of 17 bytes"), "{}", reason);
+    too_large(client.get(vec![b'k'; 17]));
+    too_large(client.request(DBCommands::Set {
+        key: b"key".to_vec(),
+        value: vec![0; 1025],
+        ttl: None,
+    }));
+    let mut batch = WriteBatch::new();
+    batch.put(b"key".to_vec(), vec![0; 1025]);
+    too_large(client.request(DBCommands::Batch { batch }));
+    too_large(client.set_stream(b"key".to_vec(), &mut io::repeat(0), 1025));
+
+    // connection is still usable
+    client.request(DBCommands::Set {
+        key: vec![b'k'; 16],
+        value: vec![1; 1024],
+        ttl: None,
+    })?;
+    assert_eq!(client.get(vec![b'k'; 16])?, Some(vec![1; 1024]));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Writer checking that bytes follow the pattern of `pattern_byte`
+struct PatternChecker {
+    pos: usize,
+}
+
+fn pattern_byte(pos: usize) -> u8 {
+    (pos % 251) as u8
+}
+
+impl Write for PatternChecker {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            assert_eq!(byte, pattern_byte(self.pos), "wrong byte at {}", self.pos);
+            self.pos += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reader of `len` bytes following the pattern
+struct PatternReader {
+    pos: usize,
+    len: usize,
+}
+
+impl Read for PatternReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = buf.len().min(self.len - self.pos);
+        for byte in &mut buf[..size] {
+            *byte = pattern_byte(self.pos);
+            self.pos += 1;
+        }
+        Ok(size)
+    }
+}
+
+// Value larger than the frame should be set and got in chunks,
+// but not as a single response
+#[test]
+fn stream_large_value() -> Result<()> {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let frame = (2 * 1024 * 1024).to_string();
+    let (sender, handle) = start_server(addr, &temp_dir, &["--max-frame-size", &frame]);
+
+    let len = 6 * 1024 * 1024 + 7;
+    let mut client = KVSClient::new(addr.to_owned())?;
+    assert!(client.capabilities().contains(Capabilities::STREAMING));
+    let mut value = PatternReader { pos: 0, len };
+    client.set_stream(b"big".to_vec(), &mut value, len as u64)?;
+
+    let mut out = PatternChecker { pos: 0 };
+    assert_eq!(
+        client.get_stream(b"big".to_vec(), &mut out)?,
+        Some(len as u64)
+    );
+    assert_eq!(out.pos, len);
+    assert_eq!(client.get_stream(b"missing".to_vec(), &mut out)?, None);
+
+    let reason = too_large(client.get(b"big".to_vec()));
+    assert!(reason.contains("response"), "{}", reason);
+
+    // small values are streamed too, in one chunk
+    client.set_stream(b"small".to_vec(), &mut &b"value"[..], 5)?;
+    assert_eq!(client.get(b"small".to_vec())?, Some(b"value".to_vec()));
+    let mut out = Vec::new();
+    client.get_stream(b"small".to_vec(), &mut out)?;
+    assert_eq!(out, b"value");
+
+    // streamed value expires like a set one
+    let ttl = Duration::from_millis(300);
+    client.set_stream_with_ttl(b"expiring".to_vec(), &mut &b"value"[..], 5, ttl)?;
+    assert_eq!(client.get(b"expiring".to_vec())?, Some(b"value".to_vec()));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get(b"expiring".to_vec())?, None);
+
+    // streaming commands are not pipelined
+    let mut pipeline = client.pipeline();
+    pipeline.add(DBCommands::GetStream {
+        key: b"small".to_vec(),
+    });
+    assert!(matches!(
+        pipeline.execute(),
+        Err(KVSError::ProtocolError { .. })
+    ));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Chunks of streamed values should fit frame limits below the default chunk size
+#[test]
+fn stream_with_small_frames() -> Result<()> {
+    let addr = "127.0.0.1:4043";
+    let temp_dir = TempDir::new().unwrap();
+    let frame = (64 * 1024).to_string();
+    let (sender, handle) = start_server(addr, &temp_dir, &["--max-frame-size", &frame]);
+
+    let len = 300 * 1024 + 3;
+    let mut client = KVSClient::new(addr.to_owned())?;
+    let mut value = PatternReader { pos: 0, len };
+    client.set_stream(b"big".to_vec(), &mut value, len as u64)?;
+    let mut out = PatternChecker { pos: 0 };
+    assert_eq!(
+        client.get_stream(b"big".to_vec(), &mut out)?,
+        Some(len as u64)
+    );
+    assert_eq!(out.pos, len);
+
+    // chunks sent by the server are just under the frame limit
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = DBCommands::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    stream.write_all(&hello.to_packet()?)?;
+    ServerResponse::from_stream(&mut stream)?;
+    let get = DBCommands::GetStream {
+        key: b"big".to_vec(),
+    };
+    stream.write_all(&get.to_packet_with_id(1)?)?;
+    assert!(matches!(
+        ServerResponse::from_stream(&mut stream)?,
+        ServerResponse::Stream { .. }
+    ));
+    match ServerResponse::from_stream(&mut stream)? {
+        ServerResponse::Chunk { data } => {
+            assert!(data.len() < 64 * 1024 && data.len() > 60 * 1024)
+        }
+        resp => panic!("expected chunk, got {:?}", resp),
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Streamed values should go through the open transaction, and other packet
+// in the middle of a streamed set should close the connection
+#[test]
+fn stream_in_transaction() -> Result<()> {
+    let addr = "127.0.0.1:4049";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, &temp_dir, &[]);
+
+    let len = 3 * 1024 * 1024 + 5;
+    let mut client = KVSClient::new(addr.to_owned())?;
+    let mut other = KVSClient::new(addr.to_owned())?;
+    client.request(DBCommands::Begin)?;
+    let mut value = PatternReader { pos: 0, len };
+    client.set_stream(b"big".to_vec(), &mut value, len as u64)?;
+    let mut out = PatternChecker { pos: 0 };
+    assert_eq!(
+        client.get_stream(b"big".to_vec(), &mut out)?,
+        Some(len as u64)
+    );
+    assert_eq!(out.pos, len);
+    assert_eq!(other.get_stream(b"big".to_vec(), &mut out)?, None);
+    client.request(DBCommands::Commit)?;
+    let mut out = PatternChecker { pos: 0 };
+    assert_eq!(
+        other.get_stream(b"big".to_vec(), &mut out)?,
+        Some(len as u64)
+    );
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let hello = DBCommands::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    stream.write_all(&hello.to_packet()?)?;
+    ServerResponse::from_stream(&mut stream)?;
+    let set = DBCommands::SetStream {
+        key: b"broken".to_vec(),
+        len: 10,
+        ttl: None,
+    };
+    stream.write_all(&set.to_packet_with_id(1)?)?;
+    ServerResponse::from_stream(&mut stream)?;
+    let chunk = DBCommands::Chunk {
+        data: b"part".to_vec(),
+    };
+    stream.write_all(&chunk.to_packet_with_id(1)?)?;
+    let get = DBCommands::Get {
+        key: b"big".to_vec(),
+    };
+    stream.write_all(&get.to_packet_with_id(2)?)?;
+    // connection is closed instead of answering the get
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.len() < 1024, "value of the get was sent");
+    assert_eq!(other.get(b"broken".to_vec())?, None);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
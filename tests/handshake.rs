@@ -0,0 +1,269 @@
+use assert_cmd::prelude::*;
+use crc16::{State, ARC};
+use kvs::{
+    Capabilities, DBCommands, ErrorCode, KVSClient, KVSError, Result, ServerResponse,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start server killed when the sender is dropped or sent to
+fn start_server(addr: &str, temp_dir: &TempDir) -> (mpsc::SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+// Packet of the protocol before version 1: HEAD, type byte, number of fields,
+// length-prefixed fields and CRC-ARC, without request id
+fn unnumbered_packet(kind: u8, fields: &[&[u8]]) -> Vec<u8> {
+    let mut packet = vec![27, 59, kind];
+    packet.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+    for field in fields {
+        packet.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        packet.extend_from_slice(field);
+    }
+    let checksum = State::<ARC>::calculate(&packet).to_be_bytes();
+    [packet, checksum.to_vec()].concat()
+}
+
+// Type byte and fields of the packet of the protocol before version 1
+fn parse_unnumbered_packet(packet: &[u8]) -> (u8, Vec<Vec<u8>>) {
+    assert_eq!(&packet[..2], &[27, 59]);
+    let read_u32 = |at: usize| u32::from_be_bytes(packet[at..at + 4].try_into().unwrap()) as usize;
+    let count = read_u32(3);
+    let mut at = 7;
+    let mut fields = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(at);
+        fields.push(packet[at + 4..at + 4 + len].to_vec());
+        at += 4 + len;
+    }
+    let checksum = State::<ARC>::calculate(&packet[..at]).to_be_bytes();
+    assert_eq!(&packet[at..], &checksum);
+    (packet[2], fields)
+}
+
+fn hello(
+    stream: &mut TcpStream,
+    version: u16,
+    capabilities: Capabilities,
+) -> Result<ServerResponse> {
+    let packet = DBCommands::Hello {
+        version,
+        capabilities,
+    }
+    .to_packet_with_id(1)?;
+    stream.write_all(&packet)?;
+    let (id, resp) = ServerResponse::from_stream_with_id(stream)?;
+    assert_eq!(id, 1);
+    Ok(resp)
+}
+
+// Client and server of the same version should agree on it
+// and on the capabilities the server supports
+#[test]
+fn handshake_same_version() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, &temp_dir);
+
+    let mut client = KVSClient::new(addr.to_owned())?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(client.capabilities(), Capabilities::SUPPORTED);
+    assert!(client
+        .capabilities()
+        .contains(Capabilities::BINARY_VALUES | Capabilities::PIPELINING | Capabilities::SCANS));
+    client.request(DBCommands::Set {
+        key: b"key".to_vec(),
+        value: b"value".to_vec(),
+        ttl: None,
+    })?;
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"value".to_vec()));
+
+    // handshake is only done once per connection
+    match client.request(DBCommands::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    }) {
+        Err(KVSError::ServerError {
+            code: ErrorCode::InvalidCommand,
+            ..
+        }) => {}
+        res => panic!("second HELLO returned {:?}", res),
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Server should answer newer client with its own version and grant only
+// the capabilities it supports, agree on older versions it speaks
+// and reject clients older than that
+#[test]
+fn handshake_other_versions() -> Result<()> {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let asked = Capabilities::SUPPORTED | Capabilities::from_bits(1 << 2 | 1 << 20);
+    match hello(&mut stream, PROTOCOL_VERSION + 1, asked)? {
+        ServerResponse::Hello {
+            version,
+            capabilities,
+        } => {
+            assert_eq!(version, PROTOCOL_VERSION);
+            assert_eq!(capabilities, Capabilities::SUPPORTED);
+        }
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    stream.write_all(
+        &DBCommands::Get {
+            key: b"key".to_vec(),
+        }
+        .to_packet_with_id(2)?,
+    )?;
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut stream)?;
+    assert_eq!(id, 2);
+    assert!(matches!(resp, ServerResponse::NotFound));
+
+    let mut stream = TcpStream::connect(addr)?;
+    match hello(&mut stream, MIN_PROTOCOL_VERSION, Capabilities::SUPPORTED)? {
+        ServerResponse::Hello { version, .. } => assert_eq!(version, MIN_PROTOCOL_VERSION),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    match hello(&mut stream, 0, Capabilities::SUPPORTED)? {
+        ServerResponse::Failure {
+            code: ErrorCode::UnsupportedVersion,
+            message,
+        } => assert!(message.contains("Protocol version 0 is not supported")),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection of rejected client is open");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Client of protocol version 1 sends requests with ids without HELLO,
+// server should serve them, pipelined ones too
+#[test]
+fn handshake_v1_client() -> Result<()> {
+    let addr = "127.0.0.1:4048";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let requests = [
+        DBCommands::Set {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            ttl: None,
+        }
+        .to_packet_with_id(1 << 20)?,
+        DBCommands::Get {
+            key: b"key".to_vec(),
+        }
+        .to_packet_with_id(2)?,
+        DBCommands::Get {
+            key: b"missing".to_vec(),
+        }
+        .to_packet_with_id(3)?,
+    ];
+    stream.write_all(&requests.concat())?;
+
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut stream)?;
+    assert_eq!(id, 1 << 20);
+    assert!(matches!(resp, ServerResponse::Success { .. }), "{:?}", resp);
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut stream)?;
+    assert_eq!(id, 2);
+    match resp {
+        ServerResponse::Value { value } => assert_eq!(value, b"value"),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let (id, resp) = ServerResponse::from_stream_with_id(&mut stream)?;
+    assert_eq!(id, 3);
+    assert!(matches!(resp, ServerResponse::NotFound));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Client before protocol version 1 sends commands without request ids,
+// it should get failure it can read and the connection closed
+#[test]
+fn handshake_old_client() -> Result<()> {
+    let addr = "127.0.0.1:4022";
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, &temp_dir);
+
+    // packets with fields and without them, whose number of fields reads as zero id
+    for packet in [unnumbered_packet(1, &[b"key"]), unnumbered_packet(1, &[])] {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(&packet)?;
+        let mut packet = Vec::new();
+        stream.read_to_end(&mut packet)?;
+
+        let (kind, fields) = parse_unnumbered_packet(&packet);
+        assert_eq!(kind, 101, "response is not failure");
+        let message = String::from_utf8(fields[0].clone()).unwrap();
+        assert!(message.contains("must start with HELLO"), "{}", message);
+        let code = u16::from_be_bytes(fields[1].as_slice().try_into().unwrap());
+        assert_eq!(ErrorCode::from_u16(code), ErrorCode::UnsupportedVersion);
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Server of protocol version 1 drops the connection on HELLO
+// as it has no such command, client should fail with protocol error
+#[test]
+fn handshake_old_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            // head, type, id, number of fields and checksum as version 1 reads them
+            let mut packet = [0u8; 17];
+            let _ = stream.read_exact(&mut packet);
+        }
+    });
+
+    match KVSClient::new(addr) {
+        Err(KVSError::ProtocolError { reason }) => {
+            assert!(reason.contains("older protocol"), "{}", reason)
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("handshake with old server succeeded"),
+    }
+}
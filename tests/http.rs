@@ -0,0 +1,359 @@
+use assert_cmd::prelude::*;
+use kvs::{KVSClient, Result};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start server with HTTP listener and extra arguments,
+// killed when the sender is sent to
+fn start_server(
+    addr: &str,
+    http_addr: &str,
+    temp_dir: &TempDir,
+    args: &[&str],
+) -> (mpsc::SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .args(["--http-addr", http_addr])
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Value {
+        assert_eq!(self.header("Content-Type"), Some("application/json"));
+        serde_json::from_slice(&self.body).unwrap()
+    }
+
+    // Kind of the JSON error body
+    fn error_kind(&self) -> String {
+        self.json()["error"]["kind"].as_str().unwrap().to_owned()
+    }
+}
+
+// Keep-alive connection sending raw HTTP/1.1 requests
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(addr: &str) -> Connection {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Connection { stream, reader }
+    }
+
+    fn request(&mut self, method: &str, target: &str, headers: &[&str], body: &[u8]) -> Response {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n",
+            method,
+            target,
+            body.len()
+        );
+        for header in headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        self.stream.write_all(head.as_bytes()).unwrap();
+        self.stream.write_all(body).unwrap();
+        self.read_response()
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "line {:?} without CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read_response(&mut self) -> Response {
+        let status_line = self.read_line();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.push((name.to_owned(), value.to_owned()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        if let Some(len) = response.header("Content-Length") {
+            let mut body = vec![0; len.parse().unwrap()];
+            self.reader.read_exact(&mut body).unwrap();
+            response.body = body;
+        }
+        response
+    }
+}
+
+// Keys should be read, written and listed over HTTP,
+// on the engine shared with the native protocol
+#[test]
+fn http_keys() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4035", "127.0.0.1:4036");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, http_addr, &temp_dir, &[]);
+    let mut conn = Connection::open(http_addr);
+
+    let resp = conn.request("GET", "/keys/key", &[], b"");
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.error_kind(), "KeyNotFound");
+    assert_eq!(resp.json()["error"]["message"], "Key not found");
+
+    assert_eq!(conn.request("PUT", "/keys/key", &[], b"value").status, 204);
+    let resp = conn.request("GET", "/keys/key", &[], b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, b"value");
+    assert_eq!(
+        resp.header("Content-Type"),
+        Some("application/octet-stream")
+    );
+    let etag = resp.header("ETag").unwrap().to_owned();
+
+    // same engine as the native protocol
+    let mut client = KVSClient::new(addr.to_owned())?;
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"value".to_vec()));
+
+    // binary key and value, percent-encoded key with a slash
+    let binary = [0u8, 255, 10, 13];
+    assert_eq!(
+        conn.request("PUT", "/keys/a%2Fb%00", &[], &binary).status,
+        204
+    );
+    assert_eq!(client.get(b"a/b\0".to_vec())?, Some(binary.to_vec()));
+    assert_eq!(conn.request("GET", "/keys/a%2Fb%00", &[], b"").body, binary);
+
+    // conditional writes
+    let stale = format!("If-Match: {}", etag);
+    let resp = conn.request("PUT", "/keys/key", &[&stale], b"new");
+    assert_eq!(resp.status, 204);
+    let current = resp.header("ETag").unwrap().to_owned();
+    assert_ne!(current, etag);
+    let resp = conn.request("PUT", "/keys/key", &[&stale], b"newer");
+    assert_eq!(resp.status, 409);
+    assert_eq!(resp.error_kind(), "PreconditionFailed");
+    assert_eq!(conn.request("GET", "/keys/key", &[], b"").body, b"new");
+    let absent = "If-None-Match: *";
+    assert_eq!(
+        conn.request("PUT", "/keys/key", &[absent], b"x").status,
+        409
+    );
+    assert_eq!(
+        conn.request("PUT", "/keys/fresh", &[absent], b"x").status,
+        204
+    );
+
+    // expiring value
+    assert_eq!(
+        conn.request("PUT", "/keys/short?ttl=300ms", &[], b"v")
+            .status,
+        204
+    );
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(conn.request("GET", "/keys/short", &[], b"").status, 404);
+    let resp = conn.request("PUT", "/keys/short?ttl=soon", &[], b"v");
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.error_kind(), "Protocol");
+
+    assert_eq!(conn.request("DELETE", "/keys/fresh", &[], b"").status, 204);
+    assert_eq!(conn.request("DELETE", "/keys/fresh", &[], b"").status, 404);
+
+    // listing
+    for i in 0..5 {
+        let target = format!("/keys/user:{}", i);
+        assert_eq!(conn.request("PUT", &target, &[], b"v").status, 204);
+    }
+    let resp = conn.request("GET", "/keys?prefix=user%3A&limit=3", &[], b"");
+    assert_eq!(resp.status, 200);
+    let entries = resp.json()["entries"].as_array().unwrap().clone();
+    let keys: Vec<&str> = entries.iter().map(|e| e["key"].as_str().unwrap()).collect();
+    assert_eq!(keys, ["user:0", "user:1", "user:2"]);
+    assert_eq!(entries[0]["value"], "v");
+    let resp = conn.request("GET", "/keys?prefix=a&encoding=hex", &[], b"");
+    assert_eq!(
+        resp.json()["entries"],
+        serde_json::json!([{"key": "612f6200", "value": "00ff0a0d"}])
+    );
+    assert_eq!(conn.request("GET", "/keys?limit=x", &[], b"").status, 400);
+
+    // routes
+    let resp = conn.request("POST", "/keys/key", &[], b"");
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("GET, PUT, DELETE"));
+    assert_eq!(resp.error_kind(), "InvalidCommand");
+    assert_eq!(conn.request("GET", "/other", &[], b"").status, 404);
+
+    // connection is closed when asked
+    let resp = conn.request("GET", "/keys/key", &["Connection: close"], b"");
+    assert_eq!(resp.header("Connection"), Some("close"));
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Batch should be applied atomically, and not at all when a version
+// does not match, malformed or too large requests get JSON errors
+#[test]
+fn http_batch() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4037", "127.0.0.1:4038");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, http_addr, &temp_dir, &["--max-value-size", "16"]);
+    let mut conn = Connection::open(http_addr);
+
+    assert_eq!(conn.request("PUT", "/keys/old", &[], b"v").status, 204);
+    let body = br#"{"ops": [
+        {"op": "put", "key": "a", "value": "1"},
+        {"op": "put", "key": "b", "value": "2"},
+        {"op": "delete", "key": "old"}
+    ]}"#;
+    assert_eq!(conn.request("POST", "/batch", &[], body).status, 204);
+    assert_eq!(conn.request("GET", "/keys/b", &[], b"").body, b"2");
+    assert_eq!(conn.request("GET", "/keys/old", &[], b"").status, 404);
+
+    let etag = conn
+        .request("GET", "/keys/a", &[], b"")
+        .header("ETag")
+        .unwrap()
+        .to_owned();
+    let version: u64 = etag.trim_matches('"').parse().unwrap();
+    let batch = |version: u64| {
+        format!(
+            r#"{{"ops": [
+                {{"op": "put", "key": "a", "value": "3", "version": {}}},
+                {{"op": "delete", "key": "b"}}
+            ]}}"#,
+            version
+        )
+    };
+    let resp = conn.request("POST", "/batch", &[], batch(version + 100).as_bytes());
+    assert_eq!(resp.status, 409);
+    assert_eq!(resp.error_kind(), "TransactionConflict");
+    assert_eq!(conn.request("GET", "/keys/b", &[], b"").body, b"2");
+    let resp = conn.request("POST", "/batch", &[], batch(version).as_bytes());
+    assert_eq!(resp.status, 204);
+    assert_eq!(conn.request("GET", "/keys/a", &[], b"").body, b"3");
+    assert_eq!(conn.request("GET", "/keys/b", &[], b"").status, 404);
+
+    let body = br#"{"ops": [{"op": "put", "key": "AA==", "value": "AQI="}]}"#;
+    assert_eq!(
+        conn.request("POST", "/batch?encoding=base64", &[], body)
+            .status,
+        204
+    );
+    assert_eq!(conn.request("GET", "/keys/%00", &[], b"").body, [1, 2]);
+
+    let resp = conn.request("POST", "/batch", &[], br#"{"ops": [{"op": "get"}]}"#);
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.error_kind(), "Protocol");
+    let body = br#"{"ops": [{"op": "put", "key": "big", "value": "more than sixteen bytes"}]}"#;
+    let resp = conn.request("POST", "/batch", &[], body);
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.error_kind(), "TooLarge");
+    assert_eq!(conn.request("GET", "/keys/big", &[], b"").status, 404);
+
+    // value over the limit is rejected before it is read
+    conn.stream
+        .write_all(b"PUT /keys/big HTTP/1.1\r\nContent-Length: 1073741824\r\n\r\nvalue")?;
+    let resp = conn.read_response();
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.error_kind(), "TooLarge");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with unread body is open");
+
+    let mut conn = Connection::open(http_addr);
+    conn.stream.write_all(b"GET /keys/a\r\n\r\n")?;
+    let resp = conn.read_response();
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.header("Connection"), Some("close"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Listing should return at most 1000 entries whatever the limit, and a body
+// declared larger than what is sent should only close its connection
+#[test]
+fn http_limits() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4046", "127.0.0.1:4047");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, http_addr, &temp_dir, &[]);
+    let mut conn = Connection::open(http_addr);
+
+    let ops: Vec<String> = (0..1005)
+        .map(|i| format!(r#"{{"op": "put", "key": "k{:04}", "value": "v"}}"#, i))
+        .collect();
+    let body = format!(r#"{{"ops": [{}]}}"#, ops.join(","));
+    assert_eq!(
+        conn.request("POST", "/batch", &[], body.as_bytes()).status,
+        204
+    );
+    let resp = conn.request("GET", "/keys?limit=100000", &[], b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.json()["entries"].as_array().unwrap().len(), 1000);
+
+    // 256 MiB announced, a few bytes sent before closing
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"PUT /keys/big HTTP/1.1\r\nContent-Length: 268435456\r\n\r\nvalue")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    let mut conn = Connection::open(http_addr);
+    assert_eq!(conn.request("GET", "/keys/big", &[], b"").status, 404);
+    assert_eq!(conn.request("GET", "/keys/k0000", &[], b"").body, b"v");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
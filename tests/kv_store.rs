@@ -1,4 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KVSError, KvStore, KvStoreConfig, KvsEngine, RecoveryPolicy, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,19 +11,19 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -27,19 +32,19 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -48,15 +53,15 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
 
     Ok(())
 }
@@ -64,18 +69,18 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove(b"key1".to_vec()).is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(store.remove(b"key1".to_vec()).is_ok());
+    assert_eq!(store.get(b"key1".to_vec())?, None);
     Ok(())
 }
 
@@ -84,7 +89,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -100,8 +105,8 @@ fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
             store.set(key, value)?;
         }
 
@@ -114,13 +119,596 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into_bytes()));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Should share the same data between handles from different threads
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id).into_bytes()).unwrap(),
+                    Some(format!("value{}", key_id).into_bytes())
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Background compaction should keep writes done while it runs
+// and delete stale generations
+#[test]
+fn compaction_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for iter in 0..500 {
+                for key_id in 0..20 {
+                    let key = format!("key{}_{}", thread_id, key_id).into_bytes();
+                    store
+                        .set(key, format!("{:0>100}", iter).into_bytes())
+                        .unwrap();
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // dropping the last handle waits for running compaction
+    drop(store);
+    let written: u64 = 4 * 500 * 20 * 100;
+    let dir_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(dir_size < written / 2, "stale generations are not deleted");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..4 {
+        for key_id in 0..20 {
+            let key = format!("key{}_{}", thread_id, key_id).into_bytes();
+            assert_eq!(store.get(key)?, Some(format!("{:0>100}", 499).into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+// Opening the store without writing must not leave empty generations behind
+#[test]
+fn reopen_without_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = || -> Result<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(temp_dir.path())? {
+            if entry?.path().extension().is_some_and(|ext| ext == "log") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    };
+    for _ in 0..5 {
+        drop(KvStore::open(temp_dir.path())?);
+    }
+    assert_eq!(log_count()?, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+    for _ in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    }
+    assert_eq!(log_count()?, 1);
+
+    // next write goes into a new generation after the last one
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+    assert_eq!(log_count()?, 2);
+    assert!(temp_dir.path().join("2.log").is_file());
+    Ok(())
+}
+
+/// Write two keys into fresh store and return length of the first generation
+fn write_two_keys(path: &Path) -> Result<u64> {
+    let store = KvStore::open(path)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+    Ok(fs::metadata(path.join("1.log"))?.len())
+}
+
+// Interrupted write at the end of the log should be cut off
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let len = write_two_keys(temp_dir.path())?;
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.write_all(&[0, 0, 0, 100, 1, 2, 3])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), len);
+
+    // strict policy does not tolerate even torn tail
+    drop(store);
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.write_all(&[0, 0])?;
+    drop(file);
+    let config = KvStoreConfig {
+        recovery: RecoveryPolicy::Strict,
+        ..KvStoreConfig::default()
+    };
+    let res = KvStore::with_config(temp_dir.path().to_owned(), config);
+    assert!(matches!(
+        res,
+        Err(KVSError::CorruptedLogError { gen: 1, .. })
+    ));
+    Ok(())
+}
+
+// Damaged record in the middle of the log should be reported with its offset
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_two_keys(temp_dir.path())?;
+
+    // flip byte of the first record payload, right after file and frame headers
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    content[5 + 12 + 2] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    match &err {
+        KVSError::CorruptedLogError { path, gen, offset } => {
+            assert_eq!((path, *gen, *offset), (&log_path, 1, 5));
+        }
+        err => panic!("Unexpected error {:?}", err),
+    }
+    assert!(err.to_string().contains("1.log"));
+
+    let config = KvStoreConfig {
+        recovery: RecoveryPolicy::SkipCorrupted,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::with_config(temp_dir.path().to_owned(), config)?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Files in the directory not named like files of the store should be left alone
+#[test]
+fn keep_foreign_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let foreign = [
+        "notes.compact",
+        "a.hint",
+        "1.hint.bak",
+        "x1.log",
+        "backup.hint.compact",
+    ];
+    for name in foreign {
+        fs::write(temp_dir.path().join(name), b"foreign")?;
+    }
+    // leftovers of the store itself are still cleaned up
+    fs::write(temp_dir.path().join("3.compact"), b"unfinished")?;
+    fs::write(temp_dir.path().join("3.hint.compact"), b"unfinished")?;
+    fs::write(temp_dir.path().join("9.hint"), b"orphan")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+    for name in foreign {
+        assert_eq!(fs::read(temp_dir.path().join(name))?, b"foreign");
+    }
+    for name in ["3.compact", "3.hint.compact", "9.hint"] {
+        assert!(!temp_dir.path().join(name).exists(), "{} is kept", name);
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+/// Write two keys into fresh store, then three more into the second generation.
+/// Return offset of the second record there
+fn write_three_keys(path: &Path) -> Result<u64> {
+    write_two_keys(path)?;
+    let store = KvStore::open(path)?;
+    store.set(b"key1".to_vec(), b"value".to_vec())?;
+    let offset = fs::metadata(path.join("2.log"))?.len();
+    store.set(b"key2".to_vec(), b"value".to_vec())?;
+    store.set(b"key3".to_vec(), b"value".to_vec())?;
+    Ok(offset)
+}
+
+// Damaged length in the middle of the log must not cut off records after it
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let offset = write_three_keys(temp_dir.path())?;
+
+    // length of the second record points past the end of the file
+    let log_path = temp_dir.path().join("2.log");
+    let mut content = fs::read(&log_path)?;
+    content[offset as usize] = 0x7f;
+    fs::write(&log_path, &content)?;
+
+    let res = KvStore::open(temp_dir.path());
+    assert!(matches!(
+        res,
+        Err(KVSError::CorruptedLogError { gen: 2, offset: o, .. }) if o == offset
+    ));
+    assert_eq!(fs::read(&log_path)?, content);
+
+    let config = KvStoreConfig {
+        recovery: RecoveryPolicy::SkipCorrupted,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::with_config(temp_dir.path().to_owned(), config)?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Damaged length of version 2 log, which has no checksum of it,
+// must not be taken for torn tail either
+#[test]
+fn detect_corrupted_unchecked_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let frame = |key: &[u8], version: u64| {
+        let mut payload = vec![5];
+        payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&5u32.to_be_bytes());
+        payload.extend_from_slice(&0u64.to_be_bytes());
+        payload.extend_from_slice(&version.to_be_bytes());
+        payload.extend_from_slice(key);
+        payload.extend_from_slice(b"value");
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    };
+    let first = frame(b"key1", 1);
+    let mut content = b"KVSL\x02".to_vec();
+    content.extend_from_slice(&first);
+    content.extend_from_slice(&frame(b"key2", 2));
+    content.extend_from_slice(&frame(b"key3", 3));
+    let offset = 5 + first.len();
+    content[offset] = 0x7f;
+    let log_path = temp_dir.path().join("1.log");
+    fs::write(&log_path, &content)?;
+
+    let res = KvStore::open(temp_dir.path());
+    assert!(matches!(
+        res,
+        Err(KVSError::CorruptedLogError { gen: 1, offset: o, .. }) if o == offset as u64
+    ));
+    assert_eq!(fs::read(&log_path)?, content);
+
+    let config = KvStoreConfig {
+        recovery: RecoveryPolicy::SkipCorrupted,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::with_config(temp_dir.path().to_owned(), config)?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Only the newest generation may end with torn record
+#[test]
+fn torn_tail_of_older_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_three_keys(temp_dir.path())?;
+
+    let first_log = temp_dir.path().join("1.log");
+    let mut file = OpenOptions::new().append(true).open(&first_log)?;
+    file.write_all(&[0, 0, 0, 100, 1, 2, 3])?;
+    drop(file);
+    let len = fs::metadata(&first_log)?.len();
+
+    let res = KvStore::open(temp_dir.path());
+    assert!(matches!(
+        res,
+        Err(KVSError::CorruptedLogError { gen: 1, .. })
+    ));
+    assert_eq!(fs::metadata(&first_log)?.len(), len);
+
+    let config = KvStoreConfig {
+        recovery: RecoveryPolicy::SkipCorrupted,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::with_config(temp_dir.path().to_owned(), config)?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(fs::metadata(&first_log)?.len(), len);
+    Ok(())
+}
+
+// Log of previous versions should still be readable
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.db"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Legacy records should be rewritten in the current format by compaction
+#[test]
+fn upgrade_legacy_log_on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(500);
+    let legacy: String = (0..3000)
+        .map(|i| format!(r#"{{"Set":{{"key":"key{}","value":"{}"}}}}"#, i % 10, value))
+        .collect();
+    fs::write(temp_dir.path().join("kvs.db"), legacy)?;
+
+    // stale legacy records are enough to start compaction on the first write
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key10".to_vec(), b"value10".to_vec())?;
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            let content = fs::read(path)?;
+            assert_eq!(&content[..5], b"KVSL\x03");
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes())?,
+            Some(value.clone().into_bytes())
+        );
+    }
+    assert_eq!(store.get(b"key10".to_vec())?, Some(b"value10".to_vec()));
+    Ok(())
+}
+
+// Index loaded from hint files should be the same as from full scan of the log
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter).into_bytes(),
+            )?;
+        }
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id).into_bytes())?;
+    }
+    drop(store);
+
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect();
+    assert!(!hints.is_empty(), "compaction did not write hint file");
+
+    let read_all = |store: &KvStore| -> Result<Vec<Option<Vec<u8>>>> {
+        (0..100)
+            .map(|key_id| store.get(format!("key{}", key_id).into_bytes()))
+            .collect()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let with_hints = read_all(&store)?;
+    drop(store);
+
+    for hint in hints {
+        fs::remove_file(hint)?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    let scanned = read_all(&store)?;
+
+    assert_eq!(with_hints, scanned);
+    assert_eq!(with_hints[0], None);
+    assert_eq!(with_hints[99], Some(format!("{:0>100}", 199).into_bytes()));
+    Ok(())
+}
+
+// Damaged hint file should be ignored in favor of the log
+#[test]
+fn damaged_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter).into_bytes(),
+            )?;
+        }
+    }
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "hint") {
+            let mut content = fs::read(&path)?;
+            let last = content.len() - 1;
+            content[last] ^= 0xff;
+            fs::write(&path, content)?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{:0>100}", 199).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Value set from a reader should be read back in pieces, also after reopen
+#[test]
+fn streamed_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let len = value.len() as u64;
+    store.set_from_reader(b"big".to_vec(), &mut value.as_slice(), len, None)?;
+    store.set_from_reader(b"empty".to_vec(), &mut io::empty(), 0, None)?;
+
+    let read_back = |store: &KvStore, key: &[u8]| -> Result<Option<Vec<u8>>> {
+        match store.get_reader(key.to_vec())? {
+            Some((len, mut reader)) => {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf)?;
+                assert_eq!(buf.len() as u64, len);
+                Ok(Some(buf))
+            }
+            None => Ok(None),
+        }
+    };
+    assert_eq!(read_back(&store, b"big")?, Some(value.clone()));
+    assert_eq!(read_back(&store, b"empty")?, Some(Vec::new()));
+    assert_eq!(read_back(&store, b"missing")?, None);
+    assert_eq!(store.get(b"big".to_vec())?, Some(value.clone()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(read_back(&store, b"big")?, Some(value));
+    assert_eq!(read_back(&store, b"empty")?, Some(Vec::new()));
+    Ok(())
+}
+
+// Reader shorter than the length should set nothing and leave no spool file
+#[test]
+fn streamed_value_too_short() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"old".to_vec())?;
+    let res = store.set_from_reader(b"key".to_vec(), &mut &[7u8; 1000][..], 2000, None);
+    assert!(res.is_err());
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"old".to_vec()));
+    let spools = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "spool")
+        })
+        .count();
+    assert_eq!(spools, 0);
+    Ok(())
+}
+
+// Damaged streamed value should fail at the end of reading, not pass silently
+#[test]
+fn streamed_value_damaged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), vec![b'a'; 100_000])?;
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            let mut content = fs::read(&path)?;
+            let middle = content.len() - 50_000;
+            content[middle] ^= 0xff;
+            fs::write(&path, content)?;
+        }
+    }
+    let (len, mut reader) = store.get_reader(b"key".to_vec())?.unwrap();
+    assert_eq!(len, 100_000);
+    let mut buf = Vec::new();
+    assert!(reader.read_to_end(&mut buf).is_err());
+    Ok(())
+}
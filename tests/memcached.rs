@@ -0,0 +1,266 @@
+use assert_cmd::prelude::*;
+use kvs::{DBCommands, KVSClient, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+// Start server with memcached listener and extra arguments,
+// killed when the sender is sent to
+fn start_server(
+    addr: &str,
+    memcached_addr: &str,
+    temp_dir: &TempDir,
+    args: &[&str],
+) -> (mpsc::SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .args(["--memcached-addr", memcached_addr])
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+// Connection sending raw lines of the text protocol
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(addr: &str) -> Connection {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Connection { stream, reader }
+    }
+
+    fn send(&mut self, text: &str) {
+        self.stream.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "line {:?} without CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    // Send command and read one line of reply
+    fn call(&mut self, text: &str) -> String {
+        self.send(text);
+        self.read_line()
+    }
+
+    // Send retrieval command and read lines up to END
+    fn get(&mut self, text: &str) -> Vec<String> {
+        self.send(text);
+        let mut lines = Vec::new();
+        loop {
+            match self.read_line() {
+                line if line == "END" => return lines,
+                line => lines.push(line),
+            }
+        }
+    }
+}
+
+// Commands should map onto the engine shared with the native protocol
+#[test]
+fn memcached_commands() -> Result<()> {
+    let (addr, memcached_addr) = ("127.0.0.1:4031", "127.0.0.1:4032");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, memcached_addr, &temp_dir, &["--memcached-flush"]);
+    let mut conn = Connection::open(memcached_addr);
+
+    assert!(conn.get("get key\r\n").is_empty());
+    assert_eq!(conn.call("set key 5 0 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+
+    // same engine as the native protocol
+    let mut client = KVSClient::new(addr.to_owned())?;
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"value".to_vec()));
+    client.request(DBCommands::Set {
+        key: b"native".to_vec(),
+        value: b"from native".to_vec(),
+        ttl: None,
+    })?;
+    assert_eq!(
+        conn.get("get native key missing\r\n"),
+        ["VALUE native 0 11", "from native", "VALUE key 0 5", "value"]
+    );
+
+    assert_eq!(conn.call("add key 0 0 5\r\nother\r\n"), "NOT_STORED");
+    assert_eq!(conn.call("add new 0 0 3\r\nnew\r\n"), "STORED");
+    assert_eq!(
+        conn.call("replace missing 0 0 5\r\nother\r\n"),
+        "NOT_STORED"
+    );
+    assert_eq!(conn.call("replace key 0 0 5\r\nother\r\n"), "STORED");
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "other"]);
+
+    let item = conn.get("gets key\r\n");
+    assert_eq!(item.len(), 2);
+    let unique: u64 = item[0]
+        .strip_prefix("VALUE key 0 5 ")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        conn.call(&format!("cas key 0 0 3 {}\r\nnew\r\n", unique + 1)),
+        "EXISTS"
+    );
+    assert_eq!(
+        conn.call(&format!("cas key 0 0 3 {}\r\nnew\r\n", unique)),
+        "STORED"
+    );
+    assert_eq!(
+        conn.call(&format!("cas key 0 0 3 {}\r\nnew\r\n", unique)),
+        "EXISTS"
+    );
+    assert_eq!(conn.call("cas missing 0 0 3 1\r\nnew\r\n"), "NOT_FOUND");
+
+    assert_eq!(conn.call("delete new\r\n"), "DELETED");
+    assert_eq!(conn.call("delete new\r\n"), "NOT_FOUND");
+
+    assert_eq!(conn.call("incr counter 1\r\n"), "NOT_FOUND");
+    assert_eq!(conn.call("set counter 0 0 2\r\n10\r\n"), "STORED");
+    assert_eq!(conn.call("incr counter 5\r\n"), "15");
+    assert_eq!(conn.call("decr counter 20\r\n"), "0");
+    assert_eq!(
+        conn.call("incr key 1\r\n"),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value"
+    );
+    assert_eq!(
+        conn.call("incr counter x\r\n"),
+        "CLIENT_ERROR invalid numeric delta argument"
+    );
+
+    // expiration
+    assert_eq!(conn.call("set gone 0 -1 5\r\nvalue\r\n"), "STORED");
+    assert!(conn.get("get gone\r\n").is_empty());
+    assert_eq!(conn.call("set short 0 1 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(conn.call("set long 0 100 1\r\n1\r\n"), "STORED");
+    assert_eq!(conn.call("incr long 1\r\n"), "2");
+    thread::sleep(Duration::from_millis(1100));
+    assert!(conn.get("get short\r\n").is_empty());
+    assert_eq!(conn.get("get long\r\n"), ["VALUE long 0 1", "2"]);
+
+    // noreply and pipelined commands in one write
+    conn.send("set quiet 0 0 1 noreply\r\nq\r\ndelete missing noreply\r\nget quiet\r\n");
+    assert_eq!(conn.read_line(), "VALUE quiet 0 1");
+    assert_eq!(conn.read_line(), "q");
+    assert_eq!(conn.read_line(), "END");
+
+    assert_eq!(conn.call("touch key 10\r\n"), "ERROR");
+    assert_eq!(
+        conn.call("set key 0 0\r\n"),
+        "CLIENT_ERROR bad command line format"
+    );
+
+    let stats = conn.get("stats\r\n");
+    assert!(
+        stats.iter().any(|stat| stat == "STAT curr_connections 1"),
+        "{:?}",
+        stats
+    );
+    assert!(
+        stats.iter().any(|stat| stat.starts_with("STAT get_hits ")),
+        "{:?}",
+        stats
+    );
+
+    assert_eq!(conn.call("flush_all\r\n"), "OK");
+    assert!(conn.get("get key native long quiet\r\n").is_empty());
+    assert_eq!(client.get(b"native".to_vec())?, None);
+
+    // later delayed flush_all replaces the pending one
+    assert_eq!(conn.call("set key 0 0 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(conn.call("flush_all 1\r\n"), "OK");
+    assert_eq!(conn.call("flush_all 60\r\n"), "OK");
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+    assert_eq!(conn.call("flush_all 1\r\n"), "OK");
+    thread::sleep(Duration::from_millis(1500));
+    assert!(conn.get("get key\r\n").is_empty());
+
+    // delay over 30 days is a unix timestamp, one out of range never comes
+    assert_eq!(conn.call("set key 0 0 5\r\nvalue\r\n"), "STORED");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let flush = format!("flush_all {}\r\n", now.as_secs() + 2);
+    assert_eq!(conn.call(&flush), "OK");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+    thread::sleep(Duration::from_millis(3000));
+    assert!(conn.get("get key\r\n").is_empty());
+    assert_eq!(conn.call("set key 0 0 5\r\nvalue\r\n"), "STORED");
+    assert_eq!(conn.call("flush_all 18446744073709551615\r\n"), "OK");
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Too large or malformed data block should get an error and the connection closed
+#[test]
+fn memcached_bad_requests() -> Result<()> {
+    let (addr, memcached_addr) = ("127.0.0.1:4033", "127.0.0.1:4034");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(
+        addr,
+        memcached_addr,
+        &temp_dir,
+        &["--max-value-size", "16", "--max-key-size", "8"],
+    );
+
+    let mut conn = Connection::open(memcached_addr);
+    assert!(conn
+        .call("get much-too-long-key\r\n")
+        .starts_with("CLIENT_ERROR"));
+    assert_eq!(conn.call("set key 0 0 5\r\nvalue\r\n"), "STORED");
+    conn.send("set key 0 0 1073741824\r\nvalue");
+    assert_eq!(conn.read_line(), "SERVER_ERROR object too large for cache");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with unread value is open");
+
+    let mut conn = Connection::open(memcached_addr);
+    assert_eq!(
+        conn.call("set key 0 0 2\r\nvalue\r\n"),
+        "CLIENT_ERROR bad data chunk"
+    );
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with bad data chunk is open");
+
+    // earlier writes are kept, flush_all is off by default
+    let mut conn = Connection::open(memcached_addr);
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+    assert_eq!(
+        conn.call("flush_all\r\n"),
+        "CLIENT_ERROR flush_all not allowed"
+    );
+    assert_eq!(conn.get("get key\r\n"), ["VALUE key 0 5", "value"]);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,342 @@
+use assert_cmd::prelude::*;
+use kvs::{KVSClient, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start server with RESP listener and extra arguments,
+// killed when the sender is sent to
+fn start_server(
+    addr: &str,
+    resp_addr: &str,
+    temp_dir: &TempDir,
+    args: &[&str],
+) -> (mpsc::SyncSender<()>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .args(["--resp-addr", resp_addr])
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(value: &str) -> Reply {
+    Reply::Bulk(Some(value.as_bytes().to_vec()))
+}
+
+fn ok() -> Reply {
+    Reply::Status("OK".to_owned())
+}
+
+// Connection sending commands as arrays of bulk strings, like Redis clients
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(addr: &str) -> Connection {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Connection { stream, reader }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut packet = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            packet.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        self.stream.write_all(&packet).unwrap();
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        self.send(args);
+        self.read_reply()
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "line {:?} without CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Status(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut data).unwrap();
+                    assert!(data.ends_with(b"\r\n"));
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(data))
+                }
+            },
+            "*" => {
+                let count: usize = rest.parse().unwrap();
+                Reply::Array((0..count).map(|_| self.read_reply()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn assert_error(reply: Reply, text: &str) {
+    match reply {
+        Reply::Error(message) => assert!(message.contains(text), "{}", message),
+        reply => panic!("expected error with {:?}, got {:?}", text, reply),
+    }
+}
+
+// Commands should map onto the engine shared with the native protocol
+#[test]
+fn resp_commands() -> Result<()> {
+    let (addr, resp_addr) = ("127.0.0.1:4025", "127.0.0.1:4026");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, resp_addr, &temp_dir, &[]);
+    let mut conn = Connection::open(resp_addr);
+
+    assert_eq!(conn.call(&["PING"]), Reply::Status("PONG".to_owned()));
+    assert_eq!(conn.call(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(conn.call(&["GET", "key"]), Reply::Bulk(None));
+    assert_eq!(conn.call(&["SET", "key", "value"]), ok());
+    assert_eq!(conn.call(&["GET", "key"]), bulk("value"));
+
+    // same engine as the native protocol
+    let mut client = KVSClient::new(addr.to_owned())?;
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"value".to_vec()));
+
+    assert_eq!(conn.call(&["SET", "key", "other", "NX"]), Reply::Bulk(None));
+    assert_eq!(conn.call(&["SET", "new", "value", "nx"]), ok());
+    assert_eq!(
+        conn.call(&["SET", "missing", "value", "XX"]),
+        Reply::Bulk(None)
+    );
+    assert_eq!(conn.call(&["GET", "missing"]), Reply::Bulk(None));
+    assert_eq!(conn.call(&["SET", "key", "other", "XX"]), ok());
+    assert_eq!(conn.call(&["GET", "key"]), bulk("other"));
+    assert_error(conn.call(&["SET", "key", "value", "NX", "XX"]), "syntax");
+    assert_error(conn.call(&["SET", "key", "value", "EX", "0"]), "expire");
+    assert_error(conn.call(&["SET", "key"]), "wrong number of arguments");
+
+    assert_eq!(conn.call(&["SET", "short", "value", "PX", "300"]), ok());
+    assert_eq!(conn.call(&["SET", "lock", "me", "NX", "PX", "300"]), ok());
+    assert_eq!(
+        conn.call(&["SET", "lock", "me", "NX", "PX", "300"]),
+        Reply::Bulk(None)
+    );
+    assert_eq!(conn.call(&["SET", "long", "value", "EX", "100"]), ok());
+    assert_eq!(conn.call(&["SET", "long", "kept", "XX", "EX", "100"]), ok());
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(conn.call(&["GET", "short"]), Reply::Bulk(None));
+    assert_eq!(conn.call(&["GET", "lock"]), Reply::Bulk(None));
+    assert_eq!(conn.call(&["GET", "long"]), bulk("kept"));
+
+    assert_eq!(
+        conn.call(&["EXISTS", "key", "missing", "key"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        conn.call(&["DEL", "key", "missing", "new"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(conn.call(&["EXISTS", "key"]), Reply::Integer(0));
+
+    assert_eq!(conn.call(&["MSET", "a", "1", "b", "2"]), ok());
+    assert_error(
+        conn.call(&["MSET", "a", "1", "b"]),
+        "wrong number of arguments",
+    );
+    assert_eq!(
+        conn.call(&["MGET", "a", "missing", "b"]),
+        Reply::Array(vec![bulk("1"), Reply::Bulk(None), bulk("2")])
+    );
+    assert_eq!(conn.call(&["INCR", "a"]), Reply::Integer(2));
+    assert_eq!(conn.call(&["INCR", "counter"]), Reply::Integer(1));
+    assert_error(conn.call(&["INCR", "long"]), "integer");
+
+    assert_error(conn.call(&["FLUSHALL"]), "unknown command 'flushall'");
+    match conn.call(&["INFO"]) {
+        Reply::Bulk(Some(info)) => {
+            let info = String::from_utf8(info).unwrap();
+            assert!(info.contains("# Server\r\nkvs_version:"), "{}", info);
+            assert!(info.contains("connected_clients:1"), "{}", info);
+        }
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    }
+
+    // inline command and pipelined commands in one write
+    conn.stream.write_all(b"PING\r\n")?;
+    assert_eq!(conn.read_reply(), Reply::Status("PONG".to_owned()));
+    conn.stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\np\r\n$1\r\n1\r\n*2\r\n$4\r\nINCR\r\n$1\r\np\r\n")?;
+    assert_eq!(conn.read_reply(), ok());
+    assert_eq!(conn.read_reply(), Reply::Integer(2));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// SCAN should return every key once across calls, filtered by MATCH,
+// and end with cursor 0
+#[test]
+fn resp_scan() -> Result<()> {
+    let (addr, resp_addr) = ("127.0.0.1:4027", "127.0.0.1:4028");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(addr, resp_addr, &temp_dir, &[]);
+    let mut conn = Connection::open(resp_addr);
+
+    for i in 0..25 {
+        assert_eq!(conn.call(&["SET", &format!("user:{:02}", i), "v"]), ok());
+        assert_eq!(conn.call(&["SET", &format!("item:{:02}", i), "v"]), ok());
+    }
+
+    let scan_all = |conn: &mut Connection, options: &[&str]| {
+        let mut cursor = "0".to_owned();
+        let mut keys = Vec::new();
+        let mut calls = 0;
+        loop {
+            let mut args = vec!["SCAN", &cursor];
+            args.extend_from_slice(options);
+            let reply = conn.call(&args);
+            calls += 1;
+            match reply {
+                Reply::Array(mut items) if items.len() == 2 => {
+                    let page = items.pop().unwrap();
+                    match (items.pop().unwrap(), page) {
+                        (Reply::Bulk(Some(next)), Reply::Array(page)) => {
+                            for key in page {
+                                match key {
+                                    Reply::Bulk(Some(key)) => {
+                                        keys.push(String::from_utf8(key).unwrap())
+                                    }
+                                    key => panic!("unexpected key {:?}", key),
+                                }
+                            }
+                            cursor = String::from_utf8(next).unwrap();
+                        }
+                        reply => panic!("unexpected SCAN reply {:?}", reply),
+                    }
+                }
+                reply => panic!("unexpected SCAN reply {:?}", reply),
+            }
+            if cursor == "0" {
+                return (keys, calls);
+            }
+        }
+    };
+
+    let (keys, calls) = scan_all(&mut conn, &[]);
+    assert_eq!(keys.len(), 50);
+    assert!(calls >= 5, "{} calls with default count", calls);
+    let (keys, _) = scan_all(&mut conn, &["MATCH", "user:1*", "COUNT", "3"]);
+    let expected: Vec<String> = (10..20).map(|i| format!("user:{}", i)).collect();
+    assert_eq!(keys, expected);
+    let (keys, _) = scan_all(&mut conn, &["MATCH", "*:0[1-3]", "COUNT", "100"]);
+    assert_eq!(
+        keys,
+        ["item:01", "item:02", "item:03", "user:01", "user:02", "user:03"]
+    );
+
+    // pattern with many stars is matched without exponential backtracking
+    let long_key = "a".repeat(200);
+    assert_eq!(conn.call(&["SET", &long_key, "v"]), ok());
+    let stars = format!("{}b", "*a".repeat(20));
+    let (keys, _) = scan_all(&mut conn, &["MATCH", &stars, "COUNT", "100"]);
+    assert!(keys.is_empty());
+    let (keys, _) = scan_all(&mut conn, &["MATCH", "*a*a*\\a?", "COUNT", "100"]);
+    assert_eq!(keys, [long_key]);
+
+    assert_error(conn.call(&["SCAN", "12345678"]), "invalid cursor");
+    assert_error(conn.call(&["SCAN", "0", "COUNT", "0"]), "syntax");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// Malformed or too large request should get an error and the connection closed
+#[test]
+fn resp_bad_requests() -> Result<()> {
+    let (addr, resp_addr) = ("127.0.0.1:4029", "127.0.0.1:4030");
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(
+        addr,
+        resp_addr,
+        &temp_dir,
+        &["--max-frame-size", "1024", "--max-key-size", "8"],
+    );
+
+    let mut conn = Connection::open(resp_addr);
+    assert_error(
+        conn.call(&["SET", "much-too-long-key", "value"]),
+        "key of 17 bytes",
+    );
+    assert_eq!(conn.call(&["SET", "key", "value"]), ok());
+
+    // bulk string of 1 GiB is rejected before it is read
+    conn.stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1073741824\r\nvalue")?;
+    assert_error(conn.read_reply(), "Too large");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with unread request is open");
+
+    let mut conn = Connection::open(resp_addr);
+    conn.stream.write_all(b"*1\r\n+PING\r\n")?;
+    assert_error(conn.read_reply(), "Protocol");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty(), "connection with malformed request is open");
+
+    // name of unknown command can't break the reply into forged ones
+    let mut conn = Connection::open(resp_addr);
+    assert_error(
+        conn.call(&["NOPE\r\n+OK"]),
+        "unknown command 'nope\\r\\n+ok'",
+    );
+    assert_eq!(conn.call(&["PING"]), Reply::Status("PONG".to_owned()));
+
+    // earlier writes are kept
+    let mut conn = Connection::open(resp_addr);
+    assert_eq!(conn.call(&["GET", "key"]), bulk("value"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    Ok(())
+}
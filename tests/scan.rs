@@ -0,0 +1,62 @@
+use kvs::{KvStore, KvsEngine, Result, SledStore};
+use tempfile::TempDir;
+
+fn pairs(items: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    items
+        .iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+// Scans should return live pairs ordered by key on any engine
+fn check_scan<S: KvsEngine>(store: S) -> Result<()> {
+    for key in ["b", "a", "user:2", "c", "user:1", "user:3", "userx"] {
+        store.set(key.as_bytes().to_vec(), format!("v_{}", key).into_bytes())?;
+    }
+    store.set(b"c".to_vec(), b"v_c2".to_vec())?;
+    store.remove(b"user:2".to_vec())?;
+
+    assert_eq!(
+        store.scan(b"a".to_vec(), Some(b"c".to_vec()), 10)?,
+        pairs(&[("a", "v_a"), ("b", "v_b")])
+    );
+    assert_eq!(
+        store.scan(b"b".to_vec(), None, 3)?,
+        pairs(&[("b", "v_b"), ("c", "v_c2"), ("user:1", "v_user:1")])
+    );
+    assert!(store
+        .scan(b"c".to_vec(), Some(b"a".to_vec()), 10)?
+        .is_empty());
+    assert!(store.scan(b"a".to_vec(), None, 0)?.is_empty());
+
+    assert_eq!(
+        store.scan_prefix(b"user:".to_vec(), 10)?,
+        pairs(&[("user:1", "v_user:1"), ("user:3", "v_user:3")])
+    );
+    assert_eq!(
+        store.scan_prefix(b"user".to_vec(), 2)?,
+        pairs(&[("user:1", "v_user:1"), ("user:3", "v_user:3")])
+    );
+    assert!(store.scan_prefix(b"z".to_vec(), 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn kvs_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)?;
+
+    // order must survive reopen
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan_prefix(b"user:".to_vec(), 10)?,
+        pairs(&[("user:1", "v_user:1"), ("user:3", "v_user:3")])
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledStore::open(temp_dir.path())?)
+}
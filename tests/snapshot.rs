@@ -0,0 +1,222 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    DBCommands, ErrorCode, KVSClient, KVSError, KvStore, KvsEngine, KvsSnapshot, Result,
+    ServerResponse, SledStore, WriteBatch,
+};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Snapshot should keep seeing data as it was when taken on any engine
+fn check_snapshot<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    let snapshot = store.snapshot()?;
+
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    store.remove(b"key2".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.put(b"key3".to_vec(), b"value3".to_vec());
+    store.apply_batch(batch)?;
+
+    assert_eq!(snapshot.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(snapshot.get(b"key3".to_vec())?, None);
+    assert_eq!(
+        snapshot.scan(b"key".to_vec(), None, 10)?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    assert_eq!(
+        snapshot.scan(b"key1".to_vec(), Some(b"key2".to_vec()), 10)?,
+        vec![(b"key1".to_vec(), b"value1".to_vec())]
+    );
+    assert_eq!(
+        snapshot.scan_prefix(b"key".to_vec(), 1)?,
+        vec![(b"key1".to_vec(), b"value1".to_vec())]
+    );
+
+    // store itself sees the new data
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    // clones share the view and can be read from other threads
+    let cloned = snapshot.clone();
+    drop(snapshot);
+    let value = thread::spawn(move || cloned.get(b"key1".to_vec()))
+        .join()
+        .unwrap()?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(&SledStore::open(temp_dir.path())?)
+}
+
+// Snapshots taken at different times should each see their own data,
+// also after snapshots between them are dropped
+fn check_snapshot_generations<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(b"key".to_vec(), b"first".to_vec())?;
+    let first = store.snapshot()?;
+    store.set(b"key".to_vec(), b"second".to_vec())?;
+    store.set(b"other".to_vec(), b"second".to_vec())?;
+    let second = store.snapshot()?;
+    let third = store.snapshot()?;
+    store.remove(b"key".to_vec())?;
+    store.set(b"other".to_vec(), b"fourth".to_vec())?;
+
+    drop(second);
+    assert_eq!(first.get(b"key".to_vec())?, Some(b"first".to_vec()));
+    assert_eq!(first.get(b"other".to_vec())?, None);
+    assert_eq!(third.get(b"key".to_vec())?, Some(b"second".to_vec()));
+    drop(third);
+    assert_eq!(
+        first.scan(Vec::new(), None, 10)?,
+        vec![(b"key".to_vec(), b"first".to_vec())]
+    );
+
+    let fourth = store.snapshot()?;
+    drop(first);
+    store.set(b"key".to_vec(), b"fifth".to_vec())?;
+    assert_eq!(
+        fourth.scan_prefix(Vec::new(), 10)?,
+        vec![(b"other".to_vec(), b"fourth".to_vec())]
+    );
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"fifth".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_snapshot_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_generations(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_snapshot_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_generations(&SledStore::open(temp_dir.path())?)
+}
+
+// Compaction should keep generations read by live snapshot
+// and delete them when the last snapshot is dropped
+#[test]
+fn kvs_snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"old".to_vec())?;
+    let snapshot = store.snapshot()?;
+
+    for iter in 0..1000 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id).into_bytes();
+            store.set(key, format!("{:0>100}", iter).into_bytes())?;
+        }
+    }
+    store.set(b"key".to_vec(), b"new".to_vec())?;
+    // dropping the last handle waits for running compaction
+    drop(store);
+
+    let first_log = temp_dir.path().join("1.log");
+    assert!(first_log.exists(), "generation of live snapshot is deleted");
+    assert_eq!(snapshot.get(b"key".to_vec())?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get(b"key0".to_vec())?, None);
+
+    drop(snapshot);
+    assert!(!first_log.exists(), "stale generation is not deleted");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"new".to_vec()));
+    assert_eq!(
+        store.get(b"key0".to_vec())?,
+        Some(format!("{:0>100}", 999).into_bytes())
+    );
+    Ok(())
+}
+
+fn take_snapshot(client: &mut KVSClient) -> Result<u64> {
+    match client.request(DBCommands::Snapshot)? {
+        ServerResponse::Snapshot { id } => Ok(id),
+        resp => panic!("Unexpected response {:?}", resp),
+    }
+}
+
+fn read_snapshot(client: &mut KVSClient, id: u64) -> Result<ServerResponse> {
+    client.request(DBCommands::SnapshotGet {
+        id,
+        key: b"key".to_vec(),
+    })
+}
+
+fn error_code<T: std::fmt::Debug>(res: Result<T>) -> ErrorCode {
+    match res {
+        Err(e @ KVSError::ServerError { .. }) => ErrorCode::from(&e),
+        res => panic!("Unexpected result {:?}", res),
+    }
+}
+
+// Server should give random snapshot ids, keep only a few snapshots
+// and release those not used for their TTL
+#[test]
+fn snapshot_leases() {
+    let addr = "127.0.0.1:4040";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--snapshot-ttl", "1s", "--max-snapshots", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let res = check_leases(addr);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+    res.unwrap();
+}
+
+fn check_leases(addr: &str) -> Result<()> {
+    let mut client = KVSClient::new(addr.to_owned())?;
+    let first = take_snapshot(&mut client)?;
+    let second = take_snapshot(&mut client)?;
+    assert!(first.abs_diff(second) > 1, "ids {} and {}", first, second);
+    assert_eq!(
+        error_code(client.request(DBCommands::Snapshot)),
+        ErrorCode::InvalidCommand
+    );
+
+    // reads renew the lease of the first one only
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(500));
+        assert!(matches!(
+            read_snapshot(&mut client, first)?,
+            ServerResponse::NotFound
+        ));
+    }
+    assert_eq!(
+        error_code(read_snapshot(&mut client, second)),
+        ErrorCode::SnapshotNotFound
+    );
+    let third = take_snapshot(&mut client)?;
+    client.request(DBCommands::ReleaseSnapshot { id: third })?;
+
+    // unused snapshot is released in background
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(
+        error_code(read_snapshot(&mut client, first)),
+        ErrorCode::SnapshotNotFound
+    );
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use kvs::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+const TASK_NUM: usize = 20;
+const ADD_COUNT: usize = 1000;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let sender = sender.clone();
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            sender.send(()).unwrap();
+        })
+    }
+
+    for _ in 0..TASK_NUM {
+        receiver.recv().unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+// Panicking jobs should not decrease number of workers
+fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 1000;
+
+    let pool = P::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || {
+            // It suppresses flood of panic messages to the console.
+            // You may find it useful to comment this out during development.
+            panic_control::disable_hook_in_current_thread();
+
+            panic!();
+        })
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    let pool = NaiveThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
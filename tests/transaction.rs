@@ -0,0 +1,266 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    DBCommands, ErrorCode, KVSClient, KVSError, KvStore, KvsEngine, Result, ServerResponse,
+    SledStore,
+};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Transactions should see own writes and detect changed reads on any engine
+fn check_transaction<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+
+    let mut transaction = store.begin();
+    assert_eq!(transaction.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    transaction.set(b"key1".to_vec(), b"value1b".to_vec());
+    transaction.remove(b"key2".to_vec());
+    transaction.remove(b"missing".to_vec());
+    assert_eq!(
+        transaction.get(b"key1".to_vec())?,
+        Some(b"value1b".to_vec())
+    );
+    assert_eq!(transaction.get(b"key2".to_vec())?, None);
+    // nothing is visible before commit
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    transaction.commit()?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1b".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    // read key changed by somebody else
+    let mut transaction = store.begin();
+    transaction.get(b"key1".to_vec())?;
+    transaction.set(b"key3".to_vec(), b"value3".to_vec());
+    store.set(b"key1".to_vec(), b"value1c".to_vec())?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KVSError::TransactionConflictError)
+    ));
+    assert_eq!(store.get(b"key3".to_vec())?, None);
+
+    // missing key created by somebody else
+    let mut transaction = store.begin();
+    assert_eq!(transaction.get(b"key2".to_vec())?, None);
+    transaction.set(b"key2".to_vec(), b"mine".to_vec());
+    store.set(b"key2".to_vec(), b"theirs".to_vec())?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KVSError::TransactionConflictError)
+    ));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"theirs".to_vec()));
+
+    // keys only written are not checked
+    let mut transaction = store.begin();
+    transaction.set(b"key1".to_vec(), b"blind".to_vec());
+    store.set(b"key1".to_vec(), b"other".to_vec())?;
+    transaction.commit()?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"blind".to_vec()));
+
+    // dropped transaction writes nothing
+    let mut transaction = store.begin();
+    transaction.set(b"key4".to_vec(), b"value4".to_vec());
+    drop(transaction);
+    assert_eq!(store.get(b"key4".to_vec())?, None);
+    Ok(())
+}
+
+// Transfers between two counters by `transact` should keep the sum
+fn check_concurrent_transfers<S: KvsEngine>(store: &S) -> Result<()> {
+    store.set(b"a".to_vec(), b"1000".to_vec())?;
+    store.set(b"b".to_vec(), b"0".to_vec())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    store
+                        .transact_with_attempts(1000, |transaction| {
+                            let a = read_number(transaction.get(b"a".to_vec())?);
+                            let b = read_number(transaction.get(b"b".to_vec())?);
+                            transaction.set(b"a".to_vec(), (a - 1).to_string().into_bytes());
+                            transaction.set(b"b".to_vec(), (b + 1).to_string().into_bytes());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"900".to_vec()));
+    assert_eq!(store.get(b"b".to_vec())?, Some(b"100".to_vec()));
+
+    // error of the closure is returned without commit
+    let res = store.transact(|transaction| -> Result<()> {
+        transaction.set(b"a".to_vec(), b"0".to_vec());
+        Err(KVSError::KeyNotFoundError)
+    });
+    assert!(matches!(res, Err(KVSError::KeyNotFoundError)));
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"900".to_vec()));
+    Ok(())
+}
+
+fn read_number(value: Option<Vec<u8>>) -> i64 {
+    String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+}
+
+#[test]
+fn kvs_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"blind".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"theirs".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn sled_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(&SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_concurrent_transfers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_transfers(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_concurrent_transfers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_transfers(&SledStore::open(temp_dir.path())?)
+}
+
+fn send(client: &mut KVSClient, command: DBCommands) -> ServerResponse {
+    client.send_cmd(command).expect("IO error")
+}
+
+// Transaction is scoped to the connection and conflicts with other clients
+#[test]
+fn transaction_session() {
+    let addr = "127.0.0.1:4011";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KVSClient::new(addr.to_owned()).unwrap();
+    let mut other = KVSClient::new(addr.to_owned()).unwrap();
+    send(
+        &mut other,
+        DBCommands::Set {
+            key: b"key".to_vec(),
+            value: b"value1".to_vec(),
+            ttl: None,
+        },
+    );
+
+    assert!(matches!(
+        send(&mut client, DBCommands::Commit),
+        ServerResponse::Failure {
+            code: ErrorCode::InvalidCommand,
+            ..
+        }
+    ));
+    send(&mut client, DBCommands::Begin);
+    assert!(matches!(
+        send(&mut client, DBCommands::Get { key: b"key".to_vec() }),
+        ServerResponse::Value { value } if value == b"value1"
+    ));
+    send(
+        &mut client,
+        DBCommands::Set {
+            key: b"key".to_vec(),
+            value: b"value2".to_vec(),
+            ttl: None,
+        },
+    );
+    // other connection does not see the write until commit
+    assert!(matches!(
+        send(&mut other, DBCommands::Get { key: b"key".to_vec() }),
+        ServerResponse::Value { value } if value == b"value1"
+    ));
+    send(
+        &mut other,
+        DBCommands::Set {
+            key: b"key".to_vec(),
+            value: b"value3".to_vec(),
+            ttl: None,
+        },
+    );
+    assert!(matches!(
+        send(&mut client, DBCommands::Commit),
+        ServerResponse::Failure {
+            code: ErrorCode::TransactionConflict,
+            ..
+        }
+    ));
+
+    send(&mut client, DBCommands::Begin);
+    send(
+        &mut client,
+        DBCommands::Get {
+            key: b"key".to_vec(),
+        },
+    );
+    send(
+        &mut client,
+        DBCommands::Rm {
+            key: b"key".to_vec(),
+        },
+    );
+    assert!(matches!(
+        send(&mut client, DBCommands::Commit),
+        ServerResponse::Success { .. }
+    ));
+    assert!(matches!(
+        send(
+            &mut other,
+            DBCommands::Get {
+                key: b"key".to_vec()
+            }
+        ),
+        ServerResponse::NotFound
+    ));
+
+    // transaction left open is discarded with the connection
+    send(&mut client, DBCommands::Begin);
+    send(
+        &mut client,
+        DBCommands::Set {
+            key: b"key".to_vec(),
+            value: b"lost".to_vec(),
+            ttl: None,
+        },
+    );
+    drop(client);
+    assert!(matches!(
+        send(
+            &mut other,
+            DBCommands::Get {
+                key: b"key".to_vec()
+            }
+        ),
+        ServerResponse::NotFound
+    ));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
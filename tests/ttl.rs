@@ -0,0 +1,151 @@
+use kvs::{parse_ttl, KVSError, KvStore, KvStoreConfig, KvsEngine, Result, SledStore};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Keys set with TTL should disappear after it on any engine
+fn check_ttl<S: KvsEngine>(store: S) -> Result<()> {
+    store.set_with_ttl(b"short".to_vec(), b"1".to_vec(), Duration::from_millis(200))?;
+    store.set_with_ttl(b"long".to_vec(), b"2".to_vec(), Duration::from_secs(60))?;
+    store.set(b"forever".to_vec(), b"3".to_vec())?;
+
+    assert_eq!(store.get(b"short".to_vec())?, Some(b"1".to_vec()));
+    let ttl = store.ttl(b"long".to_vec())?.expect("long has ttl");
+    assert!(ttl > Duration::from_secs(59) && ttl <= Duration::from_secs(60));
+    assert_eq!(store.ttl(b"forever".to_vec())?, None);
+    assert!(matches!(
+        store.ttl(b"missing".to_vec()),
+        Err(KVSError::KeyNotFoundError)
+    ));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert!(matches!(
+        store.ttl(b"short".to_vec()),
+        Err(KVSError::KeyNotFoundError)
+    ));
+    assert!(store.remove(b"short".to_vec()).is_err());
+    assert_eq!(
+        store.scan(Vec::new(), None, 10)?,
+        vec![
+            (b"forever".to_vec(), b"3".to_vec()),
+            (b"long".to_vec(), b"2".to_vec())
+        ]
+    );
+
+    // plain set drops expiration
+    store.set(b"long".to_vec(), b"4".to_vec())?;
+    assert_eq!(store.ttl(b"long".to_vec())?, None);
+    // expired key can be set again
+    store.set(b"short".to_vec(), b"5".to_vec())?;
+    assert_eq!(store.get(b"short".to_vec())?, Some(b"5".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledStore::open(temp_dir.path())?)
+}
+
+// Expiration is stored in the log, so it survives reopen
+#[test]
+fn ttl_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(b"short".to_vec(), b"1".to_vec(), Duration::from_millis(200))?;
+    store.set_with_ttl(b"long".to_vec(), b"2".to_vec(), Duration::from_secs(60))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(300));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert_eq!(store.get(b"long".to_vec())?, Some(b"2".to_vec()));
+    assert!(store.ttl(b"long".to_vec())?.expect("long has ttl") > Duration::from_secs(59));
+    Ok(())
+}
+
+fn log_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            size += fs::metadata(path)?.len();
+        }
+    }
+    Ok(size)
+}
+
+// Sweeper should write tombstones for expired keys
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sweep_interval: Duration::from_millis(50),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::with_config(temp_dir.path().to_owned(), config)?;
+    store.set_with_ttl(
+        b"key".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    let size_before = log_size(temp_dir.path())?;
+
+    thread::sleep(Duration::from_millis(300));
+    assert!(log_size(temp_dir.path())? > size_before);
+    drop(store);
+
+    // tombstone hides the key with no expiration check at all
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.scan(Vec::new(), None, 10)?.is_empty());
+    Ok(())
+}
+
+// Compaction keeps expiration of moved records in the log and hint
+#[test]
+fn ttl_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(b"ttl".to_vec(), b"value".to_vec(), Duration::from_secs(60))?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter).into_bytes(),
+            )?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"ttl".to_vec())?, Some(b"value".to_vec()));
+    assert!(store.ttl(b"ttl".to_vec())?.expect("key has ttl") > Duration::from_secs(59));
+    Ok(())
+}
+
+// TTL is parsed with its unit, and rejected when it does not fit in milliseconds
+#[test]
+fn ttl_parsing() {
+    assert_eq!(parse_ttl("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_ttl("30"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse_ttl("2h"), Ok(Duration::from_secs(7200)));
+    assert!(parse_ttl("0s").is_err());
+    assert!(parse_ttl("soon").is_err());
+    assert_eq!(
+        parse_ttl("18446744073709551615h"),
+        Err("ttl too large".to_owned())
+    );
+    assert_eq!(
+        parse_ttl("18446744073709551615ms"),
+        Ok(Duration::from_millis(u64::MAX))
+    );
+}
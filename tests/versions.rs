@@ -0,0 +1,186 @@
+use kvs::{KVSError, KvStore, KvsEngine, Result, SledStore, WriteBatch};
+use std::thread;
+use tempfile::TempDir;
+
+// Conditional writes should behave the same on any engine
+fn check_versions<S: KvsEngine>(store: &S) -> Result<()> {
+    let version1 = store.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(matches!(
+        store.set_if_absent(b"key1".to_vec(), b"value2".to_vec()),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    assert_eq!(
+        store.get_versioned(b"key1".to_vec())?,
+        Some((b"value1".to_vec(), version1))
+    );
+
+    // every set gets a higher version
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    let (_, version2) = store.get_versioned(b"key1".to_vec())?.unwrap();
+    assert!(version2 > version1);
+    assert!(matches!(
+        store.set_if_version(b"key1".to_vec(), b"value3".to_vec(), version1),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    let version3 = store.set_if_version(b"key1".to_vec(), b"value3".to_vec(), version2)?;
+    assert!(version3 > version2);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
+    assert!(matches!(
+        store.set_if_version(b"missing".to_vec(), b"value".to_vec(), version3),
+        Err(KVSError::PreconditionFailedError)
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"key2".to_vec(), b"value1".to_vec());
+    store.apply_batch(batch)?;
+    let (_, version4) = store.get_versioned(b"key2".to_vec())?.unwrap();
+    assert!(version4 > version3);
+
+    // compare and swap by value, None stands for missing key
+    assert!(matches!(
+        store.compare_and_swap(
+            b"key2".to_vec(),
+            Some(b"other".to_vec()),
+            Some(b"value2".to_vec())
+        ),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    store.compare_and_swap(
+        b"key2".to_vec(),
+        Some(b"value1".to_vec()),
+        Some(b"value2".to_vec()),
+    )?;
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    store.compare_and_swap(b"key2".to_vec(), Some(b"value2".to_vec()), None)?;
+    assert_eq!(store.get_versioned(b"key2".to_vec())?, None);
+    store.compare_and_swap(b"key3".to_vec(), None, Some(b"value1".to_vec()))?;
+    assert!(matches!(
+        store.compare_and_swap(b"key3".to_vec(), None, Some(b"value2".to_vec())),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    store.compare_and_swap(b"missing".to_vec(), None, None)?;
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// Concurrent increments by compare and swap should not lose updates
+fn check_concurrent_cas<S: KvsEngine>(store: &S) -> Result<()> {
+    let threads = 4;
+    let increments = 50;
+    store.set(b"counter".to_vec(), b"0".to_vec())?;
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..increments {
+                    loop {
+                        let current = store.get(b"counter".to_vec()).unwrap().unwrap();
+                        let number: u64 =
+                            String::from_utf8(current.clone()).unwrap().parse().unwrap();
+                        let next = (number + 1).to_string().into_bytes();
+                        match store.compare_and_swap(b"counter".to_vec(), Some(current), Some(next))
+                        {
+                            Ok(()) => break,
+                            Err(KVSError::PreconditionFailedError) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(
+        store.get(b"counter".to_vec())?,
+        Some((threads * increments).to_string().into_bytes())
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_versions(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_versions(&SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_concurrent_cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_cas(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_concurrent_cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_cas(&SledStore::open(temp_dir.path())?)
+}
+
+// Versions should survive reopen and compaction, new ones keep growing
+#[test]
+fn versions_after_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let version = store.set_if_absent(b"key".to_vec(), b"value".to_vec())?;
+    store.set(b"removed".to_vec(), b"value".to_vec())?;
+    store.remove(b"removed".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned(b"key".to_vec())?,
+        Some((b"value".to_vec(), version))
+    );
+    // removed key had the highest version, it must not be given again
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+    let (_, other_version) = store.get_versioned(b"other".to_vec())?.unwrap();
+    assert!(other_version > version + 1);
+
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{:0>100}", iter).into_bytes(),
+            )?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned(b"key".to_vec())?,
+        Some((b"value".to_vec(), version))
+    );
+    let new_version = store.set_if_version(b"key".to_vec(), b"value2".to_vec(), version)?;
+    assert!(new_version > other_version + 200 * 100);
+    Ok(())
+}
+
+// Version of removed key dropped by compaction must not be given again after reopen
+#[test]
+fn versions_of_compacted_removals() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    // removal of large value starts compaction, which drops both records
+    let large = vec![b'v'; 2 * 1024 * 1024];
+    let removed_version = store.set_if_absent(b"lock".to_vec(), large)?;
+    store.remove(b"lock".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let version = store.set_if_absent(b"lock".to_vec(), b"value".to_vec())?;
+    assert!(version > removed_version);
+    assert!(matches!(
+        store.set_if_version(b"lock".to_vec(), b"stale".to_vec(), removed_version),
+        Err(KVSError::PreconditionFailedError)
+    ));
+    Ok(())
+}